@@ -1 +1 @@
-pub mod file_builder;
\ No newline at end of file
+pub mod torrent_builder;
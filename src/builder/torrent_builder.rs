@@ -0,0 +1,358 @@
+use std::{
+    error::Error,
+    fs::{self, File},
+    io::{self, Error as IOError, ErrorKind, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use bendy::{
+    decoding::FromBencode,
+    encoding::{Encoder, ToBencode},
+};
+use sha1::{Digest, Sha1};
+
+use crate::parser::{
+    file_info::{FileInfo, FilePathInfo},
+    metadata::Metadata,
+};
+
+const MIN_PIECE_LENGTH: u64 = 16 << 10;
+const MAX_PIECE_LENGTH: u64 = 16 << 20;
+// Aim for roughly this many pieces, which keeps the metafile small without making pieces huge.
+const TARGET_NUM_PIECES: u64 = 1500;
+
+// Builds a metafile from a local file or directory.
+pub(crate) struct TorrentBuilder {
+    path: PathBuf,
+    trackers: Vec<String>,
+    web_seeds: Vec<String>,
+    comment: Option<String>,
+    private: bool,
+    source: Option<String>,
+    piece_length: Option<u64>,
+    num_threads: usize,
+}
+
+impl TorrentBuilder {
+    pub(crate) fn new(path: &str) -> Self {
+        TorrentBuilder {
+            path: PathBuf::from(path),
+            trackers: Vec::new(),
+            web_seeds: Vec::new(),
+            comment: None,
+            private: false,
+            source: None,
+            piece_length: None,
+            num_threads: thread::available_parallelism().map_or(1, |n| n.get()),
+        }
+    }
+
+    // Each tracker is placed in its own tier, in the order given.
+    pub(crate) fn tracker(mut self, url: &str) -> Self {
+        self.trackers.push(url.to_owned());
+        self
+    }
+
+    pub(crate) fn web_seed(mut self, url: &str) -> Self {
+        self.web_seeds.push(url.to_owned());
+        self
+    }
+
+    pub(crate) fn comment(mut self, comment: &str) -> Self {
+        self.comment = Some(comment.to_owned());
+        self
+    }
+
+    pub(crate) fn private(mut self, private: bool) -> Self {
+        self.private = private;
+        self
+    }
+
+    pub(crate) fn source(mut self, source: &str) -> Self {
+        self.source = Some(source.to_owned());
+        self
+    }
+
+    // Overrides the automatically chosen piece length. Must be a power of two of at least 16KiB.
+    pub(crate) fn piece_length(mut self, piece_length: u64) -> Self {
+        self.piece_length = Some(piece_length);
+        self
+    }
+
+    pub(crate) fn build(&self) -> Result<Metadata, Box<dyn Error>> {
+        let name = match self.path.file_name() {
+            Some(v) => v.to_string_lossy().into_owned(),
+            None => {
+                return Err(Box::new(IOError::new(
+                    ErrorKind::InvalidInput,
+                    "Torrent path has no file name",
+                )))
+            }
+        };
+
+        let single_file = self.path.is_file();
+        let files = if single_file {
+            vec![FilePathInfo {
+                length: fs::metadata(&self.path)?.len(),
+                path: vec![name.clone()],
+                ..Default::default()
+            }]
+        } else {
+            let mut files = Vec::new();
+            walk_dir(&self.path, &mut Vec::new(), &mut files)?;
+            files
+        };
+
+        let total_length: u64 = files.iter().map(|f| f.length).sum();
+        if total_length == 0 {
+            return Err(Box::new(IOError::new(
+                ErrorKind::InvalidInput,
+                "Cannot create a torrent with no data",
+            )));
+        }
+
+        let piece_length = match self.piece_length {
+            Some(v) if v >= MIN_PIECE_LENGTH && v.is_power_of_two() => v,
+            Some(v) => {
+                return Err(Box::new(IOError::new(
+                    ErrorKind::InvalidInput,
+                    format!("Invalid piece length: {v}"),
+                )))
+            }
+            None => choose_piece_length(total_length),
+        };
+
+        let paths: Vec<(PathBuf, u64)> = files
+            .iter()
+            .map(|f| match single_file {
+                true => (self.path.clone(), f.length),
+                false => (self.path.join(f.path.join("/")), f.length),
+            })
+            .collect();
+        let pieces = hash_pieces(&paths, piece_length, self.num_threads)?;
+
+        let info = FileInfo {
+            files,
+            disk_name: name.clone(),
+            name,
+            name_utf8: None,
+            piece_length,
+            pieces,
+            private: if self.private { Some(1) } else { None },
+            source: self.source.clone(),
+            single_file,
+            meta_version: None,
+            v2_files: Vec::new(),
+        };
+
+        let raw = self
+            .encode(&info)
+            .map_err(|e| IOError::new(ErrorKind::InvalidData, e.to_string()))?;
+        let md = Metadata::from_bencode(&raw)
+            .map_err(|e| IOError::new(ErrorKind::InvalidData, e.to_string()))?;
+
+        Ok(md)
+    }
+
+    fn encode(&self, info: &FileInfo) -> Result<Vec<u8>, bendy::encoding::Error> {
+        let created_by = format!("torrensic/{}", env!("CARGO_PKG_VERSION"));
+        let creation_date = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let announce_list: Vec<Vec<String>> =
+            self.trackers.iter().map(|t| vec![t.clone()]).collect();
+
+        let mut encoder = Encoder::new().with_max_depth(FileInfo::MAX_DEPTH + 1);
+        encoder.emit_dict(|mut e| {
+            if let Some(tracker) = self.trackers.first() {
+                e.emit_pair(b"announce", tracker)?;
+            }
+            if !self.trackers.is_empty() {
+                e.emit_pair(b"announce-list", &announce_list)?;
+            }
+            if let Some(comment) = &self.comment {
+                e.emit_pair(b"comment", comment)?;
+            }
+            e.emit_pair(b"created by", &created_by)?;
+            e.emit_pair(b"creation date", creation_date)?;
+            e.emit_pair(b"info", info)?;
+            if !self.web_seeds.is_empty() {
+                e.emit_pair(b"url-list", &self.web_seeds)?;
+            }
+            Ok(())
+        })?;
+
+        encoder.get_output()
+    }
+}
+
+fn choose_piece_length(total_length: u64) -> u64 {
+    (total_length / TARGET_NUM_PIECES)
+        .next_power_of_two()
+        .clamp(MIN_PIECE_LENGTH, MAX_PIECE_LENGTH)
+}
+
+// Collects regular files below `dir` in sorted order, so the same tree always yields the same
+// torrent. Symlinks are skipped.
+fn walk_dir(dir: &Path, prefix: &mut Vec<String>, files: &mut Vec<FilePathInfo>) -> io::Result<()> {
+    let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|e| e.file_name());
+
+    for entry in entries {
+        let file_type = entry.file_type()?;
+        prefix.push(entry.file_name().to_string_lossy().into_owned());
+
+        if file_type.is_dir() {
+            walk_dir(&entry.path(), prefix, files)?;
+        } else if file_type.is_file() {
+            files.push(FilePathInfo {
+                length: entry.metadata()?.len(),
+                path: prefix.clone(),
+                ..Default::default()
+            });
+        }
+
+        prefix.pop();
+    }
+
+    Ok(())
+}
+
+// Hashes every piece of the concatenated files, splitting the pieces into contiguous runs with
+// one thread per run.
+fn hash_pieces(
+    paths: &[(PathBuf, u64)],
+    piece_length: u64,
+    num_threads: usize,
+) -> io::Result<Vec<u8>> {
+    let total_length: u64 = paths.iter().map(|(_, len)| len).sum();
+    let num_pieces = total_length.div_ceil(piece_length);
+    let per_thread = num_pieces.div_ceil(num_threads.max(1) as u64);
+
+    let runs: Vec<io::Result<Vec<u8>>> = thread::scope(|s| {
+        let handles: Vec<_> = (0..num_pieces)
+            .step_by(per_thread.max(1) as usize)
+            .map(|first| {
+                let last = (first + per_thread).min(num_pieces);
+                s.spawn(move || {
+                    let mut hashes = Vec::new();
+                    for index in first..last {
+                        let offset = index * piece_length;
+                        let len = piece_length.min(total_length - offset);
+                        let data = read_range(paths, offset, len)?;
+                        hashes.extend_from_slice(&Sha1::digest(&data));
+                    }
+                    Ok(hashes)
+                })
+            })
+            .collect();
+
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    });
+
+    Ok(runs.into_iter().collect::<io::Result<Vec<_>>>()?.concat())
+}
+
+fn read_range(paths: &[(PathBuf, u64)], start: u64, len: u64) -> io::Result<Vec<u8>> {
+    let end = start + len;
+    let mut data = Vec::with_capacity(len.try_into().unwrap());
+    let mut cur_pos: u64 = 0;
+
+    for (path, length) in paths {
+        if cur_pos >= end {
+            break;
+        }
+        if cur_pos + length > start {
+            let mut f = File::open(path)?;
+            let file_start = start.saturating_sub(cur_pos);
+            let file_end = (end - cur_pos).min(*length);
+
+            f.seek(SeekFrom::Start(file_start))?;
+            f.take(file_end - file_start).read_to_end(&mut data)?;
+        }
+        cur_pos += length;
+    }
+
+    if data.len() as u64 != len {
+        return Err(IOError::new(
+            ErrorKind::UnexpectedEof,
+            "File changed while hashing",
+        ));
+    }
+
+    Ok(data)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::{parser::metadata::get_magnet_link, storage::test::temp_dir};
+
+    #[test]
+    fn piece_length_is_clamped_power_of_two() {
+        assert_eq!(choose_piece_length(1000), MIN_PIECE_LENGTH);
+        assert_eq!(choose_piece_length(1 << 30), 1 << 20);
+        assert_eq!(choose_piece_length(1 << 40), MAX_PIECE_LENGTH);
+    }
+
+    #[test]
+    fn builds_multi_file_torrent() {
+        let dir = Path::new(&temp_dir("create-multi")).join("content");
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        let a: Vec<u8> = (0..40_000u32).map(|i| (i % 251) as u8).collect();
+        let b: Vec<u8> = (0..5_000u32).map(|i| (i % 13) as u8).collect();
+        fs::write(dir.join("a.bin"), &a).unwrap();
+        fs::write(dir.join("sub").join("b.bin"), &b).unwrap();
+
+        let md = TorrentBuilder::new(dir.to_str().unwrap())
+            .tracker("http://tracker.example/announce")
+            .tracker("udp://backup.example:6969")
+            .web_seed("http://seed.example/")
+            .comment("build 42")
+            .source("lab")
+            .private(true)
+            .piece_length(16384)
+            .build()
+            .unwrap();
+
+        assert_eq!(md.info.name, "content");
+        assert!(!md.info.single_file);
+        assert_eq!(
+            md.info.files[1].path,
+            vec!["sub".to_owned(), "b.bin".to_owned()]
+        );
+        assert_eq!(md.info.private, Some(1));
+        assert_eq!(md.info.source.as_deref(), Some("lab"));
+        assert_eq!(md.announce_list.len(), 2);
+
+        let all = [a, b].concat();
+        for (i, piece) in all.chunks(16384).enumerate() {
+            assert_eq!(
+                &md.info.pieces[i * 20..(i + 1) * 20],
+                Sha1::digest(piece).as_slice()
+            );
+        }
+        assert!(get_magnet_link(&md).contains("&tr="));
+    }
+
+    #[test]
+    fn builds_single_file_torrent() {
+        let dir = temp_dir("create-single");
+        fs::create_dir_all(&dir).unwrap();
+        let path = Path::new(&dir).join("image.iso");
+        fs::write(&path, vec![7; 100_000]).unwrap();
+
+        let md = TorrentBuilder::new(path.to_str().unwrap())
+            .tracker("http://tracker.example/announce")
+            .build()
+            .unwrap();
+
+        assert!(md.info.single_file);
+        assert_eq!(md.info.name, "image.iso");
+        assert_eq!(md.total_length(), 100_000);
+        assert_eq!(md.num_pieces(), 100_000usize.div_ceil(16384));
+    }
+}
@@ -3,11 +3,14 @@ use std::{error::Error, fmt};
 pub mod admin_message;
 pub mod manager;
 mod peer_handler;
+pub mod peer_id;
+pub mod stats;
 mod strategy;
 
 #[derive(Debug)]
 pub(crate) enum ProtocolError {
     TorrentInfoAcquireFailed(String),
+    InvalidPeerId(String),
 }
 
 impl fmt::Display for ProtocolError {
@@ -16,6 +19,9 @@ impl fmt::Display for ProtocolError {
             ProtocolError::TorrentInfoAcquireFailed(ref msg) => {
                 write!(f, "{}", msg)
             }
+            ProtocolError::InvalidPeerId(ref msg) => {
+                write!(f, "Invalid peer ID: {}", msg)
+            }
         }
     }
 }
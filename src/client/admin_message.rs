@@ -1,12 +1,23 @@
 use std::sync::Arc;
 
+use bitvec::{prelude::Msb0, vec::BitVec};
 use tokio::sync::oneshot;
 
+use crate::parser::tracker_info::PeerInfo;
+
 pub(crate) enum AdminMessage {
     PeerBitfield(PeerBitfield),
     PieceIndexRequest(PieceIndexRequest),
     PieceDownload(PieceDownload),
+    PieceHashFailed(PieceHashFailed),
     PeerDisconnect(PeerDisconnect),
+    // Peers returned by a tracker announce.
+    NewPeers(NewPeers),
+    // Asks the manager to verify all data on disk, e.g. from the UI.
+    Recheck,
+    RecheckDone(RecheckDone),
+    // Retries writing to disk after an error paused the torrent.
+    RetryDisk,
 }
 
 pub(crate) struct PeerBitfield {
@@ -24,6 +35,18 @@ pub(crate) struct PieceDownload {
     pub index: u32,
 }
 
+pub(crate) struct PieceHashFailed {
+    pub index: u32,
+}
+
 pub(crate) struct PeerDisconnect {
     pub addr: Arc<str>,
 }
+
+pub(crate) struct NewPeers {
+    pub peers: Vec<PeerInfo>,
+}
+
+pub(crate) struct RecheckDone {
+    pub result: Result<BitVec<u8, Msb0>, String>,
+}
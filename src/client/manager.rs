@@ -1,99 +1,327 @@
-use std::{sync::Arc, time::Duration};
+use std::{collections::HashSet, sync::Arc, time::Duration};
 
 use bitvec::{prelude::Msb0, vec::BitVec};
 use tokio::{
     sync::{mpsc, watch, Mutex},
-    time,
+    task, time,
 };
 
 use crate::{
-    builder::file_builder,
     parser::{metadata::Metadata, tracker_info::PeerInfo},
+    storage::{self, disk_io::DiskIo},
+    torrent_info::tracker_session::TrackerHandle,
     utils::{self, ring_buffer::RingBuffer}
 };
 
 use super::{
-    admin_message::AdminMessage,
+    admin_message::{AdminMessage, RecheckDone},
     peer_handler::PeerHandler,
+    stats::{self, TransferStats},
     strategy::Strategy,
 };
 
 pub(crate) type BitVecMutex = Arc<Mutex<BitVec<u8, Msb0>>>;
 
+// Peers beyond this many are turned away until others disconnect.
+const MAX_PEERS: usize = 50;
+
 /* TODO for next time:
     - Combine peer handler / manager comms into one channel
     - Start looking at magnet links
 */
 pub(crate) struct Manager {
     md: Arc<Metadata>,
+    disk_io: Arc<DiskIo>,
+    // Where resume data is saved.
+    state_dir: Arc<str>,
+    // Where the torrent's data goes once complete, until it has been moved there.
+    completed_dir: Option<Arc<str>>,
+    stats: Arc<TransferStats>,
+    client_pieces: BitVecMutex,
+    // Addresses of every peer with a running handler.
+    peers: HashSet<Arc<str>>,
+    // Told once the download completes.
+    tracker: Option<TrackerHandle>,
     download_history: RingBuffer,
+    // Set while a recheck runs, during which no pieces are handed out.
+    checking: bool,
+    // Set while writing to disk fails. No pieces are handed out until a retry succeeds.
+    rx_disk_error: watch::Receiver<Option<String>>,
+    needs_recheck: bool,
+    // Pieces completed while a recheck was running, which the recheck may have missed.
+    downloaded_while_checking: Vec<u32>,
     // TODO: distinguish UI from peer handler channels
     tx_progress_bar: watch::Sender<(u32, u32)>,
     tx_in_progress: watch::Sender<Vec<bool>>,
     tx_downloaded: watch::Sender<Vec<bool>>,
     tx_speed: watch::Sender<f32>,
+    // Pieces checked and total while a recheck runs.
+    tx_checking: watch::Sender<Option<(u32, u32)>>,
+    tx_admin_message: mpsc::Sender<AdminMessage>,
     rx_admin_message: mpsc::Receiver<AdminMessage>,
 }
 
 impl Manager {
-    // All peer handler threads are started on creation
+    // Handlers for the initial peers are started on creation, and more are added as trackers
+    // return them. With `needs_recheck`, the data on disk is verified before any piece is
+    // downloaded.
+    #[allow(clippy::too_many_arguments)]
     pub(crate) fn new(
         md: Arc<Metadata>,
         peers: Arc<Vec<PeerInfo>>,
-        output_dir: &str,
+        disk_io: Arc<DiskIo>,
+        state_dir: &str,
+        completed_dir: Option<&str>,
+        stats: Arc<TransferStats>,
+        needs_recheck: bool,
         tx_progress_bar: watch::Sender<(u32, u32)>,
         tx_in_progress: watch::Sender<Vec<bool>>,
         tx_downloaded: watch::Sender<Vec<bool>>,
         tx_speed: watch::Sender<f32>,
+        tx_checking: watch::Sender<Option<(u32, u32)>>,
     ) -> Result<Self, Box<dyn std::error::Error>> {
         // TODO: do these need to be shared with peer handlers?
-        let client_pieces: BitVec<u8, Msb0> = file_builder::load_bitfield(&md, &output_dir)?;
+        let client_pieces: BitVec<u8, Msb0> = disk_io.bitfield();
         let client_pieces_ref = Arc::new(Mutex::new(client_pieces));
+        let rx_disk_error = disk_io.errors();
 
         // Peer handler channel
         let (tx_admin_message, rx_admin_message) = mpsc::channel(128);
 
-        let dir_ref: Arc<str> = Arc::from(output_dir);
-
-        for peer in peers.iter() {
-            PeerHandler::init(
-                md.clone(),
-                &peer.to_string(),
-                dir_ref.clone(),
-                client_pieces_ref.clone(),
-                tx_admin_message.clone(),
-            )
-        }
-
-        Ok(Manager {
+        let mut manager = Manager {
             md,
+            disk_io,
+            state_dir: Arc::from(state_dir),
+            completed_dir: completed_dir.map(Arc::from),
+            stats,
+            client_pieces: client_pieces_ref,
+            peers: HashSet::new(),
+            tracker: None,
             download_history: RingBuffer::new(15),
+            checking: false,
+            rx_disk_error,
+            needs_recheck,
+            downloaded_while_checking: Vec::new(),
             tx_progress_bar,
             tx_in_progress,
             tx_downloaded,
             tx_speed,
+            tx_checking,
+            tx_admin_message,
             rx_admin_message,
-        })
+        };
+        manager.add_peers(&peers);
+
+        Ok(manager)
+    }
+
+    // Used by the UI to send requests such as rechecks.
+    pub(crate) fn admin_sender(&self) -> mpsc::Sender<AdminMessage> {
+        self.tx_admin_message.clone()
+    }
+
+    pub(crate) fn set_tracker(&mut self, tracker: TrackerHandle) {
+        self.tracker = Some(tracker);
+    }
+
+    // Starts handlers for peers not connected yet, up to MAX_PEERS.
+    fn add_peers(&mut self, peers: &[PeerInfo]) {
+        for peer in peers {
+            if self.peers.len() >= MAX_PEERS {
+                break;
+            }
+            let addr: Arc<str> = peer.to_string().into();
+            if !self.peers.insert(addr.clone()) {
+                continue;
+            }
+
+            PeerHandler::init(
+                self.md.clone(),
+                &addr,
+                self.disk_io.clone(),
+                self.stats.clone(),
+                self.client_pieces.clone(),
+                self.tx_admin_message.clone(),
+            )
+        }
+    }
+
+    // Hashes everything in storage on a blocking thread, reporting back with RecheckDone.
+    fn start_recheck(&mut self) {
+        if self.checking {
+            return;
+        }
+        self.checking = true;
+
+        let md = self.md.clone();
+        let disk_io = self.disk_io.clone();
+        let tx_admin_message = self.tx_admin_message.clone();
+        let tx_checking = self.tx_checking.clone();
+        let _ = tx_checking.send(Some((0, md.geometry().num_pieces())));
+
+        task::spawn_blocking(move || {
+            // Cached pieces are written first, so the check sees them.
+            let result = disk_io
+                .flush_blocking()
+                .and_then(|_| {
+                    storage::recheck(disk_io.storage().as_ref(), |checked, total| {
+                        let _ = tx_checking.send(Some((checked, total)));
+                    })
+                })
+                .inspect(|bitfield| disk_io.replace_bitfield(bitfield.clone()))
+                .map_err(|e| e.to_string());
+
+            let _ = tx_admin_message.blocking_send(AdminMessage::RecheckDone(RecheckDone { result }));
+        });
+    }
+
+    // Lets the tracker session send `started`, which it only does the first time.
+    fn start_tracker(&self) {
+        if let Some(tracker) = &self.tracker {
+            tracker.start();
+        }
+    }
+
+    // Saves resume data in the background.
+    fn save_resume(&self) {
+        let disk_io = self.disk_io.clone();
+        let state_dir = self.state_dir.clone();
+        let stats = self.stats.clone();
+        tokio::spawn(async move {
+            let _ = disk_io
+                .save_resume(&state_dir, stats.uploaded(), stats.downloaded())
+                .await;
+        });
+    }
+
+    // Moves the data of a complete torrent to the completed directory, then saves resume data
+    // for its new location.
+    fn move_completed(&mut self) {
+        let dir = match self.completed_dir.take() {
+            Some(v) => v,
+            None => return,
+        };
+        let disk_io = self.disk_io.clone();
+        let state_dir = self.state_dir.clone();
+        let stats = self.stats.clone();
+        tokio::spawn(async move {
+            if disk_io.move_to(&dir).await.is_ok() {
+                let _ = disk_io
+                    .save_resume(&state_dir, stats.uploaded(), stats.downloaded())
+                    .await;
+            }
+        });
+    }
+
+    async fn finish_recheck(&mut self, done: RecheckDone, downloaded: &Mutex<Vec<bool>>) {
+        self.checking = false;
+        let _ = self.tx_checking.send(None);
+
+        // On failure the previous state is kept.
+        let mut bitfield = match done.result {
+            Ok(v) => v,
+            Err(_) => {
+                self.start_tracker();
+                return;
+            }
+        };
+
+        // The check itself may already see pieces flushed while it ran, so only these tell
+        // whether the torrent was finished by a download rather than found complete.
+        let downloaded_while_checking = !self.downloaded_while_checking.is_empty();
+        for index in self.downloaded_while_checking.drain(..) {
+            bitfield.set(index.try_into().unwrap(), true);
+        }
+        self.disk_io.replace_bitfield(bitfield.clone());
+        self.stats
+            .set_left(stats::bytes_left(&self.md.geometry(), bitfield.iter().by_vals()));
+        self.start_tracker();
+        self.save_resume();
+        if bitfield.all() {
+            // Pieces that finished the torrent while the recheck ran were never announced.
+            if downloaded_while_checking {
+                if let Some(tracker) = self.tracker.take() {
+                    tracker.completed();
+                }
+            }
+            self.move_completed();
+        }
+
+        *downloaded.lock().await = bitfield.iter().by_vals().collect();
+        *self.client_pieces.lock().await = bitfield;
     }
 
     async fn run(&mut self) {
         let mut ui_refresh_interval = time::interval(Duration::from_millis(60));
         let mut download_speed_interval = time::interval(Duration::from_millis(100));
+        // Cached pieces reach the disk at least this often.
+        let mut flush_interval = time::interval(Duration::from_secs(5));
+        let mut resume_interval = time::interval(Duration::from_secs(30));
 
         let in_progress = Arc::new(Mutex::new(vec![false; self.md.num_pieces()]));
-        let downloaded = Arc::new(Mutex::new(vec![false; self.md.num_pieces()]));
+        // Pieces already on disk from an earlier session are not downloaded again.
+        let on_disk: Vec<bool> = self.disk_io.bitfield().iter().by_vals().collect();
+        let downloaded = Arc::new(Mutex::new(on_disk));
 
         let mut strategy = Strategy::new(
-            self.md.num_pieces(),
+            self.md.geometry(),
             Arc::clone(&in_progress),
             Arc::clone(&downloaded),
         );
 
+        // Trackers are only told what is left once the data on disk is known.
+        if self.needs_recheck {
+            self.start_recheck();
+        } else {
+            self.start_tracker();
+            if downloaded.lock().await.iter().all(|&d| d) {
+                self.move_completed();
+            }
+        }
+
         loop {
             tokio::select! {
                 admin_message = self.rx_admin_message.recv() => {
                     let admin_message = admin_message.expect("Error receiving message");
-                    let _ = strategy.handle_message(admin_message).await;
+                    match admin_message {
+                        AdminMessage::Recheck => self.start_recheck(),
+                        AdminMessage::RecheckDone(done) => self.finish_recheck(done, &downloaded).await,
+                        AdminMessage::NewPeers(req) => self.add_peers(&req.peers),
+                        AdminMessage::RetryDisk => {
+                            let disk_io = self.disk_io.clone();
+                            tokio::spawn(async move {
+                                let _ = disk_io.retry().await;
+                            });
+                        }
+                        AdminMessage::PieceIndexRequest(req)
+                            if self.checking || self.rx_disk_error.borrow().is_some() =>
+                        {
+                            let _ = req.chan.send(None);
+                        }
+                        admin_message => {
+                            let is_download = matches!(admin_message, AdminMessage::PieceDownload(_));
+                            match &admin_message {
+                                AdminMessage::PieceDownload(req) if self.checking => {
+                                    self.downloaded_while_checking.push(req.index);
+                                }
+                                AdminMessage::PeerDisconnect(req) => {
+                                    self.peers.remove(&req.addr);
+                                }
+                                _ => {}
+                            }
+                            let _ = strategy.handle_message(admin_message).await;
+                            if is_download && !self.checking {
+                                let pieces = downloaded.lock().await;
+                                self.stats.set_left(stats::bytes_left(&self.md.geometry(), pieces.iter().copied()));
+                                if pieces.iter().all(|&d| d) {
+                                    if let Some(tracker) = self.tracker.take() {
+                                        tracker.completed();
+                                    }
+                                    self.move_completed();
+                                }
+                            }
+                        }
+                    }
                 }
                 _ = ui_refresh_interval.tick() => {
                     let _ = self.tx_progress_bar.send((
@@ -104,10 +332,17 @@ impl Manager {
                     let _ = self.tx_in_progress.send(in_progress.lock().await.clone());
                     let _ = self.tx_downloaded.send(downloaded.lock().await.clone());
 
-                    let speed = (self.download_history.average() / 0.1) * (self.md.info.piece_length as f32 / 1_000.0);
+                    let speed = (self.download_history.average() / 0.1) * (self.md.geometry().piece_length() as f32 / 1_000.0);
 
                     let _ = self.tx_speed.send(speed);
                 }
+                _ = flush_interval.tick() => {
+                    let disk_io = self.disk_io.clone();
+                    tokio::spawn(async move {
+                        let _ = disk_io.flush().await;
+                    });
+                }
+                _ = resume_interval.tick() => self.save_resume(),
                 _ = download_speed_interval.tick() => {
                     self.download_history.push(utils::count_ones(&downloaded.lock().await.to_vec()));
                 }
@@ -121,3 +356,73 @@ pub(crate) async fn run_peer_manager_task(mut peer_manager: Manager) {
     peer_manager.run().await;
 }
 
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage::{
+        memory_storage::MemoryStorage,
+        resume::ResumeData,
+        test::{temp_dir, two_file_torrent},
+        Storage,
+    };
+
+    // A manager over in-memory storage holding the torrent's data with piece 2 corrupted, with
+    // its disk I/O.
+    fn manager(
+        name: &str,
+        needs_recheck: bool,
+        tx_checking: watch::Sender<Option<(u32, u32)>>,
+    ) -> (Manager, Arc<DiskIo>) {
+        let (md, data) = two_file_torrent();
+        let md = Arc::new(md);
+        let storage = MemoryStorage::new(md.clone());
+        storage.allocate().unwrap();
+        for (index, piece) in data.chunks(16384).enumerate() {
+            storage.write_block(index as u32, 0, piece).unwrap();
+        }
+        storage.write_block(2, 100, &[0xff]).unwrap();
+        let disk_io = Arc::new(DiskIo::new(Arc::new(storage), &ResumeData::new(&md)));
+
+        let manager = Manager::new(
+            md,
+            Arc::new(Vec::new()),
+            disk_io.clone(),
+            &temp_dir(name),
+            None,
+            Arc::new(TransferStats::new(0, 0, 50_000)),
+            needs_recheck,
+            watch::channel((0, 0)).0,
+            watch::channel(Vec::new()).0,
+            watch::channel(Vec::new()).0,
+            watch::channel(0.0).0,
+            tx_checking,
+        )
+        .unwrap();
+        (manager, disk_io)
+    }
+
+    #[tokio::test]
+    async fn rechecks_on_mismatch_and_on_demand() {
+        let (tx_checking, mut rx_checking) = watch::channel(None);
+        let (manager, disk_io) = manager("manager-recheck", true, tx_checking);
+        let tx_admin_message = manager.admin_sender();
+        tokio::spawn(run_peer_manager_task(manager));
+
+        // A mismatch found on startup is checked before anything else, reporting progress.
+        rx_checking.changed().await.unwrap();
+        let progress = *rx_checking.borrow_and_update();
+        assert!(matches!(progress, Some((checked, 4)) if checked <= 4), "{progress:?}");
+        rx_checking.wait_for(Option::is_none).await.unwrap();
+        let on_disk: Vec<usize> = disk_io.bitfield().iter_ones().collect();
+        assert_eq!(on_disk, vec![0, 1, 3]);
+
+        // Pieces gone bad since are caught by a recheck asked for from the UI.
+        disk_io.storage().write_block(0, 0, &[0xff]).unwrap();
+        tx_admin_message.send(AdminMessage::Recheck).await.unwrap();
+        rx_checking.changed().await.unwrap();
+        rx_checking.wait_for(Option::is_none).await.unwrap();
+        let on_disk: Vec<usize> = disk_io.bitfield().iter_ones().collect();
+        assert_eq!(on_disk, vec![1, 3]);
+    }
+}
@@ -1,7 +1,7 @@
 mod connection;
 mod message;
 
-use std::cmp::min;
+use std::collections::{HashMap, HashSet};
 use std::io::{Error as IOError, ErrorKind};
 use std::mem;
 use std::sync::Arc;
@@ -12,25 +12,38 @@ use tokio::sync::mpsc::{self};
 use tokio::sync::oneshot;
 
 use message::bitfield::Bitfield;
+use message::hash_reject::HashReject;
+use message::hash_request::HashRequest;
+use message::hashes::Hashes;
 use message::have::Have;
 use message::piece::Piece;
+use message::request::Request;
 use message::Message;
 
-use crate::builder::file_builder;
-use crate::client::manager::BitVecMutex;
-use crate::parser::metadata::Metadata;
+use crate::client::{manager::BitVecMutex, stats::TransferStats};
+use crate::parser::{
+    geometry::Geometry,
+    merkle,
+    metadata::{Metadata, PieceCheck},
+};
+use crate::storage::disk_io::DiskIo;
 
 use connection::Connection;
 
 use super::admin_message::{
-    AdminMessage, PeerBitfield, PeerDisconnect, PieceDownload, PieceIndexRequest,
+    AdminMessage, PeerBitfield, PeerDisconnect, PieceDownload, PieceHashFailed, PieceIndexRequest,
 };
 
+// Larger requests are ignored rather than served.
+const MAX_REQUEST_LEN: u32 = 128 * 1024;
+
 pub struct PeerHandler {
     peer_state: PeerState,
     md: Arc<Metadata>,
+    geometry: Geometry,
     addr: Arc<str>,
-    output_dir: Arc<str>,
+    disk_io: Arc<DiskIo>,
+    stats: Arc<TransferStats>,
     client_pieces: BitVecMutex,
     tx_admin_message: mpsc::Sender<AdminMessage>,
 }
@@ -39,7 +52,8 @@ impl PeerHandler {
     pub(crate) fn init(
         md: Arc<Metadata>,
         addr: &str,
-        output_dir: Arc<str>,
+        disk_io: Arc<DiskIo>,
+        stats: Arc<TransferStats>,
         client_pieces: BitVecMutex,
         tx_admin_message: mpsc::Sender<AdminMessage>,
     ) {
@@ -50,9 +64,11 @@ impl PeerHandler {
                 peer_choked: true,
                 peer_interested: false,
             },
+            geometry: md.geometry(),
             md,
             addr: addr.into(),
-            output_dir,
+            disk_io,
+            stats,
             client_pieces,
             tx_admin_message,
         };
@@ -73,20 +89,26 @@ impl PeerHandler {
         };
 
         let mut block_index: u32 = 0;
+        // Blocks of the current piece that hold real data, worked out once per piece.
+        let mut data_blocks: u32 = 0;
         let mut piece_index = None;
 
         {
             // Acquire client_pieces mutex to send bitfield message to peer and determine piece index.
             // TODO: get this from the manager
-            let mut pieces = self.client_pieces.lock().await;
+            let pieces = self.client_pieces.lock().await;
             let bitfield_msg = Bitfield {
                 bitfield: bitvec_to_bytes(&pieces),
             };
             let _ = conn.push(Message::from(bitfield_msg)).await?;
-            pieces.set(0, true);
         }
 
-        let mut data_buf = vec![0; self.md.info.piece_length.try_into().unwrap()];
+        let piece_length: usize = self.geometry.piece_length().try_into().unwrap();
+        let mut data_buf = vec![0; piece_length];
+
+        // v2 piece layers missing from the metadata, as requested from and sent by this peer.
+        let mut requested_layers = HashSet::new();
+        let mut received_layers = HashMap::new();
 
         loop {
             let msg = tokio::select! {
@@ -104,12 +126,6 @@ impl PeerHandler {
             let msg = match msg {
                 Ok(v) => v,
                 Err(_) => {
-                    let _ = self
-                        .tx_admin_message
-                        .send(AdminMessage::PeerDisconnect(PeerDisconnect {
-                            addr: self.addr.clone(),
-                        }))
-                        .await;
                     return Err(Box::new(IOError::new(
                         ErrorKind::ConnectionReset,
                         "Connection reset by peer",
@@ -133,10 +149,11 @@ impl PeerHandler {
                     // Request piece index from peer manager
                     piece_index = self.get_piece_index().await;
                     self.peer_state.client_interested = piece_index.is_some();
+                    (data_buf, block_index, data_blocks) = self.start_piece(piece_index).await;
 
                     if let Some(index) = piece_index {
                         if !peer_state.client_choked {
-                            conn.request_block(&self.md, index, block_index).await?;
+                            conn.request_block(&self.geometry, index, block_index).await?;
                         } else {
                             conn.send_interested().await?;
                         }
@@ -147,6 +164,7 @@ impl PeerHandler {
 
                     if piece_index.is_none() {
                         piece_index = self.get_piece_index().await;
+                        (data_buf, block_index, data_blocks) = self.start_piece(piece_index).await;
                     }
                 }
                 Message::Piece(Piece {
@@ -155,29 +173,59 @@ impl PeerHandler {
                     block,
                 }) => {
                     // TODO: properly handle case where piece_index is None
+                    // Blocks of the wrong size are dropped, so the piece is never assembled
+                    // with a gap.
                     if index == piece_index.unwrap_or(u32::MAX)
-                        && begin == (block_index * (2 << 13))
+                        && begin == self.geometry.block_offset(block_index)
+                        && block.len() == self.geometry.block_len(index, block_index) as usize
                     {
                         let begin_usize: usize = begin.try_into().unwrap();
-                        let block_len = min(block.len(), 2 << 13);
+                        let block_len = block.len();
                         data_buf.splice(begin_usize..begin_usize + block_len, block);
-
-                        if block_index + 1 == self.md.num_blocks() {
-                            let data = mem::replace(
-                                &mut data_buf,
-                                vec![0; self.md.info.piece_length.try_into().unwrap()],
-                            );
-                            file_builder::write(&self.md, &self.output_dir, index, 0, &data)?;
-                            let _ = self
-                                .tx_admin_message
-                                .send(AdminMessage::PieceDownload(PieceDownload { index }))
-                                .await;
+                        self.disk_io.write_block(
+                            index,
+                            block_index,
+                            &data_buf[begin_usize..begin_usize + block_len],
+                        );
+                        self.stats.add_downloaded(block_len as u64);
+
+                        // Blocks past the data blocks are padding and stay zero in the buffer.
+                        if block_index + 1 == data_blocks {
+                            let mut data = mem::take(&mut data_buf);
+                            data.truncate(self.geometry.piece_len(index).try_into().unwrap());
+
+                            match self.md.verify_piece(index, &data, &received_layers) {
+                                PieceCheck::Valid => {
+                                    self.disk_io.write_piece(index, data).await;
+                                    let _ = self
+                                        .tx_admin_message
+                                        .send(AdminMessage::PieceDownload(PieceDownload {
+                                            index,
+                                        }))
+                                        .await;
+                                }
+                                check => {
+                                    self.disk_io.discard_partial(index);
+                                    // Ask for a missing layer, so the piece can be verified when
+                                    // it is next downloaded.
+                                    if let PieceCheck::MissingLayer(root) = check {
+                                        if requested_layers.insert(root.clone()) {
+                                            self.request_piece_layer(&mut conn, root).await?;
+                                        }
+                                    }
+                                    let _ = self
+                                        .tx_admin_message
+                                        .send(AdminMessage::PieceHashFailed(PieceHashFailed {
+                                            index,
+                                        }))
+                                        .await;
+                                }
+                            }
 
                             // Request piece from peer manager - if no valid ones, we are no longer interested in peer.
                             piece_index = self.get_piece_index().await;
                             self.peer_state.client_interested = piece_index.is_some();
-
-                            block_index = 0;
+                            (data_buf, block_index, data_blocks) = self.start_piece(piece_index).await;
                         } else {
                             block_index += 1;
                         }
@@ -185,7 +233,7 @@ impl PeerHandler {
                         // Request next block
                         if !peer_state.client_choked && peer_state.peer_interested {
                             if let Some(index) = piece_index {
-                                conn.request_block(&self.md, index, block_index).await?;
+                                conn.request_block(&self.geometry, index, block_index).await?;
                             }
                         } else {
                             conn.send_interested().await?;
@@ -208,7 +256,7 @@ impl PeerHandler {
                         peer_state.client_interested = true;
                     }
                     if let Some(index) = piece_index {
-                        conn.request_block(&self.md, index, block_index).await?;
+                        conn.request_block(&self.geometry, index, block_index).await?;
                     }
                 }
                 Message::Interested(_) => {
@@ -216,11 +264,91 @@ impl PeerHandler {
                     peer_state.peer_choked = false;
                 }
                 Message::NotInterested(_) => peer_state.peer_interested = false,
+                Message::Request(Request {
+                    index,
+                    begin,
+                    length,
+                }) => {
+                    // Only verified pieces on disk are served, and only to peers we aren't
+                    // choking.
+                    let have = self.disk_io.has_piece(index);
+                    if have && !peer_state.peer_choked && length <= MAX_REQUEST_LEN {
+                        if let Ok(block) = self.disk_io.read_block(index, begin, length).await {
+                            self.stats.add_uploaded(block.len() as u64);
+                            conn.push(Message::from(Piece {
+                                index,
+                                begin,
+                                block,
+                            }))
+                            .await?;
+                        }
+                    }
+                }
+                Message::HashRequest(HashRequest {
+                    pieces_root,
+                    base_layer,
+                    index,
+                    length,
+                    proof_layers,
+                }) => {
+                    let reply = match self.md.layer_hashes(
+                        &pieces_root,
+                        base_layer,
+                        index,
+                        length,
+                        proof_layers,
+                    ) {
+                        Some(hashes) => Message::from(Hashes {
+                            pieces_root,
+                            base_layer,
+                            index,
+                            length,
+                            proof_layers,
+                            hashes,
+                        }),
+                        None => Message::from(HashReject {
+                            pieces_root,
+                            base_layer,
+                            index,
+                            length,
+                            proof_layers,
+                        }),
+                    };
+                    conn.push(reply).await?;
+                }
+                Message::Hashes(hashes) => {
+                    if let Some(layer) = self.accept_piece_layer(&hashes) {
+                        received_layers.insert(hashes.pieces_root, layer);
+                    }
+                }
                 _ => continue,
             }
         }
     }
 
+    // A buffer for the piece, the first block to request and the number of data blocks, carrying
+    // on from blocks received earlier by any peer or in an earlier session. Blocks are requested
+    // in order, so only the leading run of received blocks is used.
+    async fn start_piece(&self, piece_index: Option<u32>) -> (Vec<u8>, u32, u32) {
+        let piece_length: usize = self.geometry.piece_length().try_into().unwrap();
+        let index = match piece_index {
+            Some(v) => v,
+            None => return (vec![0; piece_length], 0, 0),
+        };
+
+        let data_blocks = self.md.num_data_blocks(index);
+        match self.disk_io.partial_piece(index).await {
+            Ok(Some((mut data, blocks))) => {
+                data.resize(piece_length, 0);
+                // The last data block completes the piece, so it is always requested.
+                let last = data_blocks.saturating_sub(1);
+                let next: u32 = blocks.leading_ones().try_into().unwrap();
+                (data, next.min(last), data_blocks)
+            }
+            _ => (vec![0; piece_length], 0, data_blocks),
+        }
+    }
+
     async fn send_bitfield_update(&self, bitfield: Vec<bool>) {
         let (tx, rx) = oneshot::channel();
 
@@ -263,8 +391,57 @@ impl PeerHandler {
         }
     }
 
+    // Requests the whole piece layer for a v2 file, which is checked against the pieces root when
+    // it arrives.
+    async fn request_piece_layer(
+        &self,
+        conn: &mut Connection,
+        pieces_root: Vec<u8>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let file = match self.md.info.v2_file(&pieces_root) {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+        let num_pieces = file.length.div_ceil(self.md.info.piece_length);
+
+        let msg = Message::from(HashRequest {
+            pieces_root,
+            base_layer: merkle::piece_layer_index(self.md.info.piece_length),
+            index: 0,
+            length: num_pieces.next_power_of_two().try_into().unwrap(),
+            proof_layers: 0,
+        });
+        conn.push(msg).await
+    }
+
+    // Returns the piece layer from a hashes message, if it is complete and matches the pieces root.
+    fn accept_piece_layer(&self, hashes: &Hashes) -> Option<Vec<u8>> {
+        let piece_length = self.md.info.piece_length;
+        let file = self.md.info.v2_file(&hashes.pieces_root)?;
+        if hashes.base_layer != merkle::piece_layer_index(piece_length) || hashes.index != 0 {
+            return None;
+        }
+
+        let num_pieces: usize = file.length.div_ceil(piece_length).try_into().unwrap();
+        let layer = hashes.hashes.get(..num_pieces * 32)?;
+        let root = merkle::layer_root(&merkle::split_layer(layer)?, piece_length);
+
+        if root.as_slice() == hashes.pieces_root.as_slice() {
+            Some(layer.to_vec())
+        } else {
+            None
+        }
+    }
+
     async fn start(mut proto_task: PeerHandler) {
         let _ = proto_task.run().await;
+        // However the connection ended, the manager can now forget the peer.
+        let _ = proto_task
+            .tx_admin_message
+            .send(AdminMessage::PeerDisconnect(PeerDisconnect {
+                addr: proto_task.addr.clone(),
+            }))
+            .await;
     }
 }
 
@@ -12,7 +12,7 @@ use tokio::{
 };
 
 use read_task::{run_read_task, ReadTask};
-use crate::parser::metadata::Metadata;
+use crate::parser::{geometry::Geometry, metadata::Metadata};
 
 use self::handshake::handshake;
 use super::message::interested::Interested;
@@ -85,14 +85,14 @@ impl Connection {
 
     pub(crate) async fn request_block(
         &mut self,
-        md: &Metadata,
+        geometry: &Geometry,
         piece_index: u32,
         block_index: u32,
     ) -> Result<(), Box<dyn Error>> {
         let request_msg = Message::from(Request {
             index: piece_index,
-            begin: block_index * (2 << 13),
-            length: md.block_len(piece_index, block_index),
+            begin: geometry.block_offset(block_index),
+            length: geometry.block_len(piece_index, block_index),
         });
         self.wr.write_all(&request_msg.serialise()).await?;
         Ok(())
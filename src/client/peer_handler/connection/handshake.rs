@@ -6,6 +6,7 @@ use tokio::{
     net::TcpStream,
 };
 
+use crate::client::peer_id;
 use crate::parser::metadata::Metadata;
 
 pub(crate) async fn handshake(
@@ -15,9 +16,13 @@ pub(crate) async fn handshake(
 ) -> Result<Vec<u8>, Box<dyn Error>> {
     let pstr: Vec<u8> = b"BitTorrent protocol".to_vec();
     let pstrlen: Vec<u8> = vec![pstr.len().try_into().unwrap()];
-    let reserved: Vec<u8> = vec![0; 8];
+    let mut reserved: Vec<u8> = vec![0; 8];
+    if md.info.is_v2() {
+        // BEP 52: advertise support for the v2 protocol.
+        reserved[7] |= 0x10;
+    }
     let info_hash = &md.info_hash;
-    let peer_id: Vec<u8> = b"-TO0000-0123456789AB".to_vec();
+    let peer_id = peer_id::session().as_bytes();
 
     let msg = [
         pstrlen.as_slice(),
@@ -39,6 +44,14 @@ pub(crate) async fn handshake(
         )));
     }
 
+    // Drop connections to ourselves, e.g. when a tracker hands back our own address.
+    if n >= 68 && buf[48..68] == peer_id[..] {
+        return Err(Box::new(IOError::new(
+            ErrorKind::AddrInUse,
+            "Connected to own peer ID",
+        )));
+    }
+
     let remaining: Vec<u8> = if n > 68 { buf[68..n].to_vec() } else { Vec::new() };
 
     Ok(remaining)
@@ -4,7 +4,8 @@ use byteorder::{BigEndian, ReadBytesExt};
 use enum_dispatch::enum_dispatch;
 
 use self::{
-    bitfield::Bitfield, cancel::Cancel, choke::Choke, have::Have, interested::Interested,
+    bitfield::Bitfield, cancel::Cancel, choke::Choke, hash_reject::HashReject,
+    hash_request::HashRequest, hashes::Hashes, have::Have, interested::Interested,
     keep_alive::KeepAlive, not_interested::NotInterested, piece::Piece, request::Request,
     unchoke::Unchoke,
 };
@@ -12,6 +13,9 @@ use self::{
 pub mod bitfield;
 pub mod cancel;
 pub mod choke;
+pub mod hash_reject;
+pub mod hash_request;
+pub mod hashes;
 pub mod have;
 pub mod interested;
 pub mod keep_alive;
@@ -75,6 +79,9 @@ pub enum Message {
     Request(Request),
     Piece(Piece),
     Cancel(Cancel),
+    HashRequest(HashRequest),
+    Hashes(Hashes),
+    HashReject(HashReject),
 }
 
 pub fn parse(raw: &Vec<u8>) -> Result<(Option<Message>, Vec<u8>), ()> {
@@ -207,6 +214,45 @@ pub fn parse(raw: &Vec<u8>) -> Result<(Option<Message>, Vec<u8>), ()> {
                 rem,
             ));
         }
+        21..=23 => {
+            // pieces root, base layer, index, length and proof layers, plus hashes for id 22
+            if len_prefix < 49 || (id != 22 && len_prefix != 49) {
+                return Err(());
+            }
+            let pieces_root = raw[5..37].to_vec();
+            let mut fields = &raw[37..53];
+
+            let base_layer = fields.read_u32::<BigEndian>().unwrap();
+            let index = fields.read_u32::<BigEndian>().unwrap();
+            let length = fields.read_u32::<BigEndian>().unwrap();
+            let proof_layers = fields.read_u32::<BigEndian>().unwrap();
+
+            let msg = match id {
+                21 => Message::from(HashRequest {
+                    pieces_root,
+                    base_layer,
+                    index,
+                    length,
+                    proof_layers,
+                }),
+                22 => Message::from(Hashes {
+                    pieces_root,
+                    base_layer,
+                    index,
+                    length,
+                    proof_layers,
+                    hashes: raw[53..msg_len].to_vec(),
+                }),
+                _ => Message::from(HashReject {
+                    pieces_root,
+                    base_layer,
+                    index,
+                    length,
+                    proof_layers,
+                }),
+            };
+            Ok((Some(msg), rem))
+        }
         _ => Err(()),
     }
 }
@@ -299,4 +345,40 @@ mod test {
 
         assert_eq!(raw, serialised);
     }
+
+    fn hash_message(id: u8, extra: &[u8]) -> Vec<u8> {
+        let len: u32 = (49 + extra.len()).try_into().unwrap();
+        [
+            len.to_be_bytes().to_vec(),
+            vec![id],
+            vec![9; 32],
+            vec![0, 0, 0, 3, 0, 0, 0, 4, 0, 0, 0, 2, 0, 0, 0, 1],
+            extra.to_vec(),
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn parse_serialise_preserves_hash_request() {
+        let raw = hash_message(21, &[]);
+        let serialised = parse_then_serialise(&raw);
+
+        assert_eq!(raw, serialised);
+    }
+
+    #[test]
+    fn parse_serialise_preserves_hashes() {
+        let raw = hash_message(22, &[5; 64]);
+        let serialised = parse_then_serialise(&raw);
+
+        assert_eq!(raw, serialised);
+    }
+
+    #[test]
+    fn parse_serialise_preserves_hash_reject() {
+        let raw = hash_message(23, &[]);
+        let serialised = parse_then_serialise(&raw);
+
+        assert_eq!(raw, serialised);
+    }
 }
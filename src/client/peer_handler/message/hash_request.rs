@@ -0,0 +1,29 @@
+use super::PeerWireMessage;
+
+pub struct HashRequest {
+    pub pieces_root: Vec<u8>,
+    pub base_layer: u32,
+    pub index: u32,
+    pub length: u32,
+    pub proof_layers: u32,
+}
+
+impl PeerWireMessage for HashRequest {
+    fn id(&self) -> Option<u8> {
+        Some(21)
+    }
+
+    fn payload(&self) -> Vec<u8> {
+        [
+            self.pieces_root.clone(),
+            [self.base_layer, self.index, self.length, self.proof_layers]
+                .map(u32::to_be_bytes)
+                .concat(),
+        ]
+        .concat()
+    }
+
+    fn name(&self) -> String {
+        String::from("hash request")
+    }
+}
@@ -0,0 +1,31 @@
+use super::PeerWireMessage;
+
+pub struct Hashes {
+    pub pieces_root: Vec<u8>,
+    pub base_layer: u32,
+    pub index: u32,
+    pub length: u32,
+    pub proof_layers: u32,
+    pub hashes: Vec<u8>,
+}
+
+impl PeerWireMessage for Hashes {
+    fn id(&self) -> Option<u8> {
+        Some(22)
+    }
+
+    fn payload(&self) -> Vec<u8> {
+        [
+            self.pieces_root.clone(),
+            [self.base_layer, self.index, self.length, self.proof_layers]
+                .map(u32::to_be_bytes)
+                .concat(),
+            self.hashes.clone(),
+        ]
+        .concat()
+    }
+
+    fn name(&self) -> String {
+        String::from("hashes")
+    }
+}
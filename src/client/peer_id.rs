@@ -0,0 +1,113 @@
+use std::sync::OnceLock;
+
+use rand::Rng;
+
+use super::ProtocolError;
+
+// Azureus-style client prefix: "-TS" followed by a four character version and a trailing dash.
+const CLIENT_CODE: &str = "TS";
+
+static SESSION_PEER_ID: OnceLock<PeerId> = OnceLock::new();
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) struct PeerId([u8; 20]);
+
+impl PeerId {
+    // Generates a fresh ID of the form -TS0100-xxxxxxxxxxxx, with the version taken from the crate.
+    pub(crate) fn generate() -> Self {
+        let mut id = [0; 20];
+        id[..8].copy_from_slice(Self::prefix().as_bytes());
+        rand::thread_rng().fill(&mut id[8..]);
+        PeerId(id)
+    }
+
+    // Accepts either a full 20 byte ID, or a shorter prefix which is padded with random bytes.
+    pub(crate) fn parse(raw: &str) -> Result<Self, ProtocolError> {
+        let raw = raw.as_bytes();
+        if raw.is_empty() || raw.len() > 20 {
+            return Err(ProtocolError::InvalidPeerId(format!(
+                "peer ID must be between 1 and 20 bytes, got {}",
+                raw.len()
+            )));
+        }
+
+        let mut id = [0; 20];
+        id[..raw.len()].copy_from_slice(raw);
+        rand::thread_rng().fill(&mut id[raw.len()..]);
+        Ok(PeerId(id))
+    }
+
+    pub(crate) fn as_bytes(&self) -> &[u8; 20] {
+        &self.0
+    }
+
+    fn prefix() -> String {
+        let version = [
+            env!("CARGO_PKG_VERSION_MAJOR"),
+            env!("CARGO_PKG_VERSION_MINOR"),
+            env!("CARGO_PKG_VERSION_PATCH"),
+        ]
+        .map(|v| Self::version_char(v.parse().unwrap_or(0)));
+
+        format!("-{CLIENT_CODE}{}{}{}0-", version[0], version[1], version[2])
+    }
+
+    // Each version component is packed into a single character: 0-9, then A-Z.
+    fn version_char(v: u32) -> char {
+        std::char::from_digit(v.min(35), 36)
+            .unwrap()
+            .to_ascii_uppercase()
+    }
+}
+
+// Sets the peer ID used for the rest of the session. Must be called before the first call to session().
+pub(crate) fn init(peer_id_override: Option<&str>) -> Result<&'static PeerId, ProtocolError> {
+    let peer_id = match peer_id_override {
+        Some(v) => PeerId::parse(v)?,
+        None => PeerId::generate(),
+    };
+
+    SESSION_PEER_ID
+        .set(peer_id)
+        .map_err(|_| ProtocolError::InvalidPeerId("session peer ID already set".to_owned()))?;
+
+    Ok(session())
+}
+
+// Returns the peer ID shared by all trackers and peer connections in this session.
+pub(crate) fn session() -> &'static PeerId {
+    SESSION_PEER_ID.get_or_init(PeerId::generate)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn generated_id_has_azureus_prefix() {
+        let id = PeerId::generate();
+        assert_eq!(&id.as_bytes()[..8], b"-TS0100-");
+    }
+
+    #[test]
+    fn generated_ids_differ() {
+        assert!(PeerId::generate() != PeerId::generate());
+    }
+
+    #[test]
+    fn parse_pads_short_override() {
+        let id = PeerId::parse("-XX1234-").unwrap();
+        assert_eq!(&id.as_bytes()[..8], b"-XX1234-");
+    }
+
+    #[test]
+    fn parse_keeps_full_override() {
+        let id = PeerId::parse("-XX1234-abcdefghijkl").unwrap();
+        assert_eq!(id.as_bytes(), b"-XX1234-abcdefghijkl");
+    }
+
+    #[test]
+    fn parse_rejects_long_override() {
+        assert!(PeerId::parse("-XX1234-abcdefghijklm").is_err());
+    }
+}
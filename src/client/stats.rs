@@ -0,0 +1,69 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::parser::geometry::Geometry;
+
+// Bytes transferred for a torrent over its lifetime, shared by every peer handler and carried
+// across sessions in the resume data. Also tracks how much is left to download, as reported to
+// trackers.
+pub(crate) struct TransferStats {
+    uploaded: AtomicU64,
+    downloaded: AtomicU64,
+    left: AtomicU64,
+}
+
+impl TransferStats {
+    pub(crate) fn new(uploaded: u64, downloaded: u64, left: u64) -> Self {
+        TransferStats {
+            uploaded: AtomicU64::new(uploaded),
+            downloaded: AtomicU64::new(downloaded),
+            left: AtomicU64::new(left),
+        }
+    }
+
+    pub(crate) fn add_uploaded(&self, bytes: u64) {
+        self.uploaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_downloaded(&self, bytes: u64) {
+        self.downloaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn uploaded(&self) -> u64 {
+        self.uploaded.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn downloaded(&self) -> u64 {
+        self.downloaded.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_left(&self, bytes: u64) {
+        self.left.store(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn left(&self) -> u64 {
+        self.left.load(Ordering::Relaxed)
+    }
+}
+
+// Bytes in the pieces that aren't verified yet, given whether each piece is.
+pub(crate) fn bytes_left(geometry: &Geometry, have: impl IntoIterator<Item = bool>) -> u64 {
+    have.into_iter()
+        .zip(0..geometry.num_pieces())
+        .filter(|(have, _)| !have)
+        .map(|(_, index)| u64::from(geometry.piece_len(index)))
+        .sum()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn bytes_left_counts_missing_pieces() {
+        let geometry = Geometry::new(50_000, 16384);
+        assert_eq!(bytes_left(&geometry, [false; 4]), 50_000);
+        assert_eq!(bytes_left(&geometry, [true, false, true, true]), 16384);
+        assert_eq!(bytes_left(&geometry, [true, true, true, false]), 848);
+        assert_eq!(bytes_left(&geometry, [true; 4]), 0);
+    }
+}
@@ -2,6 +2,8 @@ use std::{collections::HashMap, sync::Arc};
 
 use tokio::sync::Mutex;
 
+use crate::parser::geometry::Geometry;
+
 use super::admin_message::AdminMessage;
 
 pub(crate) struct Strategy {
@@ -15,10 +17,11 @@ pub(crate) struct Strategy {
 
 impl Strategy {
     pub fn new(
-        num_pieces: usize,
+        geometry: Geometry,
         in_progress: Arc<Mutex<Vec<bool>>>,
         downloaded: Arc<Mutex<Vec<bool>>>,
     ) -> Self {
+        let num_pieces: usize = geometry.num_pieces().try_into().unwrap();
         return Strategy {
             peer_bitfield_map: HashMap::new(),
             num_pieces,
@@ -53,9 +56,19 @@ impl Strategy {
                     self.endgame_mode = prog.iter().zip(down.iter()).all(|(&a, &b)| a || b);
                 }
             }
+            AdminMessage::PieceHashFailed(req) => {
+                // Return the piece to the pool so it is downloaded again.
+                let index: usize = req.index.try_into().unwrap();
+                self.in_progress.lock().await[index] = false;
+            }
             AdminMessage::PeerDisconnect(_req) => {
                 //println!("{0} disconnected", req.addr);
             }
+            // Handled by the manager itself.
+            AdminMessage::Recheck
+            | AdminMessage::RecheckDone(_)
+            | AdminMessage::RetryDisk
+            | AdminMessage::NewPeers(_) => {}
         }
         return Ok(());
     }
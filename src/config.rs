@@ -0,0 +1,297 @@
+use std::{
+    env,
+    io::{Error as IOError, ErrorKind},
+};
+
+use crate::storage::{AllocationMode, StorageKind, StorageOptions};
+
+// Session-wide settings. Defaults can be overridden through TORRENSIC_* environment variables.
+pub(crate) struct Config {
+    pub torrent_file: String,
+    pub output_dir: String,
+    // Holds resume data, apart from the downloads themselves.
+    pub state_dir: String,
+    // Replaces the randomly generated peer ID. Shorter values are used as a prefix.
+    pub peer_id: Option<String>,
+    // Where piece data is kept: plain file I/O by default, or memory-mapped files with "mmap".
+    pub storage: StorageKind,
+    // Sparse files by default, "full" to reserve all disk space up front or "lazy" to only create
+    // files once they are written to.
+    pub allocation: AllocationMode,
+    // Unfinished files get a `.part` suffix, unless disabled with "0".
+    pub part_files: bool,
+    // Keeps unfinished files apart from the output directory.
+    pub incomplete_dir: Option<String>,
+    // Finished torrents are moved here.
+    pub completed_dir: Option<String>,
+    // Announces to every tracker at once with "1", rather than to the first that answers.
+    pub announce_to_all: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            torrent_file: String::from("torrents/airfryer.torrent"),
+            output_dir: String::from("downloads"),
+            state_dir: String::from("state"),
+            peer_id: None,
+            storage: StorageKind::default(),
+            allocation: AllocationMode::default(),
+            part_files: true,
+            incomplete_dir: None,
+            completed_dir: None,
+            announce_to_all: false,
+        }
+    }
+}
+
+impl Config {
+    pub(crate) fn from_env() -> Self {
+        let default = Config::default();
+
+        Config {
+            torrent_file: env::var("TORRENSIC_TORRENT").unwrap_or(default.torrent_file),
+            output_dir: env::var("TORRENSIC_OUTPUT_DIR").unwrap_or(default.output_dir),
+            state_dir: env::var("TORRENSIC_STATE_DIR").unwrap_or(default.state_dir),
+            peer_id: env::var("TORRENSIC_PEER_ID").ok().or(default.peer_id),
+            storage: match env::var("TORRENSIC_STORAGE").as_deref() {
+                Ok("mmap") => StorageKind::Mmap,
+                _ => default.storage,
+            },
+            allocation: match env::var("TORRENSIC_ALLOCATION").as_deref() {
+                Ok("full") => AllocationMode::Full,
+                Ok("lazy") => AllocationMode::Lazy,
+                _ => default.allocation,
+            },
+            part_files: match env::var("TORRENSIC_PART_FILES").as_deref() {
+                Ok("0") => false,
+                _ => default.part_files,
+            },
+            incomplete_dir: env::var("TORRENSIC_INCOMPLETE_DIR")
+                .ok()
+                .or(default.incomplete_dir),
+            completed_dir: env::var("TORRENSIC_COMPLETED_DIR")
+                .ok()
+                .or(default.completed_dir),
+            announce_to_all: match env::var("TORRENSIC_ANNOUNCE_ALL").as_deref() {
+                Ok("1") => true,
+                _ => default.announce_to_all,
+            },
+        }
+    }
+
+    pub(crate) fn storage_options(&self) -> StorageOptions {
+        StorageOptions {
+            allocation: self.allocation,
+            part_files: self.part_files,
+            incomplete_dir: self.incomplete_dir.clone(),
+        }
+    }
+}
+
+pub(crate) enum Command {
+    Download,
+    Create(CreateOptions),
+    // Prints the details of a metafile.
+    Info(String),
+    // Rechecks the downloaded data of a metafile against its piece hashes.
+    Verify(String),
+    // Asks the trackers of one or more metafiles how big their swarms are.
+    Scrape(Vec<String>),
+    // Runs as a tracker instead of downloading.
+    Tracker(TrackerOptions),
+}
+
+// Options for `torrensic tracker`.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct TrackerOptions {
+    // Addresses to serve HTTP and UDP announces on. Both default to 0.0.0.0:6969 when neither is
+    // given.
+    pub http: Option<String>,
+    pub udp: Option<String>,
+    // Info hashes of the only torrents tracked. Any torrent is tracked if empty.
+    pub allow: Vec<Vec<u8>>,
+    // Announces must start with one of these as the first part of the path, such as
+    // `/<passkey>/announce`, unless empty.
+    pub passkeys: Vec<String>,
+}
+
+const TRACKER_USAGE: &str = "Usage: torrensic tracker [--http <addr>] [--udp <addr>] \
+[--allow <info hash>]... [--passkey <key>]...";
+
+const DEFAULT_TRACKER_ADDR: &str = "0.0.0.0:6969";
+
+// Options for `torrensic create <path>`.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct CreateOptions {
+    pub path: String,
+    // Defaults to `<name>.torrent` in the working directory.
+    pub output: Option<String>,
+    pub trackers: Vec<String>,
+    pub web_seeds: Vec<String>,
+    pub comment: Option<String>,
+    pub private: bool,
+    pub source: Option<String>,
+    pub piece_length: Option<u64>,
+    pub magnet: bool,
+}
+
+pub(crate) const CREATE_USAGE: &str = "Usage: torrensic create <path> [-o <file>] [-t <tracker>]... \
+[-w <web seed>]... [-c <comment>] [--private] [--source <source>] [--piece-length <bytes>] [--magnet]";
+
+// Parses the command line, without the program name.
+pub(crate) fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Command, IOError> {
+    match args.next().as_deref() {
+        None => Ok(Command::Download),
+        Some("create") => parse_create(args).map(Command::Create),
+        Some("info") => match (args.next(), args.next()) {
+            (Some(path), None) => Ok(Command::Info(path)),
+            _ => Err(invalid_arg(String::from("Usage: torrensic info <file>"))),
+        },
+        Some("verify") => match (args.next(), args.next()) {
+            (Some(path), None) => Ok(Command::Verify(path)),
+            _ => Err(invalid_arg(String::from("Usage: torrensic verify <file>"))),
+        },
+        Some("tracker") => parse_tracker(args).map(Command::Tracker),
+        Some("scrape") => {
+            let paths: Vec<String> = args.collect();
+            if paths.is_empty() {
+                return Err(invalid_arg(String::from(
+                    "Usage: torrensic scrape <file>...",
+                )));
+            }
+            Ok(Command::Scrape(paths))
+        }
+        Some(other) => Err(invalid_arg(format!("Unknown command: {other}"))),
+    }
+}
+
+fn parse_tracker<I: Iterator<Item = String>>(mut args: I) -> Result<TrackerOptions, IOError> {
+    let mut opts = TrackerOptions::default();
+
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| invalid_arg(format!("Missing value for {arg}")))
+        };
+
+        match arg.as_str() {
+            "--http" => opts.http = Some(value()?),
+            "--udp" => opts.udp = Some(value()?),
+            "--allow" => {
+                let v = value()?;
+                match hex::decode(&v) {
+                    Ok(info_hash) if info_hash.len() == 20 => opts.allow.push(info_hash),
+                    _ => return Err(invalid_arg(format!("Invalid info hash: {v}"))),
+                }
+            }
+            "--passkey" => opts.passkeys.push(value()?),
+            _ => return Err(invalid_arg(String::from(TRACKER_USAGE))),
+        }
+    }
+
+    if opts.http.is_none() && opts.udp.is_none() {
+        opts.http = Some(String::from(DEFAULT_TRACKER_ADDR));
+        opts.udp = Some(String::from(DEFAULT_TRACKER_ADDR));
+    }
+    Ok(opts)
+}
+
+fn parse_create<I: Iterator<Item = String>>(mut args: I) -> Result<CreateOptions, IOError> {
+    let mut opts = CreateOptions::default();
+    let mut path = None;
+
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| invalid_arg(format!("Missing value for {arg}")))
+        };
+
+        match arg.as_str() {
+            "-o" | "--output" => opts.output = Some(value()?),
+            "-t" | "--tracker" => opts.trackers.push(value()?),
+            "-w" | "--web-seed" => opts.web_seeds.push(value()?),
+            "-c" | "--comment" => opts.comment = Some(value()?),
+            "--source" => opts.source = Some(value()?),
+            "--piece-length" => {
+                let v = value()?;
+                opts.piece_length = Some(
+                    v.parse()
+                        .map_err(|_| invalid_arg(format!("Invalid piece length: {v}")))?,
+                );
+            }
+            "--private" => opts.private = true,
+            "--magnet" => opts.magnet = true,
+            _ if arg.starts_with('-') => return Err(invalid_arg(format!("Unknown option: {arg}"))),
+            _ if path.is_none() => path = Some(arg),
+            _ => return Err(invalid_arg(format!("Unexpected argument: {arg}"))),
+        }
+    }
+
+    opts.path = path.ok_or_else(|| invalid_arg(CREATE_USAGE.to_owned()))?;
+    Ok(opts)
+}
+
+fn invalid_arg(msg: String) -> IOError {
+    IOError::new(ErrorKind::InvalidInput, msg)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn args(s: &str) -> impl Iterator<Item = String> + '_ {
+        s.split_whitespace().map(str::to_owned)
+    }
+
+    #[test]
+    fn parses_create_options() {
+        let cmd = parse_args(args(
+            "create data -t http://a/ann -t udp://b:1 -w http://seed/ --private --piece-length 65536 -o out.torrent",
+        ))
+        .unwrap();
+
+        let Command::Create(opts) = cmd else {
+            panic!("expected create command");
+        };
+        assert_eq!(opts.path, "data");
+        assert_eq!(opts.trackers, vec!["http://a/ann", "udp://b:1"]);
+        assert_eq!(opts.web_seeds, vec!["http://seed/"]);
+        assert!(opts.private);
+        assert_eq!(opts.piece_length, Some(65536));
+        assert_eq!(opts.output.as_deref(), Some("out.torrent"));
+    }
+
+    #[test]
+    fn rejects_bad_create_arguments() {
+        assert!(parse_args(args("create")).is_err());
+        assert!(parse_args(args("create data -t")).is_err());
+        assert!(parse_args(args("create data --bogus")).is_err());
+        assert!(parse_args(args("frobnicate")).is_err());
+        assert!(parse_args(args("info")).is_err());
+        assert!(parse_args(args("scrape")).is_err());
+        assert!(parse_args(args("tracker --allow abcd")).is_err());
+    }
+
+    #[test]
+    fn parses_tracker_options() {
+        let Command::Tracker(opts) = parse_args(args("tracker")).unwrap() else {
+            panic!("expected tracker command");
+        };
+        assert_eq!(opts.http.as_deref(), Some("0.0.0.0:6969"));
+        assert_eq!(opts.udp.as_deref(), Some("0.0.0.0:6969"));
+
+        let hash = "ab".repeat(20);
+        let cmd = parse_args(args(&format!(
+            "tracker --udp 127.0.0.1:7000 --allow {hash} --passkey secret"
+        )))
+        .unwrap();
+        let Command::Tracker(opts) = cmd else {
+            panic!("expected tracker command");
+        };
+        assert_eq!(opts.http, None);
+        assert_eq!(opts.udp.as_deref(), Some("127.0.0.1:7000"));
+        assert_eq!(opts.allow, vec![vec![0xab; 20]]);
+        assert_eq!(opts.passkeys, vec!["secret"]);
+    }
+}
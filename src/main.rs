@@ -1,21 +1,42 @@
 mod builder;
 mod client;
+mod config;
 mod parser;
+mod storage;
 mod torrent_info;
+mod tracker;
 mod ui;
 mod utils;
 
-use std::sync::Arc;
+use std::{collections::HashMap, io::Write, path::Path, sync::Arc};
 
-use builder::file_builder;
-use client::manager::run_peer_manager_task;
-use tokio::{self, sync::watch};
+use builder::torrent_builder::TorrentBuilder;
+use client::{
+    manager::run_peer_manager_task,
+    peer_id,
+    stats::{self, TransferStats},
+};
+use config::{Command, Config, CreateOptions, TrackerOptions};
+use parser::metadata::{get_magnet_link, write_metadata, Metadata};
+use storage::{
+    disk_io::DiskIo,
+    resume::{self, ResumeData},
+};
+use tokio::{
+    self,
+    net::{TcpListener, UdpSocket},
+    sync::watch,
+};
 
-use torrent_info::{magnet_acquirer::MagnetAcquirer, tracker_acquirer::TrackerAcquirer, TorrentInfo, TorrentInfoAcquirer};
+use torrent_info::{magnet_acquirer::MagnetAcquirer, tracker_acquirer::TrackerAcquirer, tracker_session::TrackerSession, TorrentInfo, TorrentInfoAcquirer};
+use ui::widgets::trackers_info::swarm_line;
 
 use crate::{
     client::manager::Manager,
-    ui::controller::{run_controller_task, Controller},
+    ui::{
+        controller::{run_controller_task, Controller},
+        widgets::torrent_desc::desc_lines,
+    },
 };
 
 /*
@@ -28,23 +49,68 @@ use crate::{
 */
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let torrent_file = String::from("torrents/airfryer.torrent");
-    let output_dir = String::from("downloads");
+    let config = Config::from_env();
+
+    match config::parse_args(std::env::args().skip(1))? {
+        Command::Download => {}
+        Command::Create(opts) => return create_torrent(opts),
+        Command::Info(path) => {
+            let md = parser::metadata::read_metadata(&path).map_err(|e| e.to_string())?;
+            for line in desc_lines(&md) {
+                println!("{}", line);
+            }
+            return Ok(());
+        }
+        Command::Verify(path) => return verify_torrent(&path, &config),
+        Command::Scrape(paths) => return scrape_torrents(&paths).await,
+        Command::Tracker(opts) => return run_tracker(opts).await,
+    }
 
-    let magnet_acquirer = MagnetAcquirer::new();
-    magnet_acquirer.acquire(torrent_file.clone()).await?;
+    let torrent_file = config.torrent_file.clone();
 
+    peer_id::init(config.peer_id.as_deref())?;
 
     let info_acquirer = TrackerAcquirer {};
-    let (md, peers) = match info_acquirer.acquire(torrent_file).await? {
-        TorrentInfo { md, peers } => (Arc::new(md), Arc::new(peers)),
-    };
+    let TorrentInfo { md, peers } = info_acquirer.acquire(torrent_file.clone()).await?;
 
-    match file_builder::create(&md, &output_dir, true) {
-        Ok(_) => {}
-        Err(e) => {
-            println!("{:?}", e)
+    // Private torrents (BEP 27) must only get peers from their own trackers, so they are kept
+    // out of the DHT.
+    if !md.info.is_private() {
+        let magnet_acquirer = MagnetAcquirer::new();
+        magnet_acquirer.acquire(torrent_file).await?;
+    }
+    let (md, peers) = (Arc::new(md), Arc::new(peers));
+
+    let download_dir = download_dir(&config, &md);
+    let storage = storage::open(
+        config.storage,
+        md.clone(),
+        download_dir,
+        &config.storage_options(),
+    );
+    let disk_state = storage::blocking(&storage, |s| Ok((s.allocate()?, s.file_states()?))).await;
+    // A failed allocation, e.g. for lack of disk space, starts the torrent paused. So does a
+    // corrupt resume file, which is reported while the data on disk is rechecked.
+    let (resume_data, needs_recheck, disk_error) = match disk_state {
+        Ok((state, files)) => {
+            let (saved, error) = match resume::load(&config.state_dir, &md) {
+                Ok(saved) => (saved, None),
+                Err(e) if e.kind() == std::io::ErrorKind::InvalidData => (None, Some(e)),
+                Err(e) => return Err(Box::new(e)),
+            };
+            let (resume_data, needs_recheck) = resume::validate(&md, &state, saved, &files);
+            (resume_data, needs_recheck, error)
         }
+        Err(e) => (ResumeData::new(&md), false, Some(e)),
+    };
+    let stats = Arc::new(TransferStats::new(
+        resume_data.uploaded,
+        resume_data.downloaded,
+        stats::bytes_left(&md.geometry(), resume_data.bitfield.iter().by_vals()),
+    ));
+    let disk_io = Arc::new(DiskIo::new(storage, &resume_data));
+    if let Some(e) = disk_error {
+        disk_io.report_error(&e);
     }
 
     let (tx_progress, rx_progress) = watch::channel((0, 0));
@@ -52,30 +118,190 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         watch::channel(vec![false; md.num_pieces()]);
     let (tx_downloaded_pieces, rx_downloaded_pieces) = watch::channel(vec![false; md.num_pieces()]);
     let (tx_speed, rx_speed) = watch::channel(0.0);
+    let (tx_checking, rx_checking) = watch::channel(None);
 
-    let peer_manager = Manager::new(
+    let mut peer_manager = Manager::new(
         md.clone(),
         peers.clone(),
-        &output_dir,
+        disk_io.clone(),
+        &config.state_dir,
+        config.completed_dir.as_deref().filter(|dir| *dir != download_dir),
+        stats.clone(),
+        needs_recheck,
         tx_progress,
         tx_in_progress_pieces,
         tx_downloaded_pieces,
         tx_speed,
+        tx_checking,
     )?;
+    // The manager has the session announce `started` once any recheck on startup is done, so it
+    // reports what is really left to download.
+    let tracker = TrackerSession::start(
+        md.clone(),
+        stats.clone(),
+        peer_manager.admin_sender(),
+        config.announce_to_all,
+    );
+    peer_manager.set_tracker(tracker.clone());
+
     let ui_controller = Controller::new(
         md.clone(),
-        peers.clone(),
+        peers,
         rx_progress,
         rx_in_progress_pieces,
         rx_downloaded_pieces,
         rx_speed,
+        rx_checking,
+        disk_io.errors(),
+        tracker.statuses(),
+        peer_manager.admin_sender(),
     )
     .await;
 
     tokio::spawn(run_peer_manager_task(peer_manager));
     run_controller_task(ui_controller).await;
 
+    tracker.stop().await;
+
+    disk_io
+        .save_resume(&config.state_dir, stats.uploaded(), stats.downloaded())
+        .await?;
     println!("Closed");
 
     Ok(())
 }
+
+// Torrents finished in an earlier session have already been moved to the completed directory.
+fn download_dir<'a>(config: &'a Config, md: &Metadata) -> &'a str {
+    match &config.completed_dir {
+        Some(dir) if Path::new(&storage::root_path(md, dir)).exists() => dir,
+        _ => &config.output_dir,
+    }
+}
+
+// Scrapes every tracker once for all the torrents it serves, then prints each torrent's swarm
+// per tracker.
+async fn scrape_torrents(paths: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut mds = Vec::new();
+    for path in paths {
+        mds.push(parser::metadata::read_metadata(path).map_err(|e| e.to_string())?);
+    }
+
+    let mut info_hashes: HashMap<String, Vec<Vec<u8>>> = HashMap::new();
+    for md in &mds {
+        for url in md.trackers().into_iter().flatten() {
+            info_hashes.entry(url).or_default().push(md.info_hash.clone());
+        }
+    }
+    let mut results = HashMap::new();
+    for (url, hashes) in &info_hashes {
+        let res = TrackerAcquirer::scrape(url, hashes).await.map_err(|e| e.to_string());
+        results.insert(url.clone(), res);
+    }
+
+    for md in &mds {
+        println!("{}", md.info.name);
+        for url in md.trackers().iter().flatten() {
+            let line = match &results[url] {
+                Ok(files) => match files.get(&md.info_hash) {
+                    Some(swarm) => swarm_line(swarm),
+                    None => String::from("unknown to tracker"),
+                },
+                Err(e) => e.clone(),
+            };
+            println!("  {}: {}", url, line);
+        }
+    }
+
+    Ok(())
+}
+
+// Serves announces and scrapes until interrupted.
+async fn run_tracker(opts: TrackerOptions) -> Result<(), Box<dyn std::error::Error>> {
+    let tracker = tracker::Tracker::new(&opts);
+    let path = match opts.passkeys.first() {
+        Some(_) => "/<passkey>/announce",
+        None => "/announce",
+    };
+
+    if let Some(addr) = &opts.http {
+        let listener = TcpListener::bind(addr).await?;
+        println!("Tracking on http://{}{}", listener.local_addr()?, path);
+        tokio::spawn(tracker::http::serve(tracker.clone(), listener));
+    }
+    if let Some(addr) = &opts.udp {
+        let socket = UdpSocket::bind(addr).await?;
+        println!("Tracking on udp://{}{}", socket.local_addr()?, path);
+        tokio::spawn(tracker::udp::serve(tracker.clone(), socket));
+    }
+
+    tokio::signal::ctrl_c().await?;
+    Ok(())
+}
+
+fn create_torrent(opts: CreateOptions) -> Result<(), Box<dyn std::error::Error>> {
+    let mut builder = TorrentBuilder::new(&opts.path).private(opts.private);
+    for tracker in &opts.trackers {
+        builder = builder.tracker(tracker);
+    }
+    for web_seed in &opts.web_seeds {
+        builder = builder.web_seed(web_seed);
+    }
+    if let Some(comment) = &opts.comment {
+        builder = builder.comment(comment);
+    }
+    if let Some(source) = &opts.source {
+        builder = builder.source(source);
+    }
+    if let Some(piece_length) = opts.piece_length {
+        builder = builder.piece_length(piece_length);
+    }
+
+    let md = builder.build()?;
+    let output = opts
+        .output
+        .unwrap_or_else(|| format!("{}.torrent", md.info.name));
+    write_metadata(&output, &md).map_err(|e| e.to_string())?;
+
+    println!("Created {} ({} pieces)", output, md.num_pieces());
+    if opts.magnet {
+        println!("{}", get_magnet_link(&md));
+    }
+
+    Ok(())
+}
+
+// Hashes the downloaded data of a torrent, reports how much of it is valid and records the
+// result as resume data.
+fn verify_torrent(path: &String, config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    let md = Arc::new(parser::metadata::read_metadata(path).map_err(|e| e.to_string())?);
+    let step = (md.geometry().num_pieces() / 100).max(1);
+    let storage = storage::open(
+        config.storage,
+        md.clone(),
+        download_dir(config, &md),
+        &config.storage_options(),
+    );
+
+    let bitfield = storage::recheck(storage.as_ref(), |checked, total| {
+        if checked % step == 0 || checked == total {
+            print!("\rChecked {}/{} pieces", checked, total);
+            let _ = std::io::stdout().flush();
+        }
+    })?;
+
+    println!();
+    println!(
+        "{} of {} pieces valid",
+        bitfield.count_ones(),
+        md.num_pieces()
+    );
+
+    let mut resume_data =
+        resume::load(&config.state_dir, &md)?.unwrap_or_else(|| ResumeData::new(&md));
+    resume_data.bitfield = bitfield;
+    resume_data.files = storage.file_states()?;
+    resume::save(&config.state_dir, &md, &resume_data)?;
+
+    Ok(())
+}
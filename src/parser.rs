@@ -1,4 +1,8 @@
 pub mod file_info;
+pub mod geometry;
+pub mod merkle;
 pub mod metadata;
+pub mod sanitise;
+pub mod scrape_info;
 pub mod tracker_info;
 pub mod magnet_message;
\ No newline at end of file
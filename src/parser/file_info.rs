@@ -1,19 +1,49 @@
+use super::sanitise::{sanitise_component, sanitise_path, PathDeduper};
+
 use bendy::{
     decoding::{Error as DecError, FromBencode, Object, ResultExt},
     encoding::{AsString, Error as EncError, SingleItemEncoder, ToBencode},
 };
 
+#[derive(Default)]
 pub(crate) struct FilePathInfo {
-    pub length: u32,
+    pub length: u64,
+    pub path: Vec<String>,
+    // UTF-8 copy of the path, set by clients whose `path` is in the torrent's declared encoding.
+    pub path_utf8: Option<Vec<String>>,
+    pub md5sum: Option<String>,
+    // BEP 47 attribute flags, e.g. "p" for padding, "x" for executable, "l" for symlink.
+    pub attr: Option<String>,
+    pub symlink_path: Option<Vec<String>>,
+    // Where the file is stored, relative to the torrent's directory. Derived from the path with
+    // hostile components rewritten and duplicates renamed, see `sanitise`.
+    pub disk_path: Vec<String>,
+}
+
+// A file from the BEP 52 file tree. Empty files have no pieces root.
+pub(crate) struct V2FileInfo {
+    pub length: u64,
     pub path: Vec<String>,
+    pub pieces_root: Option<Vec<u8>>,
 }
 
 pub(crate) struct FileInfo {
     pub files: Vec<FilePathInfo>,
     pub name: String,
-    pub piece_length: u32,
+    pub name_utf8: Option<String>,
+    // Sanitised name used for the torrent's file or directory on disk.
+    pub disk_name: String,
+    pub piece_length: u64,
     pub pieces: Vec<u8>,
     pub private: Option<u32>,
+    // Set by some private trackers so that cross-seeded torrents get distinct info hashes.
+    pub source: Option<String>,
+    // Single-file torrents have a top-level length instead of a files list. Both are normalised
+    // into `files`, with a single-file torrent holding one entry whose path is just the name.
+    pub single_file: bool,
+    pub meta_version: Option<u32>,
+    // Files from the v2 file tree in tree order, each starting on a piece boundary. Empty for v1.
+    pub v2_files: Vec<V2FileInfo>,
 }
 
 /////////////////
@@ -26,21 +56,39 @@ impl FromBencode for FilePathInfo {
     where
         Self: Sized,
     {
-        let mut length: Option<u32> = None;
+        let mut length: Option<u64> = None;
         let mut path: Option<Vec<String>> = None;
+        let mut path_utf8: Option<Vec<String>> = None;
+        let mut md5sum: Option<String> = None;
+        let mut attr: Option<String> = None;
+        let mut symlink_path: Option<Vec<String>> = None;
 
         let mut dict = object.try_into_dictionary()?;
 
         while let Some(pair) = dict.next_pair()? {
             match pair {
+                (b"attr", val) => {
+                    attr = String::decode_bencode_object(val).context("attr").ok();
+                }
                 (b"length", val) => {
-                    length = u32::decode_bencode_object(val)
+                    length = u64::decode_bencode_object(val)
                         .context("length")
                         .map(Some)?;
                 }
+                (b"md5sum", val) => {
+                    md5sum = String::decode_bencode_object(val).context("md5sum").ok();
+                }
                 (b"path", val) => {
                     path = Vec::decode_bencode_object(val).context("path").map(Some)?;
                 }
+                (b"path.utf-8", val) => {
+                    path_utf8 = Vec::decode_bencode_object(val).context("path.utf-8").ok();
+                }
+                (b"symlink path", val) => {
+                    symlink_path = Vec::decode_bencode_object(val)
+                        .context("symlink path")
+                        .ok();
+                }
                 _ => {
                     continue;
                 }
@@ -50,22 +98,90 @@ impl FromBencode for FilePathInfo {
         let length = length.ok_or_else(|| DecError::missing_field("length"))?;
         let path = path.ok_or_else(|| DecError::missing_field("path"))?;
 
-        Ok(FilePathInfo { length, path })
+        Ok(FilePathInfo {
+            length,
+            path,
+            path_utf8,
+            md5sum,
+            attr,
+            symlink_path,
+            disk_path: Vec::new(),
+        })
     }
 }
 
+// Walks a v2 file tree, where each directory is a dictionary keyed by path component and each
+// file is a dictionary holding its details under the empty key.
+fn decode_file_tree(
+    object: Object,
+    path: &mut Vec<String>,
+    files: &mut Vec<V2FileInfo>,
+) -> Result<(), DecError> {
+    let mut dict = object.try_into_dictionary()?;
+
+    while let Some((key, val)) = dict.next_pair()? {
+        if key.is_empty() {
+            let mut length: Option<u64> = None;
+            let mut pieces_root: Option<Vec<u8>> = None;
+
+            let mut leaf = val.try_into_dictionary()?;
+            while let Some(pair) = leaf.next_pair()? {
+                match pair {
+                    (b"length", val) => {
+                        length = u64::decode_bencode_object(val)
+                            .context("length")
+                            .map(Some)?;
+                    }
+                    (b"pieces root", val) => {
+                        let raw = val.try_into_bytes().context("pieces root")?;
+                        if raw.len() != 32 {
+                            return Err(DecError::missing_field("pieces root"));
+                        }
+                        pieces_root = Some(raw.to_vec());
+                    }
+                    _ => {
+                        continue;
+                    }
+                }
+            }
+
+            let length = length.ok_or_else(|| DecError::missing_field("length"))?;
+            files.push(V2FileInfo {
+                length,
+                path: path.clone(),
+                pieces_root: if length > 0 { pieces_root } else { None },
+            });
+        } else {
+            path.push(String::from_utf8_lossy(key).into_owned());
+            decode_file_tree(val, path, files)?;
+            path.pop();
+        }
+    }
+
+    Ok(())
+}
+
 impl FromBencode for FileInfo {
-    const EXPECTED_RECURSION_DEPTH: usize = 4;
+    // v2 file trees nest one level per path component.
+    const EXPECTED_RECURSION_DEPTH: usize = 64;
 
     fn decode_bencode_object(object: Object) -> Result<Self, DecError>
     where
         Self: Sized,
     {
         let mut files: Option<Vec<FilePathInfo>> = None;
+        let mut length: Option<u64> = None;
         let mut name: Option<String> = None;
+        let mut name_utf8: Option<String> = None;
+        let mut md5sum: Option<String> = None;
+        let mut attr: Option<String> = None;
+        let mut symlink_path: Option<Vec<String>> = None;
         let mut pieces: Option<Vec<u8>> = None;
-        let mut piece_length: Option<u32> = None;
+        let mut piece_length: Option<u64> = None;
         let mut private: Option<u32> = None;
+        let mut source: Option<String> = None;
+        let mut meta_version: Option<u32> = None;
+        let mut v2_files: Vec<V2FileInfo> = Vec::new();
 
         let mut dict = match object.try_into_dictionary() {
             Ok(v) => v,
@@ -76,17 +192,39 @@ impl FromBencode for FileInfo {
 
         while let Some(pair) = dict.next_pair().unwrap() {
             match pair {
+                (b"attr", val) => {
+                    attr = String::decode_bencode_object(val).context("attr").ok();
+                }
+                (b"file tree", val) => {
+                    decode_file_tree(val, &mut Vec::new(), &mut v2_files).context("file tree")?;
+                }
                 (b"files", val) => {
                     files = Vec::decode_bencode_object(val).ok();
                 }
+                (b"length", val) => {
+                    length = u64::decode_bencode_object(val)
+                        .context("length")
+                        .map(Some)?;
+                }
+                (b"md5sum", val) => {
+                    md5sum = String::decode_bencode_object(val).context("md5sum").ok();
+                }
+                (b"meta version", val) => {
+                    meta_version = u32::decode_bencode_object(val)
+                        .context("meta version")
+                        .map(Some)?;
+                }
                 (b"name", val) | (b"display-name", val) => {
                     name = String::decode_bencode_object(val).ok();
                 }
+                (b"name.utf-8", val) => {
+                    name_utf8 = String::decode_bencode_object(val).context("name.utf-8").ok();
+                }
                 (b"pieces", val) => {
                     pieces = val.try_into_bytes().map(Vec::from).map(Some)?;
                 }
                 (b"piece length", val) => {
-                    piece_length = u32::decode_bencode_object(val)
+                    piece_length = u64::decode_bencode_object(val)
                         .context("piece length")
                         .map(Some)?;
                 }
@@ -95,27 +233,108 @@ impl FromBencode for FileInfo {
                         .context("private")
                         .map(Some)?;
                 }
+                (b"source", val) => {
+                    source = String::decode_bencode_object(val).context("source").ok();
+                }
+                (b"symlink path", val) => {
+                    symlink_path = Vec::decode_bencode_object(val)
+                        .context("symlink path")
+                        .ok();
+                }
                 _ => {
                     continue;
                 }
             }
         }
 
-        let files = files.ok_or_else(|| DecError::missing_field("files"))?;
         let name = name.ok_or_else(|| DecError::missing_field("name"))?;
-        let pieces = pieces.ok_or_else(|| DecError::missing_field("pieces"))?;
         let piece_length = piece_length.ok_or_else(|| DecError::missing_field("piece_length"))?;
+        let is_v2 = meta_version == Some(2) && !v2_files.is_empty();
+
+        let mut single_file = files.is_none() && length.is_some();
+        let mut files = match (files, length) {
+            (Some(files), _) => files,
+            // A single file keeps its md5sum and attributes in the info dictionary itself.
+            (None, Some(length)) => vec![FilePathInfo {
+                length,
+                path: vec![name.clone()],
+                path_utf8: name_utf8.clone().map(|n| vec![n]),
+                md5sum,
+                attr,
+                symlink_path,
+                disk_path: Vec::new(),
+            }],
+            (None, None) if is_v2 => {
+                single_file = v2_files.len() == 1 && v2_files[0].path == [name.clone()];
+                v2_layout(&v2_files, piece_length)
+            }
+            (None, None) => return Err(DecError::missing_field("files")),
+        };
+
+        // v2-only torrents carry their piece hashes in the piece layers rather than in the info.
+        let pieces = match pieces {
+            Some(pieces) => pieces,
+            None if is_v2 => Vec::new(),
+            None => return Err(DecError::missing_field("pieces")),
+        };
+
+        let disk_name = sanitise_component(name_utf8.as_ref().unwrap_or(&name))
+            .unwrap_or_else(|| String::from("_"));
+        let mut deduper = PathDeduper::new();
+        for file in files.iter_mut() {
+            let path = sanitise_path(file.path_utf8.as_ref().unwrap_or(&file.path));
+            file.disk_path = if single_file {
+                vec![disk_name.clone()]
+            } else if file.is_padding() {
+                // Never stored, so it can't clash with anything.
+                path
+            } else {
+                deduper.dedupe(path)
+            };
+        }
 
         Ok(FileInfo {
             files,
             name,
+            name_utf8,
+            disk_name,
             piece_length,
             pieces,
             private,
+            source,
+            single_file,
+            meta_version,
+            v2_files,
         })
     }
 }
 
+// Lays v2 files end to end, inserting BEP 47 style padding so that each file after the first
+// starts on a piece boundary, matching the layout a hybrid torrent declares explicitly.
+fn v2_layout(v2_files: &[V2FileInfo], piece_length: u64) -> Vec<FilePathInfo> {
+    let mut files = Vec::new();
+
+    for (i, file) in v2_files.iter().enumerate() {
+        files.push(FilePathInfo {
+            length: file.length,
+            path: file.path.clone(),
+            ..Default::default()
+        });
+
+        let pad = (piece_length - file.length % piece_length) % piece_length;
+        if pad > 0 && i + 1 < v2_files.len() {
+            files.push(FilePathInfo {
+                length: pad,
+                path: vec![".pad".to_owned(), pad.to_string()],
+                attr: Some("p".to_owned()),
+                ..Default::default()
+            });
+        }
+    }
+
+    files
+}
+
 /////////////////
 // Encoding
 
@@ -124,8 +343,21 @@ impl ToBencode for FilePathInfo {
 
     fn encode(&self, encoder: bendy::encoding::SingleItemEncoder) -> Result<(), EncError> {
         encoder.emit_dict(|mut e| {
+            if let Some(attr) = &self.attr {
+                e.emit_pair(b"attr", attr)?;
+            }
             e.emit_pair(b"length", &self.length)?;
-            e.emit_pair(b"path", &self.path)
+            if let Some(md5sum) = &self.md5sum {
+                e.emit_pair(b"md5sum", md5sum)?;
+            }
+            e.emit_pair(b"path", &self.path)?;
+            if let Some(path_utf8) = &self.path_utf8 {
+                e.emit_pair(b"path.utf-8", path_utf8)?;
+            }
+            if let Some(symlink_path) = &self.symlink_path {
+                e.emit_pair(b"symlink path", symlink_path)?;
+            }
+            Ok(())
         })?;
 
         Ok(())
@@ -136,17 +368,222 @@ impl ToBencode for FileInfo {
     const MAX_DEPTH: usize = 4;
 
     fn encode(&self, encoder: SingleItemEncoder) -> Result<(), EncError> {
+        // Keys must be emitted in sorted order.
+        let single = self.files.first().filter(|_| self.single_file);
         encoder.emit_dict(|mut e| {
-            e.emit_pair(b"files", &self.files)?;
+            if let Some(attr) = single.and_then(|f| f.attr.as_ref()) {
+                e.emit_pair(b"attr", attr)?;
+            }
+            if self.single_file {
+                e.emit_pair(b"length", self.total_length())?;
+            } else {
+                e.emit_pair(b"files", &self.files)?;
+            }
+            if let Some(md5sum) = single.and_then(|f| f.md5sum.as_ref()) {
+                e.emit_pair(b"md5sum", md5sum)?;
+            }
             e.emit_pair(b"name", &self.name)?;
+            if let Some(name_utf8) = &self.name_utf8 {
+                e.emit_pair(b"name.utf-8", name_utf8)?;
+            }
             e.emit_pair(b"piece length", &self.piece_length)?;
             e.emit_pair(b"pieces", AsString(&self.pieces))?;
             match &self.private {
-                Some(private) => e.emit_pair(b"private", private),
-                None => Ok({}),
+                Some(private) => e.emit_pair(b"private", private)?,
+                None => {}
             }
+            if let Some(source) = &self.source {
+                e.emit_pair(b"source", source)?;
+            }
+            if let Some(symlink_path) = single.and_then(|f| f.symlink_path.as_ref()) {
+                e.emit_pair(b"symlink path", symlink_path)?;
+            }
+            Ok(())
         })?;
 
         Ok(())
     }
 }
+
+impl FilePathInfo {
+    fn has_attr(&self, flag: char) -> bool {
+        self.attr.as_ref().is_some_and(|attr| attr.contains(flag))
+    }
+
+    // BEP 47 padding, which is all zeros and never stored. Older clients mark padding by name
+    // instead of with an attribute.
+    pub fn is_padding(&self) -> bool {
+        self.has_attr('p')
+            || self
+                .path
+                .last()
+                .is_some_and(|name| name.starts_with("_____padding_file_"))
+    }
+
+    pub fn is_executable(&self) -> bool {
+        self.has_attr('x')
+    }
+
+    // Only Windows has a hidden attribute to apply.
+    #[cfg_attr(not(windows), allow(dead_code))]
+    pub fn is_hidden(&self) -> bool {
+        self.has_attr('h')
+    }
+}
+
+impl FileInfo {
+    pub fn total_length(&self) -> u64 {
+        self.files.iter().map(|f| f.length).sum()
+    }
+
+    pub fn is_v2(&self) -> bool {
+        self.meta_version == Some(2) && !self.v2_files.is_empty()
+    }
+
+    pub fn is_hybrid(&self) -> bool {
+        self.is_v2() && !self.pieces.is_empty()
+    }
+
+    // BEP 27: peers of a private torrent may only come from its own trackers.
+    pub fn is_private(&self) -> bool {
+        self.private == Some(1)
+    }
+
+    pub fn v2_file(&self, pieces_root: &[u8]) -> Option<&V2FileInfo> {
+        self.v2_files
+            .iter()
+            .find(|f| f.pieces_root.as_deref() == Some(pieces_root))
+    }
+
+    // Finds the v2 file containing the piece, along with the index of the piece within it.
+    pub fn v2_piece(&self, index: u32) -> Option<(&V2FileInfo, u32)> {
+        let mut first_piece: u64 = 0;
+
+        for file in &self.v2_files {
+            let num_pieces = file.length.div_ceil(self.piece_length);
+            if u64::from(index) < first_piece + num_pieces {
+                let piece = u64::from(index) - first_piece;
+                return Some((file, piece.try_into().unwrap()));
+            }
+            first_piece += num_pieces;
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn decodes_single_file_layout() {
+        let raw = b"d6:lengthi1234e4:name8:file.iso12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+        let info = FileInfo::from_bencode(raw).unwrap();
+
+        assert!(info.single_file);
+        assert_eq!(info.files.len(), 1);
+        assert_eq!(info.files[0].length, 1234);
+        assert_eq!(info.files[0].path, vec!["file.iso".to_owned()]);
+        assert!(!info.is_private());
+    }
+
+    #[test]
+    fn only_private_one_makes_a_torrent_private() {
+        let raw = b"d6:lengthi1e4:name1:a12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaa7:privatei1ee";
+        assert!(FileInfo::from_bencode(raw).unwrap().is_private());
+        let raw = b"d6:lengthi1e4:name1:a12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaa7:privatei0ee";
+        assert!(!FileInfo::from_bencode(raw).unwrap().is_private());
+    }
+
+    #[test]
+    fn decodes_multi_file_layout() {
+        let raw = b"d5:filesld6:lengthi10e4:pathl1:a5:b.txteed6:lengthi20e4:pathl5:c.txteee4:name3:dir12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+        let info = FileInfo::from_bencode(raw).unwrap();
+
+        assert!(!info.single_file);
+        assert_eq!(info.total_length(), 30);
+        assert_eq!(info.files[0].path, vec!["a".to_owned(), "b.txt".to_owned()]);
+    }
+
+    #[test]
+    fn single_file_layout_round_trips() {
+        let raw = b"d6:lengthi1234e4:name8:file.iso12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+        let info = FileInfo::from_bencode(raw).unwrap();
+
+        assert_eq!(info.to_bencode().unwrap(), raw.to_vec());
+    }
+
+    #[test]
+    fn decodes_optional_file_fields() {
+        let raw = b"d5:filesld4:attr1:x6:lengthi10e6:md5sum32:0123456789abcdef0123456789abcdef\
+4:pathl3:run7:run.exee10:path.utf-8l3:run7:run.exeeed4:attr1:l6:lengthi0e4:pathl4:linke\
+12:symlink pathl3:run7:run.exeeee4:name3:dir10:name.utf-83:dir12:piece lengthi16384e\
+6:pieces20:aaaaaaaaaaaaaaaaaaaa6:source3:labe";
+        let info = FileInfo::from_bencode(raw).unwrap();
+
+        assert_eq!(info.name_utf8.as_deref(), Some("dir"));
+        assert_eq!(info.source.as_deref(), Some("lab"));
+        assert_eq!(info.files[0].attr.as_deref(), Some("x"));
+        assert_eq!(
+            info.files[0].md5sum.as_deref(),
+            Some("0123456789abcdef0123456789abcdef")
+        );
+        assert_eq!(info.files[0].path_utf8, Some(vec!["run".to_owned(), "run.exe".to_owned()]));
+        assert_eq!(info.files[1].attr.as_deref(), Some("l"));
+        assert_eq!(
+            info.files[1].symlink_path,
+            Some(vec!["run".to_owned(), "run.exe".to_owned()])
+        );
+        assert_eq!(info.to_bencode().unwrap(), raw.to_vec());
+    }
+
+    #[test]
+    fn recognises_padding_files() {
+        let raw = b"d5:filesld6:lengthi10e4:pathl1:aeed4:attr1:p6:lengthi16374e4:pathl4:.pad5:16374eed6:lengthi5e4:pathl24:_____padding_file_0_____eee4:name3:dir12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+        let info = FileInfo::from_bencode(raw).unwrap();
+
+        assert!(!info.files[0].is_padding());
+        assert!(info.files[1].is_padding());
+        assert!(info.files[2].is_padding());
+    }
+
+    #[test]
+    fn decodes_v2_file_tree() {
+        let root = "r".repeat(32);
+        let raw = format!(
+            "d9:file treed1:ad5:a.txtd0:d6:lengthi40000e11:pieces root32:{root}ee5:empty\
+             d0:d6:lengthi0eeee5:b.txtd0:d6:lengthi10e11:pieces root32:{root}eee\
+             12:meta versioni2e4:name4:test12:piece lengthi32768ee"
+        );
+        let info = FileInfo::from_bencode(raw.as_bytes()).unwrap();
+
+        assert!(info.is_v2());
+        assert!(!info.is_hybrid());
+        assert_eq!(info.v2_files.len(), 3);
+        assert_eq!(
+            info.v2_files[0].path,
+            vec!["a".to_owned(), "a.txt".to_owned()]
+        );
+        assert!(info.v2_files[1].pieces_root.is_none());
+        assert_eq!(info.v2_files[2].pieces_root, Some(root.into_bytes()));
+
+        // a.txt is padded to two full pieces, the empty file needs no padding.
+        assert_eq!(info.files.len(), 4);
+        assert_eq!(info.files[1].length, 2 * 32768 - 40000);
+        assert_eq!(info.total_length(), 2 * 32768 + 10);
+
+        assert_eq!(
+            info.v2_piece(1).map(|(f, i)| (f.length, i)),
+            Some((40000, 1))
+        );
+        assert_eq!(info.v2_piece(2).map(|(f, i)| (f.length, i)), Some((10, 0)));
+        assert!(info.v2_piece(3).is_none());
+    }
+
+    #[test]
+    fn rejects_info_without_files_or_length() {
+        let raw = b"d4:name3:dir12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+        assert!(FileInfo::from_bencode(raw).is_err());
+    }
+}
@@ -0,0 +1,156 @@
+// Size of a single block request, as used by every mainstream client.
+pub(crate) const BLOCK_SIZE: u32 = 2 << 13;
+
+// Exact piece and block boundaries for a torrent. Only the final piece may be short, and within
+// any piece only the final block may be short.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Geometry {
+    total_length: u64,
+    piece_length: u64,
+    num_pieces: u32,
+}
+
+impl Geometry {
+    pub(crate) fn new(total_length: u64, piece_length: u64) -> Self {
+        let num_pieces = if piece_length == 0 {
+            0
+        } else {
+            total_length.div_ceil(piece_length)
+        };
+
+        Geometry {
+            total_length,
+            piece_length,
+            num_pieces: num_pieces.try_into().unwrap(),
+        }
+    }
+
+    pub(crate) fn total_length(&self) -> u64 {
+        self.total_length
+    }
+
+    pub(crate) fn piece_length(&self) -> u64 {
+        self.piece_length
+    }
+
+    pub(crate) fn num_pieces(&self) -> u32 {
+        self.num_pieces
+    }
+
+    // Absolute offset of the first byte of the piece within the torrent.
+    pub(crate) fn piece_offset(&self, index: u32) -> u64 {
+        u64::from(index) * self.piece_length
+    }
+
+    pub(crate) fn piece_len(&self, index: u32) -> u32 {
+        if index >= self.num_pieces {
+            return 0;
+        }
+        let remaining = self.total_length - self.piece_offset(index);
+        remaining.min(self.piece_length).try_into().unwrap()
+    }
+
+    pub(crate) fn num_blocks(&self, index: u32) -> u32 {
+        self.piece_len(index).div_ceil(BLOCK_SIZE)
+    }
+
+    // Offset of the block within its piece, as sent in request and piece messages.
+    pub(crate) fn block_offset(&self, block_index: u32) -> u32 {
+        block_index * BLOCK_SIZE
+    }
+
+    pub(crate) fn block_len(&self, index: u32, block_index: u32) -> u32 {
+        let piece_len = self.piece_len(index);
+        let begin = self.block_offset(block_index);
+        if begin >= piece_len {
+            return 0;
+        }
+        (piece_len - begin).min(BLOCK_SIZE)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn exact_multiple_has_no_short_piece() {
+        let g = Geometry::new(4 * 262_144, 262_144);
+        assert_eq!(g.num_pieces(), 4);
+        assert_eq!(g.piece_len(3), 262_144);
+        assert_eq!(g.num_blocks(3), 16);
+        assert_eq!(g.block_len(3, 15), BLOCK_SIZE);
+    }
+
+    #[test]
+    fn short_last_piece() {
+        // Three full pieces plus 20000 bytes: the last piece has one full block and one of 3616.
+        let g = Geometry::new(3 * 262_144 + 20_000, 262_144);
+        assert_eq!(g.num_pieces(), 4);
+        assert_eq!(g.piece_len(2), 262_144);
+        assert_eq!(g.piece_len(3), 20_000);
+        assert_eq!(g.num_blocks(3), 2);
+        assert_eq!(g.block_len(3, 0), BLOCK_SIZE);
+        assert_eq!(g.block_len(3, 1), 20_000 - BLOCK_SIZE);
+        assert_eq!(g.block_len(3, 2), 0);
+    }
+
+    #[test]
+    fn last_piece_smaller_than_block() {
+        let g = Geometry::new(262_144 + 100, 262_144);
+        assert_eq!(g.num_pieces(), 2);
+        assert_eq!(g.num_blocks(1), 1);
+        assert_eq!(g.block_len(1, 0), 100);
+    }
+
+    #[test]
+    fn piece_length_not_multiple_of_block_size() {
+        // 40000 byte pieces: two full blocks and one of 7232 bytes per piece.
+        let g = Geometry::new(100_000, 40_000);
+        assert_eq!(g.num_pieces(), 3);
+        assert_eq!(g.num_blocks(0), 3);
+        assert_eq!(g.block_len(0, 1), BLOCK_SIZE);
+        assert_eq!(g.block_len(0, 2), 40_000 - 2 * BLOCK_SIZE);
+        assert_eq!(g.piece_len(2), 20_000);
+        assert_eq!(g.num_blocks(2), 2);
+        assert_eq!(g.block_len(2, 1), 20_000 - BLOCK_SIZE);
+    }
+
+    #[test]
+    fn piece_length_smaller_than_block_size() {
+        let g = Geometry::new(10_000, 4096);
+        assert_eq!(g.num_pieces(), 3);
+        assert_eq!(g.num_blocks(0), 1);
+        assert_eq!(g.block_len(0, 0), 4096);
+        assert_eq!(g.block_len(2, 0), 10_000 - 2 * 4096);
+    }
+
+    #[test]
+    fn offsets_do_not_overflow_u32() {
+        let g = Geometry::new(5 << 30, 1 << 20);
+        assert_eq!(g.num_pieces(), 5 << 10);
+        assert_eq!(g.piece_offset((5 << 10) - 1), (5u64 << 30) - (1 << 20));
+        assert_eq!(g.piece_len((5 << 10) - 1), 1 << 20);
+    }
+
+    #[test]
+    fn total_length_beyond_u32() {
+        // 200 GB torrent with 16 MiB pieces.
+        let total = 200_000_000_000u64;
+        let g = Geometry::new(total, 16 << 20);
+        assert_eq!(g.num_pieces(), 11921);
+        let last = g.num_pieces() - 1;
+        assert_eq!(g.piece_offset(last) + u64::from(g.piece_len(last)), total);
+        assert_eq!(
+            g.piece_len(last),
+            (total - u64::from(last) * (16 << 20)) as u32
+        );
+    }
+
+    #[test]
+    fn out_of_range_piece_is_empty() {
+        let g = Geometry::new(1000, 512);
+        assert_eq!(g.piece_len(2), 0);
+        assert_eq!(g.num_blocks(2), 0);
+    }
+}
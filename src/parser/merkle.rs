@@ -0,0 +1,213 @@
+use sha2::{Digest, Sha256};
+
+use super::geometry::BLOCK_SIZE;
+
+// BEP 52 merkle trees are built over SHA-256 hashes of 16KiB blocks. Leaves past the end of a
+// file are zero, and a tree is always padded out to a power of two leaves.
+pub(crate) type Hash = [u8; 32];
+
+pub(crate) const ZERO_HASH: Hash = [0; 32];
+
+pub(crate) fn sha256(data: &[u8]) -> Hash {
+    Sha256::digest(data).into()
+}
+
+fn hash_pair(left: &Hash, right: &Hash) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+// Leaf hashes for a run of file data. The final block may be short and is hashed as-is.
+pub(crate) fn block_hashes(data: &[u8]) -> Vec<Hash> {
+    data.chunks(BLOCK_SIZE as usize).map(sha256).collect()
+}
+
+// Root of a tree with `width` leaves (rounded up to a power of two), padding missing leaves
+// with `pad`.
+pub(crate) fn root(leaves: &[Hash], width: usize, pad: Hash) -> Hash {
+    let width = width.max(leaves.len()).max(1).next_power_of_two();
+    let mut layer = leaves.to_vec();
+    layer.resize(width, pad);
+
+    while layer.len() > 1 {
+        layer = layer
+            .chunks_exact(2)
+            .map(|pair| hash_pair(&pair[0], &pair[1]))
+            .collect();
+    }
+
+    layer[0]
+}
+
+// Root of a subtree whose leaves are all zero, used to pad a piece layer.
+pub(crate) fn pad_hash(leaves: usize) -> Hash {
+    root(&[], leaves, ZERO_HASH)
+}
+
+pub(crate) fn leaves_per_piece(piece_length: u64) -> usize {
+    (piece_length / u64::from(BLOCK_SIZE))
+        .max(1)
+        .try_into()
+        .unwrap()
+}
+
+// Layer number of the piece layer, counting up from the 16KiB leaf layer at 0.
+pub(crate) fn piece_layer_index(piece_length: u64) -> u32 {
+    leaves_per_piece(piece_length).trailing_zeros()
+}
+
+// Hash of a single piece as stored in the piece layer.
+pub(crate) fn piece_hash(data: &[u8], piece_length: u64) -> Hash {
+    root(
+        &block_hashes(data),
+        leaves_per_piece(piece_length),
+        ZERO_HASH,
+    )
+}
+
+// Pieces root of a file no longer than a piece, where the root covers the blocks directly.
+pub(crate) fn small_file_root(data: &[u8]) -> Hash {
+    let hashes = block_hashes(data);
+    root(&hashes, hashes.len(), ZERO_HASH)
+}
+
+// Pieces root computed from a file's piece layer.
+pub(crate) fn layer_root(layer: &[Hash], piece_length: u64) -> Hash {
+    root(layer, layer.len(), pad_hash(leaves_per_piece(piece_length)))
+}
+
+pub(crate) fn split_layer(raw: &[u8]) -> Option<Vec<Hash>> {
+    if !raw.len().is_multiple_of(32) {
+        return None;
+    }
+    Some(
+        raw.chunks_exact(32)
+            .map(|c| c.try_into().unwrap())
+            .collect(),
+    )
+}
+
+// Answers a hash request against a piece layer: `length` hashes starting at `index`, followed by
+// the uncle hashes needed to prove them against the pieces root, up to `proof_layers` of them.
+pub(crate) fn layer_hashes(
+    layer: &[Hash],
+    pad: Hash,
+    index: usize,
+    length: usize,
+    proof_layers: usize,
+) -> Option<Vec<Hash>> {
+    let width = layer.len().max(1).next_power_of_two();
+    if length == 0
+        || !length.is_power_of_two()
+        || !index.is_multiple_of(length)
+        || index + length > width
+    {
+        return None;
+    }
+
+    let mut level = layer.to_vec();
+    level.resize(width, pad);
+
+    let mut res = level[index..index + length].to_vec();
+
+    // Climb to the root of the requested range, then collect one sibling per layer above it.
+    while level.len() > width / length {
+        level = level
+            .chunks_exact(2)
+            .map(|pair| hash_pair(&pair[0], &pair[1]))
+            .collect();
+    }
+
+    let mut node = index / length;
+    for _ in 0..proof_layers {
+        if level.len() == 1 {
+            break;
+        }
+        res.push(level[node ^ 1]);
+        level = level
+            .chunks_exact(2)
+            .map(|pair| hash_pair(&pair[0], &pair[1]))
+            .collect();
+        node /= 2;
+    }
+
+    Some(res)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const BLOCK: usize = BLOCK_SIZE as usize;
+
+    #[test]
+    fn single_block_root_is_block_hash() {
+        let data = vec![7; 1000];
+        assert_eq!(small_file_root(&data), sha256(&data));
+    }
+
+    #[test]
+    fn three_blocks_are_padded_to_four() {
+        let data = vec![1; 2 * BLOCK + 10];
+        let h = block_hashes(&data);
+        let expected = hash_pair(&hash_pair(&h[0], &h[1]), &hash_pair(&h[2], &ZERO_HASH));
+
+        assert_eq!(h.len(), 3);
+        assert_eq!(small_file_root(&data), expected);
+    }
+
+    #[test]
+    fn short_last_piece_is_padded_to_piece_width() {
+        let piece_length = 4 * BLOCK as u64;
+        let data = vec![3; BLOCK + 1];
+        let h = block_hashes(&data);
+        let expected = hash_pair(&hash_pair(&h[0], &h[1]), &hash_pair(&ZERO_HASH, &ZERO_HASH));
+
+        assert_eq!(piece_hash(&data, piece_length), expected);
+    }
+
+    #[test]
+    fn layer_root_matches_full_tree() {
+        // A file of five pieces of two blocks each: the pieces root over the piece layer must
+        // equal the root over all the blocks.
+        let piece_length = 2 * BLOCK as u64;
+        let data: Vec<u8> = (0..(9 * BLOCK + 5)).map(|i| (i % 251) as u8).collect();
+
+        let layer: Vec<Hash> = data
+            .chunks(piece_length as usize)
+            .map(|piece| piece_hash(piece, piece_length))
+            .collect();
+        let blocks = block_hashes(&data);
+
+        assert_eq!(layer.len(), 5);
+        assert_eq!(
+            layer_root(&layer, piece_length),
+            root(&blocks, blocks.len(), ZERO_HASH)
+        );
+    }
+
+    #[test]
+    fn layer_hashes_with_proof_reaches_root() {
+        let layer: Vec<Hash> = (0..6u8).map(|i| sha256(&[i])).collect();
+        let pad = pad_hash(2);
+        let full_root = root(&layer, layer.len(), pad);
+
+        // Two requested hashes, then the uncles at the second and third layers.
+        let res = layer_hashes(&layer, pad, 4, 2, 8).unwrap();
+        assert_eq!(res.len(), 4);
+
+        let subtree = hash_pair(&res[0], &res[1]);
+        let right_half = hash_pair(&subtree, &res[2]);
+        assert_eq!(hash_pair(&res[3], &right_half), full_root);
+    }
+
+    #[test]
+    fn layer_hashes_rejects_misaligned_request() {
+        let layer: Vec<Hash> = (0..4u8).map(|i| sha256(&[i])).collect();
+        assert!(layer_hashes(&layer, ZERO_HASH, 1, 2, 0).is_none());
+        assert!(layer_hashes(&layer, ZERO_HASH, 0, 3, 0).is_none());
+        assert!(layer_hashes(&layer, ZERO_HASH, 4, 2, 0).is_none());
+    }
+}
@@ -1,22 +1,56 @@
+use std::collections::{BTreeMap, HashMap};
+
 use bendy::{
     decoding::{Error as DecError, FromBencode, ResultExt},
-    encoding::{Error as EncError, ToBencode},
+    encoding::{AsString, Error as EncError, ToBencode},
+    value::Value,
 };
 
 use sha1::{Digest, Sha1};
-use urlencoding::encode_binary;
+use sha2::Sha256;
+use urlencoding::{encode, encode_binary};
 
-use super::file_info::FileInfo;
+use super::{
+    file_info::FileInfo,
+    geometry::{Geometry, BLOCK_SIZE},
+    merkle,
+};
 
 pub(crate) struct Metadata {
     pub announce: Option<String>,
+    // Empty when the torrent only has `announce`.
     pub announce_list: Vec<Vec<String>>,
     pub info: FileInfo,
+    // Descriptive keys, preferring the `.utf-8` variants where present. These are read from
+    // `extra`, which stays the source of truth when the metafile is written back out.
+    pub comment: Option<String>,
+    pub created_by: Option<String>,
+    // Seconds since the Unix epoch.
+    pub creation_date: Option<i64>,
+    pub encoding: Option<String>,
+    // BEP 19 web seeds. A single URL is accepted in place of a list.
+    pub url_list: Vec<String>,
+    // BEP 17 HTTP seeds.
+    pub httpseeds: Vec<String>,
+    // DHT bootstrap nodes for trackerless torrents, as host and port.
+    pub nodes: Vec<(String, u16)>,
+    // The hash used on the wire: SHA-1 for v1 and hybrid torrents, or the v2 hash truncated to
+    // 20 bytes for v2-only torrents.
     pub info_hash: Vec<u8>,
+    // Full SHA-256 info hash, for v2 and hybrid torrents.
+    pub info_hash_v2: Option<Vec<u8>>,
+    // Concatenated piece hashes keyed by pieces root, for v2 files longer than one piece.
+    pub piece_layers: BTreeMap<Vec<u8>, Vec<u8>>,
+    // The info dictionary exactly as it appeared in the metafile. FileInfo only models the keys we
+    // use, so anything that is hashed or written back out must come from these bytes instead.
+    pub info_bytes: Vec<u8>,
+    // Top-level keys we don't model, kept so that saved metafiles match the original.
+    pub extra: BTreeMap<Vec<u8>, Value<'static>>,
 }
 
 impl FromBencode for Metadata {
-    const EXPECTED_RECURSION_DEPTH: usize = 5;
+    // Leaves room for the nested v2 file tree inside the info dictionary.
+    const EXPECTED_RECURSION_DEPTH: usize = 66;
 
     fn decode_bencode_object(object: bendy::decoding::Object) -> Result<Self, DecError>
     where
@@ -26,6 +60,9 @@ impl FromBencode for Metadata {
         let mut announce_list: Option<Vec<Vec<String>>> = None;
         let mut info: Option<FileInfo> = None;
         let mut info_hash: Option<Vec<u8>> = None;
+        let mut info_bytes: Option<Vec<u8>> = None;
+        let mut piece_layers = BTreeMap::new();
+        let mut extra = BTreeMap::new();
 
         let mut dict = object.try_into_dictionary()?;
 
@@ -44,81 +81,527 @@ impl FromBencode for Metadata {
                     let mut hasher: Sha1 = Sha1::new();
                     hasher.update(raw);
                     info_hash = Some(hasher.finalize().to_vec());
+                    info_bytes = Some(raw.to_vec());
 
                     info = FileInfo::from_bencode(raw).context("info").ok();
                 }
-                _ => {
-                    continue;
+                (b"piece layers", val) => {
+                    let mut layers = val.try_into_dictionary().context("piece layers")?;
+                    while let Some((root, layer)) = layers.next_pair()? {
+                        let layer = layer.try_into_bytes().context("piece layers")?;
+                        piece_layers.insert(root.to_vec(), layer.to_vec());
+                    }
+                }
+                (key, val) => {
+                    let val = Value::decode_bencode_object(val)?.into_owned();
+                    extra.insert(key.to_vec(), val);
                 }
             }
         }
 
-        let announce_list =
-            announce_list.ok_or_else(|| DecError::missing_field("announce-list"))?;
+        let announce_list = announce_list.unwrap_or_default();
         let info = info.ok_or_else(|| DecError::missing_field("info"))?;
         let info_hash = info_hash.ok_or_else(|| DecError::missing_field("info_hash"))?;
+        let info_bytes = info_bytes.ok_or_else(|| DecError::missing_field("info"))?;
+
+        let info_hash_v2 = if info.is_v2() {
+            Some(Sha256::digest(&info_bytes).to_vec())
+        } else {
+            None
+        };
+        let info_hash = match &info_hash_v2 {
+            Some(v2) if !info.is_hybrid() => v2[..20].to_vec(),
+            _ => info_hash,
+        };
+
+        // Layers that don't hash up to their pieces root are useless, so drop them and let the
+        // hashes be fetched from peers instead.
+        piece_layers.retain(|root, layer| {
+            merkle::split_layer(layer)
+                .map(|layer| merkle::layer_root(&layer, info.piece_length).as_slice() == root)
+                .unwrap_or(false)
+        });
 
         Ok(Metadata {
             announce,
             announce_list,
             info,
+            comment: extra_text(&extra, "comment"),
+            created_by: extra_text(&extra, "created by"),
+            creation_date: match extra.get(b"creation date".as_slice()) {
+                Some(Value::Integer(v)) => Some(*v),
+                _ => None,
+            },
+            encoding: extra_text(&extra, "encoding"),
+            url_list: extra_strings(&extra, b"url-list"),
+            httpseeds: extra_strings(&extra, b"httpseeds"),
+            nodes: extra_nodes(&extra),
             info_hash,
+            info_hash_v2,
+            piece_layers,
+            info_bytes,
+            extra,
         })
     }
 }
 
 impl ToBencode for Metadata {
-    const MAX_DEPTH: usize = 5;
+    const MAX_DEPTH: usize = 66;
 
     fn encode(&self, encoder: bendy::encoding::SingleItemEncoder) -> Result<(), EncError> {
-        encoder.emit_dict(|mut e| {
+        // Re-emit the original info dictionary rather than self.info, so the info hash is unchanged.
+        let info = Value::from_bencode(&self.info_bytes).map_err(EncError::malformed_content)?;
+
+        encoder.emit_unsorted_dict(|e| {
             match &self.announce {
                 Some(announce) => e.emit_pair(b"announce", announce)?,
                 None => {}
             };
-            e.emit_pair(b"announce-list", &self.announce_list)?;
-            e.emit_pair(b"info", &self.info)
+            if !self.announce_list.is_empty() {
+                e.emit_pair(b"announce-list", &self.announce_list)?;
+            }
+            e.emit_pair(b"info", &info)?;
+            if !self.piece_layers.is_empty() {
+                e.emit_pair_with(b"piece layers", |e| {
+                    e.emit_dict(|mut e| {
+                        for (root, layer) in &self.piece_layers {
+                            e.emit_pair(root, AsString(layer))?;
+                        }
+                        Ok(())
+                    })
+                })?;
+            }
+            for (key, val) in &self.extra {
+                e.emit_pair(key, val)?;
+            }
+            Ok(())
         })?;
 
         Ok(())
     }
 }
 
+fn value_str(val: &Value) -> Option<String> {
+    match val {
+        Value::Bytes(b) => Some(String::from_utf8_lossy(b).into_owned()),
+        _ => None,
+    }
+}
+
+// Text value of `key`, taken from `key.utf-8` if the torrent has one.
+fn extra_text(extra: &BTreeMap<Vec<u8>, Value<'static>>, key: &str) -> Option<String> {
+    extra
+        .get(format!("{key}.utf-8").as_bytes())
+        .or_else(|| extra.get(key.as_bytes()))
+        .and_then(value_str)
+}
+
+fn extra_strings(extra: &BTreeMap<Vec<u8>, Value<'static>>, key: &[u8]) -> Vec<String> {
+    match extra.get(key) {
+        Some(Value::List(list)) => list.iter().filter_map(value_str).collect(),
+        Some(val) => value_str(val).into_iter().collect(),
+        None => Vec::new(),
+    }
+}
+
+fn extra_nodes(extra: &BTreeMap<Vec<u8>, Value<'static>>) -> Vec<(String, u16)> {
+    let nodes = match extra.get(b"nodes".as_slice()) {
+        Some(Value::List(list)) => list,
+        _ => return Vec::new(),
+    };
+
+    nodes
+        .iter()
+        .filter_map(|node| match node {
+            Value::List(pair) => match pair.as_slice() {
+                [host, Value::Integer(port)] => {
+                    Some((value_str(host)?, u16::try_from(*port).ok()?))
+                }
+                _ => None,
+            },
+            _ => None,
+        })
+        .collect()
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum PieceCheck {
+    Valid,
+    Invalid,
+    // The piece belongs to a v2 file whose piece layer we don't have yet.
+    MissingLayer(Vec<u8>),
+}
+
 impl Metadata {
     pub fn num_pieces(&self) -> usize {
-        return self.info.pieces.len() / 20;
+        self.geometry().num_pieces().try_into().unwrap()
+    }
+
+    // Number of leading blocks of the piece that hold real data. Padding only ever runs to the
+    // end of a piece, so the blocks after these are known zeros and are never requested.
+    pub fn num_data_blocks(&self, index: u32) -> u32 {
+        let geometry = self.geometry();
+        let start = geometry.piece_offset(index);
+        let end = start + u64::from(geometry.piece_len(index));
+
+        let mut data_end = start;
+        let mut cur_pos: u64 = 0;
+        for file in &self.info.files {
+            if cur_pos >= end {
+                break;
+            }
+            if !file.is_padding() && file.length > 0 && cur_pos + file.length > start {
+                data_end = (cur_pos + file.length).min(end);
+            }
+            cur_pos += file.length;
+        }
+
+        // A piece made up only of padding still needs one block to complete it.
+        let data_len: u32 = (data_end - start).try_into().unwrap();
+        data_len
+            .div_ceil(BLOCK_SIZE)
+            .clamp(1, geometry.num_blocks(index).max(1))
+    }
+
+    // Checks a complete piece against the v1 piece hash and, for v2 and hybrid torrents, against
+    // the file's merkle tree. Layers received from peers can be passed in `extra_layers`.
+    pub fn verify_piece(
+        &self,
+        index: u32,
+        data: &[u8],
+        extra_layers: &HashMap<Vec<u8>, Vec<u8>>,
+    ) -> PieceCheck {
+        let index_usize: usize = index.try_into().unwrap();
+
+        if !self.info.pieces.is_empty() {
+            let expected = match self
+                .info
+                .pieces
+                .get(index_usize * 20..(index_usize + 1) * 20)
+            {
+                Some(v) => v,
+                None => return PieceCheck::Invalid,
+            };
+            if Sha1::digest(data).as_slice() != expected {
+                return PieceCheck::Invalid;
+            }
+        }
+
+        if !self.info.is_v2() {
+            return PieceCheck::Valid;
+        }
+
+        let (file, piece) = match self.info.v2_piece(index) {
+            Some(v) => v,
+            None => return PieceCheck::Invalid,
+        };
+        let root = match &file.pieces_root {
+            Some(v) => v,
+            None => return PieceCheck::Invalid,
+        };
+
+        // Drop any padding following the end of the file.
+        let piece_length = self.info.piece_length;
+        let file_data_len = (file.length - u64::from(piece) * piece_length).min(piece_length);
+        let data = match data.get(..file_data_len.try_into().unwrap()) {
+            Some(v) => v,
+            None => return PieceCheck::Invalid,
+        };
+
+        if file.length <= piece_length {
+            return match merkle::small_file_root(data).as_slice() == root.as_slice() {
+                true => PieceCheck::Valid,
+                false => PieceCheck::Invalid,
+            };
+        }
+
+        let layer = match self.piece_layers.get(root).or(extra_layers.get(root)) {
+            Some(v) => v,
+            None => return PieceCheck::MissingLayer(root.clone()),
+        };
+        let piece: usize = piece.try_into().unwrap();
+        let expected = match layer.get(piece * 32..(piece + 1) * 32) {
+            Some(v) => v,
+            None => return PieceCheck::Invalid,
+        };
+
+        match merkle::piece_hash(data, piece_length).as_slice() == expected {
+            true => PieceCheck::Valid,
+            false => PieceCheck::Invalid,
+        }
     }
 
-    pub fn num_blocks(&self) -> u32 {
-        return (self.info.piece_length / (2 << 13)).try_into().unwrap();
+    // Serves a BEP 52 hash request from our piece layers. Only requests for the piece layer
+    // itself can be answered, since block hashes aren't kept.
+    pub fn layer_hashes(
+        &self,
+        pieces_root: &[u8],
+        base_layer: u32,
+        index: u32,
+        length: u32,
+        proof_layers: u32,
+    ) -> Option<Vec<u8>> {
+        let piece_length = self.info.piece_length;
+        if base_layer != merkle::piece_layer_index(piece_length) {
+            return None;
+        }
+
+        let layer = merkle::split_layer(self.piece_layers.get(pieces_root)?)?;
+        let pad = merkle::pad_hash(merkle::leaves_per_piece(piece_length));
+        let hashes = merkle::layer_hashes(
+            &layer,
+            pad,
+            index.try_into().ok()?,
+            length.try_into().ok()?,
+            proof_layers.try_into().ok()?,
+        )?;
+
+        Some(hashes.concat())
     }
 
-    pub fn block_len(&self, index: u32, block_index: u32) -> u32 {
-        if index + 1 < self.num_pieces().try_into().unwrap() || block_index + 1 < self.num_blocks()
-        {
-            return 2 << 13;
-        } else {
-            let total_size = self.info.files.iter().map(|f| f.length).sum::<u32>();
-            let block_size = (index * self.info.piece_length) + (self.num_blocks() - 1) * (2 << 13);
-            return total_size - block_size;
+    pub fn total_length(&self) -> u64 {
+        self.info.total_length()
+    }
+
+    // Tracker tiers per BEP 12. `announce` is only used when there is no announce-list.
+    pub fn trackers(&self) -> Vec<Vec<String>> {
+        match &self.announce {
+            Some(announce) if self.announce_list.is_empty() => vec![vec![announce.clone()]],
+            _ => self.announce_list.clone(),
         }
     }
+
+    pub fn geometry(&self) -> Geometry {
+        Geometry::new(self.total_length(), self.info.piece_length)
+    }
 }
 
 pub(crate) fn read_metadata(path: &String) -> Result<Metadata, DecError> {
-    let res = std::fs::read(path).expect("Failed to read file");
+    let res = std::fs::read(path).map_err(DecError::malformed_content)?;
     let metadata = Metadata::from_bencode(&res)?;
 
     Ok(metadata)
 }
 
-pub(crate) fn get_urlenc_info_hash(metadata: &Metadata) -> Result<String, EncError> {
-    let bytes = metadata.info.to_bencode()?;
+pub(crate) fn write_metadata(path: &String, metadata: &Metadata) -> Result<(), EncError> {
+    let res = metadata.to_bencode()?;
+    std::fs::write(path, res).map_err(EncError::malformed_content)?;
+
+    Ok(())
+}
+
+// The info hash is always taken from the raw info bytes, never from a re-encoded FileInfo.
+pub(crate) fn get_urlenc_info_hash(metadata: &Metadata) -> String {
+    encode_binary(&metadata.info_hash).to_string()
+}
+
+pub(crate) fn get_magnet_link(metadata: &Metadata) -> String {
+    // v2-only torrents have no v1 hash, so only the multihash form is given for them.
+    let mut topics = Vec::new();
+    if !metadata.info.is_v2() || metadata.info.is_hybrid() {
+        topics.push(format!("xt=urn:btih:{}", hex::encode(&metadata.info_hash)));
+    }
+    if let Some(v2) = &metadata.info_hash_v2 {
+        topics.push(format!("xt=urn:btmh:1220{}", hex::encode(v2)));
+    }
+
+    let mut link = format!(
+        "magnet:?{}&dn={}",
+        topics.join("&"),
+        encode(&metadata.info.name)
+    );
+
+    let trackers = metadata
+        .announce
+        .iter()
+        .chain(metadata.announce_list.iter().flatten());
+    let mut seen = Vec::new();
+    for tracker in trackers {
+        if !seen.contains(&tracker) {
+            link.push_str(&format!("&tr={}", encode(tracker)));
+            seen.push(tracker);
+        }
+    }
+
+    link
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Contains keys in both the top level and the info dictionary that we don't model.
+    const RAW: &[u8] = b"d8:announce13:http://tr/ann13:announce-listll13:http://tr/annee7:comment5:hello4:infod6:lengthi5e6:md5sum32:0123456789abcdef0123456789abcdef4:name5:a.txt12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaa6:source3:labee";
+
+    fn info_slice() -> &'static [u8] {
+        let start = RAW.windows(5).position(|w| w == b"4:inf").unwrap() + 6;
+        &RAW[start..RAW.len() - 1]
+    }
+
+    #[test]
+    fn info_hash_uses_raw_info_bytes() {
+        let md = Metadata::from_bencode(RAW).unwrap();
+
+        let mut hasher: Sha1 = Sha1::new();
+        hasher.update(info_slice());
+        assert_eq!(md.info_bytes, info_slice());
+        assert_eq!(md.info_hash, hasher.finalize().to_vec());
+        assert_eq!(get_urlenc_info_hash(&md), encode_binary(&md.info_hash));
+    }
+
+    #[test]
+    fn re_encoding_preserves_unknown_keys() {
+        let md = Metadata::from_bencode(RAW).unwrap();
+
+        assert!(md.extra.contains_key(b"comment".as_slice()));
+        assert_eq!(md.to_bencode().unwrap(), RAW.to_vec());
+    }
+
+    #[test]
+    fn parses_descriptive_fields() {
+        let raw = b"d8:announce13:http://tr/ann7:comment3:old13:comment.utf-83:new10:created by\
+9:torrensic13:creation datei1700000000e8:encoding5:UTF-89:httpseedsl10:http://hs/e\
+4:infod6:lengthi5e4:name5:a.txt12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae\
+5:nodesll9:127.0.0.1i6881eel4:bad.i70000eee8:url-list10:http://ws/e";
+        let md = Metadata::from_bencode(raw).unwrap();
+
+        assert!(md.announce_list.is_empty());
+        assert_eq!(md.trackers(), vec![vec!["http://tr/ann".to_owned()]]);
+        assert_eq!(md.comment.as_deref(), Some("new"));
+        assert_eq!(md.created_by.as_deref(), Some("torrensic"));
+        assert_eq!(md.creation_date, Some(1_700_000_000));
+        assert_eq!(md.encoding.as_deref(), Some("UTF-8"));
+        assert_eq!(md.url_list, vec!["http://ws/".to_owned()]);
+        assert_eq!(md.httpseeds, vec!["http://hs/".to_owned()]);
+        assert_eq!(md.nodes, vec![("127.0.0.1".to_owned(), 6881)]);
+        assert_eq!(md.to_bencode().unwrap(), raw.to_vec());
+    }
+
+    #[test]
+    fn magnet_link_lists_trackers_once() {
+        let md = Metadata::from_bencode(RAW).unwrap();
+        let link = get_magnet_link(&md);
+
+        assert!(link.starts_with(&format!(
+            "magnet:?xt=urn:btih:{}",
+            hex::encode(&md.info_hash)
+        )));
+        assert_eq!(link.matches("&tr=").count(), 1);
+    }
+
+    #[test]
+    fn padding_blocks_are_not_requested() {
+        // a.bin fills one and a bit blocks of the first piece, the rest of which is padding.
+        let raw = format!(
+            "d4:infod5:filesld6:lengthi20000e4:pathl5:a.bineed4:attr1:p6:lengthi12768e\
+             4:pathl4:.pad5:12768eed6:lengthi40000e4:pathl5:b.bineee4:name3:dir\
+             12:piece lengthi32768e6:pieces60:{}ee",
+            "a".repeat(60)
+        );
+        let md = Metadata::from_bencode(raw.as_bytes()).unwrap();
+
+        assert_eq!(md.num_pieces(), 3);
+        assert_eq!(md.num_data_blocks(0), 2);
+        assert_eq!(md.num_data_blocks(1), 2);
+        assert_eq!(md.num_data_blocks(2), 1);
+    }
+
+    // A v2-only torrent holding one file of three and a bit 16KiB pieces.
+    fn v2_torrent(data: &[u8]) -> Vec<u8> {
+        let piece_length = 16384u64;
+        let layer: Vec<merkle::Hash> = data
+            .chunks(piece_length as usize)
+            .map(|piece| merkle::piece_hash(piece, piece_length))
+            .collect();
+        let root = merkle::layer_root(&layer, piece_length);
+
+        [
+            b"d8:announce13:http://tr/ann13:announce-listll13:http://tr/annee".to_vec(),
+            format!(
+                "4:infod9:file treed5:a.bind0:d6:lengthi{}e11:pieces root32:",
+                data.len()
+            )
+            .into_bytes(),
+            root.to_vec(),
+            b"eee12:meta versioni2e4:name5:a.bin12:piece lengthi16384ee".to_vec(),
+            b"12:piece layersd32:".to_vec(),
+            root.to_vec(),
+            format!("{}:", layer.len() * 32).into_bytes(),
+            layer.concat(),
+            b"ee".to_vec(),
+        ]
+        .concat()
+    }
 
-    let mut hasher: Sha1 = Sha1::new();
-    hasher.update(bytes);
-    let sha_hex: Vec<u8> = hasher.finalize().to_vec();
-    let sha_url = encode_binary(&sha_hex);
+    #[test]
+    fn v2_torrent_uses_truncated_sha256_info_hash() {
+        let data: Vec<u8> = (0..3 * 16384 + 100).map(|i| (i % 253) as u8).collect();
+        let md = Metadata::from_bencode(&v2_torrent(&data)).unwrap();
 
-    Ok(sha_url.to_string())
+        let v2 = Sha256::digest(&md.info_bytes).to_vec();
+        assert_eq!(md.info_hash_v2, Some(v2.clone()));
+        assert_eq!(md.info_hash, v2[..20].to_vec());
+        assert_eq!(md.num_pieces(), 4);
+        assert!(get_magnet_link(&md).starts_with("magnet:?xt=urn:btmh:1220"));
+    }
+
+    #[test]
+    fn v2_pieces_are_verified_against_piece_layer() {
+        let data: Vec<u8> = (0..3 * 16384 + 100).map(|i| (i % 253) as u8).collect();
+        let md = Metadata::from_bencode(&v2_torrent(&data)).unwrap();
+        let none = HashMap::new();
+
+        assert_eq!(
+            md.verify_piece(1, &data[16384..32768], &none),
+            PieceCheck::Valid
+        );
+        assert_eq!(
+            md.verify_piece(3, &data[3 * 16384..], &none),
+            PieceCheck::Valid
+        );
+        assert_eq!(
+            md.verify_piece(2, &data[16384..32768], &none),
+            PieceCheck::Invalid
+        );
+    }
+
+    #[test]
+    fn missing_piece_layer_is_reported() {
+        let data: Vec<u8> = (0..3 * 16384 + 100).map(|i| (i % 253) as u8).collect();
+        let mut md = Metadata::from_bencode(&v2_torrent(&data)).unwrap();
+        let layers = std::mem::take(&mut md.piece_layers);
+        let root = layers.keys().next().unwrap().clone();
+
+        assert_eq!(
+            md.verify_piece(0, &data[..16384], &HashMap::new()),
+            PieceCheck::MissingLayer(root)
+        );
+        let layers = layers.into_iter().collect();
+        assert_eq!(
+            md.verify_piece(0, &data[..16384], &layers),
+            PieceCheck::Valid
+        );
+    }
+
+    #[test]
+    fn corrupt_piece_layer_is_dropped() {
+        let data: Vec<u8> = (0..3 * 16384 + 100).map(|i| (i % 253) as u8).collect();
+        let mut raw = v2_torrent(&data);
+        let len = raw.len();
+        raw[len - 3] ^= 1;
+
+        let md = Metadata::from_bencode(&raw).unwrap();
+        assert!(md.piece_layers.is_empty());
+    }
+
+    #[test]
+    fn hash_requests_are_served_from_piece_layer() {
+        let data: Vec<u8> = (0..3 * 16384 + 100).map(|i| (i % 253) as u8).collect();
+        let md = Metadata::from_bencode(&v2_torrent(&data)).unwrap();
+        let root = md.piece_layers.keys().next().unwrap().clone();
+
+        let hashes = md.layer_hashes(&root, 0, 0, 4, 0).unwrap();
+        assert_eq!(hashes, md.piece_layers[&root]);
+        assert!(md.layer_hashes(&root, 1, 0, 4, 0).is_none());
+    }
 }
@@ -0,0 +1,288 @@
+use std::collections::HashSet;
+
+// Longest file name most filesystems accept, in bytes.
+const MAX_COMPONENT_LEN: usize = 255;
+// Extensions longer than this are treated as part of the name when truncating.
+const MAX_EXTENSION_LEN: usize = 16;
+
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+// Makes a single path component safe to create inside the download directory. Separators, NUL
+// and characters Windows rejects are replaced, `.` and empty components are dropped, `..` can't
+// climb out, reserved device names are renamed and long names are cut down keeping the
+// extension. The same rules are used on every platform, so a torrent lays out the same
+// everywhere.
+pub(crate) fn sanitise_component(component: &str) -> Option<String> {
+    if component.is_empty() || component == "." {
+        return None;
+    }
+    if component == ".." {
+        return Some(String::from("_"));
+    }
+
+    let replaced: String = component
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | '<' | '>' | ':' | '"' | '|' | '?' | '*' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+
+    // Windows silently drops trailing dots and spaces, which would make distinct names collide.
+    let mut name = replaced.trim_end_matches(['.', ' ']).to_owned();
+    if name.is_empty() {
+        name = String::from("_");
+    }
+
+    let stem = name.split('.').next().unwrap_or_default();
+    if RESERVED_NAMES.iter().any(|r| r.eq_ignore_ascii_case(stem)) {
+        name.insert(stem.len(), '_');
+    }
+
+    Some(truncate(&name, MAX_COMPONENT_LEN))
+}
+
+// Shortens a name to at most `max` bytes on a character boundary, keeping a short extension.
+fn truncate(name: &str, max: usize) -> String {
+    if name.len() <= max {
+        return name.to_owned();
+    }
+
+    let (stem, ext) = match name.rfind('.') {
+        Some(i) if i > 0 && name.len() - i <= MAX_EXTENSION_LEN => name.split_at(i),
+        _ => (name, ""),
+    };
+
+    let mut end = max - ext.len();
+    while !stem.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}{}", &stem[..end], ext)
+}
+
+pub(crate) fn sanitise_path(path: &[String]) -> Vec<String> {
+    let res: Vec<String> = path.iter().filter_map(|c| sanitise_component(c)).collect();
+    if res.is_empty() {
+        return vec![String::from("_")];
+    }
+    res
+}
+
+// Adds " (n)" before the extension.
+fn with_suffix(name: &str, n: usize) -> String {
+    let name = match name.rfind('.') {
+        Some(i) if i > 0 => format!("{} ({}){}", &name[..i], n, &name[i..]),
+        _ => format!("{} ({})", name, n),
+    };
+    truncate(&name, MAX_COMPONENT_LEN)
+}
+
+// Gives every file a distinct path, so that no two files of a torrent share storage and no file
+// sits where another needs a directory. Paths are compared case-insensitively to be safe on
+// case-insensitive filesystems. Later files are renamed, keeping earlier ones stable.
+pub(crate) struct PathDeduper {
+    files: HashSet<String>,
+    dirs: HashSet<String>,
+}
+
+impl PathDeduper {
+    pub(crate) fn new() -> Self {
+        PathDeduper {
+            files: HashSet::new(),
+            dirs: HashSet::new(),
+        }
+    }
+
+    pub(crate) fn dedupe(&mut self, path: Vec<String>) -> Vec<String> {
+        let mut res: Vec<String> = Vec::with_capacity(path.len());
+        let last = path.len() - 1;
+
+        for (i, component) in path.into_iter().enumerate() {
+            let mut candidate = component.clone();
+            let mut n = 0;
+            loop {
+                let key = key(&res, &candidate);
+                let taken = if i == last {
+                    self.files.contains(&key) || self.dirs.contains(&key)
+                } else {
+                    self.files.contains(&key)
+                };
+                if !taken {
+                    break;
+                }
+                n += 1;
+                candidate = with_suffix(&component, n);
+            }
+
+            res.push(candidate);
+            if i != last {
+                self.dirs.insert(key(&res, ""));
+            }
+        }
+
+        self.files.insert(key(&res, ""));
+        res
+    }
+}
+
+fn key(prefix: &[String], last: &str) -> String {
+    let mut key = prefix.join("/");
+    if !last.is_empty() {
+        if !key.is_empty() {
+            key.push('/');
+        }
+        key.push_str(last);
+    }
+    key.to_lowercase()
+}
+
+#[cfg(test)]
+mod test {
+    use bendy::decoding::FromBencode;
+
+    use super::*;
+    use crate::parser::file_info::FileInfo;
+
+    fn strings(v: &[&str]) -> Vec<String> {
+        v.iter().map(|s| s.to_string()).collect()
+    }
+
+    fn info(files: &str, name: &str) -> FileInfo {
+        let raw = format!(
+            "d5:filesl{}e4:name{}:{}12:piece lengthi16384e6:pieces20:{}e",
+            files,
+            name.len(),
+            name,
+            "a".repeat(20)
+        );
+        FileInfo::from_bencode(raw.as_bytes()).unwrap()
+    }
+
+    fn file(path: &[&str]) -> String {
+        let path: String = path.iter().map(|c| format!("{}:{}", c.len(), c)).collect();
+        format!("d6:lengthi1e4:pathl{}ee", path)
+    }
+
+    #[test]
+    fn parent_components_cannot_escape() {
+        let info = info(&file(&["..", "..", "etc", "passwd"]), "..");
+        assert_eq!(info.disk_name, "_");
+        assert_eq!(
+            info.files[0].disk_path,
+            strings(&["_", "_", "etc", "passwd"])
+        );
+    }
+
+    #[test]
+    fn absolute_and_separator_components_are_flattened() {
+        let info = info(
+            &[file(&["/etc/passwd"]), file(&["C:\\Windows", "x"])].concat(),
+            "/tmp",
+        );
+        assert_eq!(info.disk_name, "_tmp");
+        assert_eq!(info.files[0].disk_path, strings(&["_etc_passwd"]));
+        assert_eq!(info.files[1].disk_path, strings(&["C__Windows", "x"]));
+    }
+
+    #[test]
+    fn nul_and_control_characters_are_replaced() {
+        let info = info(&file(&["a\0b", "c\nd"]), "n\0");
+        assert_eq!(info.disk_name, "n_");
+        assert_eq!(info.files[0].disk_path, strings(&["a_b", "c_d"]));
+    }
+
+    #[test]
+    fn empty_and_dot_components_are_dropped() {
+        let info = info(&[file(&["", ".", "a"]), file(&["", "."])].concat(), "dir");
+        assert_eq!(info.files[0].disk_path, strings(&["a"]));
+        assert_eq!(info.files[1].disk_path, strings(&["_"]));
+    }
+
+    #[test]
+    fn reserved_names_are_renamed() {
+        let info = info(
+            &[file(&["con"]), file(&["LPT1.txt"]), file(&["console"])].concat(),
+            "AUX",
+        );
+        assert_eq!(info.disk_name, "AUX_");
+        assert_eq!(info.files[0].disk_path, strings(&["con_"]));
+        assert_eq!(info.files[1].disk_path, strings(&["LPT1_.txt"]));
+        assert_eq!(info.files[2].disk_path, strings(&["console"]));
+    }
+
+    #[test]
+    fn trailing_dots_and_spaces_are_trimmed() {
+        let info = info(&[file(&["a. ."]), file(&["..."])].concat(), "dir");
+        assert_eq!(info.files[0].disk_path, strings(&["a"]));
+        assert_eq!(info.files[1].disk_path, strings(&["_"]));
+    }
+
+    #[test]
+    fn long_components_keep_their_extension() {
+        let long = format!("{}.mkv", "x".repeat(300));
+        let info = info(&file(&[&long]), "dir");
+        let name = &info.files[0].disk_path[0];
+        assert_eq!(name.len(), MAX_COMPONENT_LEN);
+        assert!(name.ends_with("x.mkv"));
+    }
+
+    #[test]
+    fn truncation_respects_character_boundaries() {
+        let long = "é".repeat(200);
+        let name = sanitise_component(&long).unwrap();
+        assert!(name.len() <= MAX_COMPONENT_LEN);
+        assert!(name.chars().all(|c| c == 'é'));
+    }
+
+    #[test]
+    fn duplicate_paths_are_renamed() {
+        let info = info(
+            &[
+                file(&["a", "b.txt"]),
+                file(&["a", "b.txt"]),
+                file(&["A", "B.TXT"]),
+                file(&["a", "..", "a", "b.txt"]),
+            ]
+            .concat(),
+            "dir",
+        );
+        assert_eq!(info.files[0].disk_path, strings(&["a", "b.txt"]));
+        assert_eq!(info.files[1].disk_path, strings(&["a", "b (1).txt"]));
+        assert_eq!(info.files[2].disk_path, strings(&["A", "B (2).TXT"]));
+        assert_eq!(info.files[3].disk_path, strings(&["a", "_", "a", "b.txt"]));
+    }
+
+    #[test]
+    fn files_and_directories_do_not_collide() {
+        let info = info(
+            &[
+                file(&["a"]),
+                file(&["a", "b"]),
+                file(&["c", "d"]),
+                file(&["c"]),
+            ]
+            .concat(),
+            "dir",
+        );
+        assert_eq!(info.files[0].disk_path, strings(&["a"]));
+        assert_eq!(info.files[1].disk_path, strings(&["a (1)", "b"]));
+        assert_eq!(info.files[2].disk_path, strings(&["c", "d"]));
+        assert_eq!(info.files[3].disk_path, strings(&["c (1)"]));
+    }
+
+    #[test]
+    fn utf8_paths_are_preferred() {
+        let raw = format!(
+            "d5:filesld6:lengthi1e4:pathl4:cafee10:path.utf-8l5:caf\u{e9}eee4:name2:ne\
+             10:name.utf-83:n\u{e9}12:piece lengthi16384e6:pieces20:{}e",
+            "a".repeat(20)
+        );
+        let info = FileInfo::from_bencode(raw.as_bytes()).unwrap();
+        assert_eq!(info.disk_name, "n\u{e9}");
+        assert_eq!(info.files[0].disk_path, strings(&["caf\u{e9}"]));
+    }
+}
@@ -0,0 +1,200 @@
+use std::collections::HashMap;
+
+use bendy::{
+    decoding::{Error as DecError, FromBencode, Object, ResultExt},
+    encoding::{Error as EncError, SingleItemEncoder, ToBencode},
+};
+
+// How healthy a torrent's swarm is, according to a tracker.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct ScrapeInfo {
+    // Seeders.
+    pub complete: u32,
+    // Times the torrent was downloaded to completion.
+    pub downloaded: u32,
+    // Leechers.
+    pub incomplete: u32,
+}
+
+impl ScrapeInfo {
+    pub(crate) fn swarm_size(&self) -> u32 {
+        self.complete.saturating_add(self.incomplete)
+    }
+}
+
+// A tracker's answer to a scrape, with stats for each info hash it knows.
+pub(crate) struct ScrapeResponse {
+    pub files: HashMap<Vec<u8>, ScrapeInfo>,
+    pub failure_reason: Option<String>,
+}
+
+impl FromBencode for ScrapeInfo {
+    const EXPECTED_RECURSION_DEPTH: usize = 1;
+
+    fn decode_bencode_object(object: Object) -> Result<Self, DecError>
+    where
+        Self: Sized,
+    {
+        let mut info = ScrapeInfo::default();
+        let mut dict = object.try_into_dictionary()?;
+
+        while let Some(pair) = dict.next_pair()? {
+            match pair {
+                (b"complete", val) => {
+                    info.complete = u32::decode_bencode_object(val).context("complete")?;
+                }
+                (b"downloaded", val) => {
+                    info.downloaded = u32::decode_bencode_object(val).context("downloaded")?;
+                }
+                (b"incomplete", val) => {
+                    info.incomplete = u32::decode_bencode_object(val).context("incomplete")?;
+                }
+                _ => continue,
+            }
+        }
+
+        Ok(info)
+    }
+}
+
+impl FromBencode for ScrapeResponse {
+    const EXPECTED_RECURSION_DEPTH: usize = 3;
+
+    fn decode_bencode_object(object: Object) -> Result<Self, DecError>
+    where
+        Self: Sized,
+    {
+        let mut files = HashMap::new();
+        let mut failure_reason = None;
+        let mut dict = object.try_into_dictionary()?;
+
+        while let Some(pair) = dict.next_pair()? {
+            match pair {
+                (b"failure reason", val) => {
+                    failure_reason =
+                        Some(String::decode_bencode_object(val).context("failure reason")?);
+                }
+                (b"files", val) => {
+                    let mut entries = val.try_into_dictionary().context("files")?;
+                    while let Some((info_hash, val)) = entries.next_pair()? {
+                        let info = ScrapeInfo::decode_bencode_object(val).context("files")?;
+                        files.insert(info_hash.to_vec(), info);
+                    }
+                }
+                _ => continue,
+            }
+        }
+
+        Ok(ScrapeResponse {
+            files,
+            failure_reason,
+        })
+    }
+}
+
+impl ToBencode for ScrapeInfo {
+    const MAX_DEPTH: usize = 1;
+
+    fn encode(&self, encoder: SingleItemEncoder) -> Result<(), EncError> {
+        encoder.emit_dict(|mut e| {
+            e.emit_pair(b"complete", self.complete)?;
+            e.emit_pair(b"downloaded", self.downloaded)?;
+            e.emit_pair(b"incomplete", self.incomplete)
+        })
+    }
+}
+
+impl ToBencode for ScrapeResponse {
+    const MAX_DEPTH: usize = 3;
+
+    fn encode(&self, encoder: SingleItemEncoder) -> Result<(), EncError> {
+        encoder.emit_dict(|mut e| {
+            if let Some(reason) = &self.failure_reason {
+                e.emit_pair(b"failure reason", reason)?;
+            }
+            e.emit_pair_with(b"files", |e| {
+                e.emit_unsorted_dict(|e| {
+                    for (info_hash, info) in &self.files {
+                        e.emit_pair(info_hash, info)?;
+                    }
+                    Ok(())
+                })
+            })
+        })
+    }
+}
+
+impl ScrapeResponse {
+    // A UDP scrape answer holds seeders, completed and leechers for each info hash, in the order
+    // they were asked for, after the action and transaction ID.
+    pub(crate) fn from_udp(raw: &[u8], info_hashes: &[Vec<u8>]) -> Self {
+        let entries = raw.get(8..).unwrap_or_default().chunks_exact(12);
+        let files = info_hashes
+            .iter()
+            .zip(entries)
+            .map(|(info_hash, entry)| {
+                let field = |at: usize| u32::from_be_bytes(entry[at..at + 4].try_into().unwrap());
+                let info = ScrapeInfo {
+                    complete: field(0),
+                    downloaded: field(4),
+                    incomplete: field(8),
+                };
+                (info_hash.clone(), info)
+            })
+            .collect();
+
+        ScrapeResponse {
+            files,
+            failure_reason: None,
+        }
+    }
+
+    // The UDP scrape reply read by `from_udp`. Info hashes the tracker doesn't know are
+    // answered with zeros.
+    pub(crate) fn to_udp(&self, trans_id: u32, info_hashes: &[Vec<u8>]) -> Vec<u8> {
+        let action: u32 = 2;
+        let mut raw = [action.to_be_bytes(), trans_id.to_be_bytes()].concat();
+        for info_hash in info_hashes {
+            let info = self.files.get(info_hash).copied().unwrap_or_default();
+            for field in [info.complete, info.downloaded, info.incomplete] {
+                raw.extend(field.to_be_bytes());
+            }
+        }
+        raw
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn http_and_udp_scrapes_are_decoded() {
+        let mut raw = b"d5:filesd20:".to_vec();
+        raw.extend([7; 20]);
+        raw.extend(b"d8:completei5e10:downloadedi50e10:incompletei10e4:name3:fooeee");
+        let res = ScrapeResponse::from_bencode(&raw).unwrap();
+        let expected = ScrapeInfo {
+            complete: 5,
+            downloaded: 50,
+            incomplete: 10,
+        };
+        assert_eq!(res.files.get(&vec![7; 20]), Some(&expected));
+        assert_eq!(expected.swarm_size(), 15);
+
+        let mut raw = vec![0, 0, 0, 2, 0, 0, 0, 9];
+        for field in [5u32, 50, 10, 1, 2, 3] {
+            raw.extend(field.to_be_bytes());
+        }
+        let res = ScrapeResponse::from_udp(&raw, &[vec![7; 20], vec![8; 20], vec![9; 20]]);
+        assert_eq!(res.files.len(), 2);
+        assert_eq!(res.files[&vec![7; 20]], expected);
+        assert_eq!(res.files[&vec![8; 20]].incomplete, 3);
+
+        let hashes = [vec![7; 20], vec![8; 20]];
+        let decoded = ScrapeResponse::from_udp(&res.to_udp(9, &hashes), &hashes);
+        assert_eq!(decoded.files, res.files);
+        let raw = res.to_bencode().unwrap();
+        assert_eq!(ScrapeResponse::from_bencode(&raw).unwrap().files, res.files);
+    }
+}
@@ -1,15 +1,43 @@
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
 use bendy::{
-    decoding::{Error as DecError, FromBencode, ResultExt},
-    encoding::{Error as EncError, ToBencode},
+    decoding::{Error as DecError, FromBencode, Object, ResultExt},
+    encoding::{AsString, Error as EncError, ToBencode},
 };
 use byteorder::{BigEndian, ReadBytesExt};
 
 pub(crate) struct TrackerInfo {
     pub interval: u32,
+    // Announces must never be sent more often than this.
+    pub min_interval: Option<u32>,
     pub tracker_id: Option<String>,
     pub peers: Vec<PeerInfo>,
+    // Set when the tracker refused the announce, in which case nothing else is meaningful.
+    pub failure_reason: Option<String>,
+    // The announce went through, but the tracker has something to say about it.
+    pub warning_message: Option<String>,
+    // Seeders and leechers, if the tracker reports them.
+    pub complete: Option<u32>,
+    pub incomplete: Option<u32>,
+}
+
+// Peers in the compact form of BEP 23 and BEP 7: the address and port of each, packed
+// back to back. Trailing bytes that don't make up a whole peer are ignored.
+fn compact_peers(raw: &[u8], addr_len: usize) -> Vec<PeerInfo> {
+    raw.chunks_exact(addr_len + 2)
+        .map(|chunk| {
+            let (ip, port) = chunk.split_at(addr_len);
+            let ip = match <[u8; 16]>::try_from(ip) {
+                Ok(v6) => Ipv6Addr::from(v6).to_string(),
+                Err(_) => Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3]).to_string(),
+            };
+            PeerInfo {
+                peer_id: None,
+                ip,
+                port: u16::from_be_bytes([port[0], port[1]]),
+            }
+        })
+        .collect()
 }
 
 impl FromBencode for TrackerInfo {
@@ -20,8 +48,14 @@ impl FromBencode for TrackerInfo {
         Self: Sized,
     {
         let mut interval: Option<u32> = None;
+        let mut min_interval: Option<u32> = None;
         let mut tracker_id: Option<String> = None;
         let mut peers: Option<Vec<PeerInfo>> = None;
+        let mut peers6: Vec<PeerInfo> = Vec::new();
+        let mut failure_reason: Option<String> = None;
+        let mut warning_message: Option<String> = None;
+        let mut complete: Option<u32> = None;
+        let mut incomplete: Option<u32> = None;
 
         let mut dict = object.try_into_dictionary()?;
 
@@ -30,27 +64,74 @@ impl FromBencode for TrackerInfo {
                 (b"interval", val) => {
                     interval = u32::decode_bencode_object(val).context("interval").ok();
                 }
+                (b"min interval", val) => {
+                    min_interval = u32::decode_bencode_object(val).context("min interval").ok();
+                }
                 (b"tracker id", val) => {
                     tracker_id = String::decode_bencode_object(val)
                         .context("tracker id")
                         .ok();
                 }
+                (b"peers", Object::Bytes(raw)) => peers = Some(compact_peers(raw, 4)),
                 (b"peers", val) => {
                     peers = Vec::decode_bencode_object(val).context("peers").ok();
                 }
+                (b"peers6", val) => {
+                    peers6 = compact_peers(val.try_into_bytes().context("peers6")?, 16);
+                }
+                (b"failure reason", val) => {
+                    failure_reason =
+                        Some(String::decode_bencode_object(val).context("failure reason")?);
+                }
+                (b"warning message", val) => {
+                    warning_message = String::decode_bencode_object(val)
+                        .context("warning message")
+                        .ok();
+                }
+                (b"complete", val) => {
+                    complete = u32::decode_bencode_object(val).context("complete").ok();
+                }
+                (b"incomplete", val) => {
+                    incomplete = u32::decode_bencode_object(val).context("incomplete").ok();
+                }
                 _ => {
                     continue;
                 }
             }
         }
 
+        // A refusal carries no other fields.
+        if failure_reason.is_some() {
+            return Ok(TrackerInfo {
+                interval: interval.unwrap_or(0),
+                min_interval,
+                tracker_id,
+                peers: Vec::new(),
+                failure_reason,
+                warning_message,
+                complete,
+                incomplete,
+            });
+        }
+
         let interval = interval.ok_or_else(|| DecError::missing_field("interval"))?;
-        let peers = peers.ok_or_else(|| DecError::missing_field("peers"))?;
+        let mut peers = match (peers, peers6.is_empty()) {
+            (Some(peers), _) => peers,
+            // Trackers only handing out IPv6 peers may leave `peers` out.
+            (None, false) => Vec::new(),
+            (None, true) => return Err(DecError::missing_field("peers")),
+        };
+        peers.extend(peers6);
 
         Ok(TrackerInfo {
             interval,
+            min_interval,
             tracker_id,
             peers,
+            failure_reason,
+            warning_message,
+            complete,
+            incomplete,
         })
     }
 }
@@ -59,55 +140,107 @@ impl ToBencode for TrackerInfo {
     const MAX_DEPTH: usize = 5;
 
     fn encode(&self, encoder: bendy::encoding::SingleItemEncoder) -> Result<(), EncError> {
+        self.encode_dict(encoder, false)
+    }
+}
+
+// Encodes peers in the compact form, as asked for with `compact=1`.
+pub(crate) struct CompactTrackerInfo<'a>(pub &'a TrackerInfo);
+
+impl ToBencode for CompactTrackerInfo<'_> {
+    const MAX_DEPTH: usize = 5;
+
+    fn encode(&self, encoder: bendy::encoding::SingleItemEncoder) -> Result<(), EncError> {
+        self.0.encode_dict(encoder, true)
+    }
+}
+
+impl TrackerInfo {
+    fn encode_dict(
+        &self,
+        encoder: bendy::encoding::SingleItemEncoder,
+        compact: bool,
+    ) -> Result<(), EncError> {
         encoder.emit_dict(|mut e| {
+            if let Some(complete) = self.complete {
+                e.emit_pair(b"complete", complete)?;
+            }
+            if let Some(reason) = &self.failure_reason {
+                e.emit_pair(b"failure reason", reason)?;
+            }
+            if let Some(incomplete) = self.incomplete {
+                e.emit_pair(b"incomplete", incomplete)?;
+            }
             e.emit_pair(b"interval", self.interval)?;
+            if let Some(min_interval) = self.min_interval {
+                e.emit_pair(b"min interval", min_interval)?;
+            }
+            if compact {
+                let (v4, v6): (Vec<_>, Vec<_>) = self
+                    .peers
+                    .iter()
+                    .filter_map(PeerInfo::to_compact)
+                    .partition(|peer| peer.len() == 6);
+                e.emit_pair(b"peers", AsString(v4.concat()))?;
+                if !v6.is_empty() {
+                    e.emit_pair(b"peers6", AsString(v6.concat()))?;
+                }
+            } else {
+                e.emit_pair(b"peers", &self.peers)?;
+            }
             match &self.tracker_id {
                 Some(id) => e.emit_pair(b"tracker id", id)?,
                 None => {}
             };
-            e.emit_pair(b"peers", &self.peers)
+            if let Some(warning) = &self.warning_message {
+                e.emit_pair(b"warning message", warning)?;
+            }
+            Ok(())
         })?;
         Ok(())
     }
-}
 
-impl TrackerInfo {
     pub fn from_raw(raw: Vec<u8>) -> Result<Self, ()> {
         let mut interval = &raw[8..12];
         let mut leechers = &raw[12..16];
         let mut seeders = &raw[16..20];
 
-        // TODO: include leecher/seeder info in TrackerInfo
         let interval = interval.read_u32::<BigEndian>().unwrap();
-        let _leechers = leechers.read_u32::<BigEndian>().unwrap();
-        let _seeders = seeders.read_u32::<BigEndian>().unwrap();
-
-        let raw_peers: Vec<_> = raw[20..]
-            .to_vec()
-            .chunks_exact(6)
-            .map(|v| v.to_vec())
-            .collect();
-
-        let mut peers: Vec<PeerInfo> = Vec::new();
-
-        for chunk in raw_peers {
-            let (mut ip, mut port) = chunk.split_at(4);
-            let ip = Ipv4Addr::from(ip.read_u32::<BigEndian>().unwrap()).to_string();
-            let port = port.read_u16::<BigEndian>().unwrap();
-
-            peers.push(PeerInfo {
-                ip,
-                port,
-                peer_id: None,
-            });
-        }
+        let leechers = leechers.read_u32::<BigEndian>().unwrap();
+        let seeders = seeders.read_u32::<BigEndian>().unwrap();
 
         Ok(TrackerInfo {
             interval,
-            peers,
+            min_interval: None,
+            peers: compact_peers(&raw[20..], 4),
             tracker_id: None,
+            failure_reason: None,
+            warning_message: None,
+            complete: Some(seeders),
+            incomplete: Some(leechers),
         })
     }
+
+    // The UDP announce reply read by `from_raw`. Only IPv4 peers fit in it.
+    pub fn to_raw(&self, trans_id: u32) -> Vec<u8> {
+        let action: u32 = 1;
+        let peers: Vec<Vec<u8>> = self
+            .peers
+            .iter()
+            .filter_map(PeerInfo::to_compact)
+            .filter(|peer| peer.len() == 6)
+            .collect();
+
+        [
+            action.to_be_bytes().to_vec(),
+            trans_id.to_be_bytes().to_vec(),
+            self.interval.to_be_bytes().to_vec(),
+            self.incomplete.unwrap_or(0).to_be_bytes().to_vec(),
+            self.complete.unwrap_or(0).to_be_bytes().to_vec(),
+            peers.concat(),
+        ]
+        .concat()
+    }
 }
 
 #[derive(Clone)]
@@ -175,7 +308,88 @@ impl ToBencode for PeerInfo {
 }
 
 impl PeerInfo {
+    // The address and port packed as in compact peer lists, unless the IP isn't an address.
+    pub fn to_compact(&self) -> Option<Vec<u8>> {
+        let ip = match self.ip.parse::<IpAddr>().ok()? {
+            IpAddr::V4(ip) => ip.octets().to_vec(),
+            IpAddr::V6(ip) => ip.octets().to_vec(),
+        };
+        Some([ip, self.port.to_be_bytes().to_vec()].concat())
+    }
+
+    // IPv6 addresses are bracketed, so the result can be connected to as is.
     pub fn to_string(&self) -> String {
-        format!("{}:{}", self.ip, self.port)
+        if self.ip.contains(':') {
+            format!("[{}]:{}", self.ip, self.port)
+        } else {
+            format!("{}:{}", self.ip, self.port)
+        }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn compact_and_ipv6_peers_are_decoded() {
+        let mut raw = b"d8:completei5e10:incompletei3e8:intervali1800e5:peers12:".to_vec();
+        raw.extend([10, 0, 0, 1, 0x1a, 0xe1, 192, 168, 1, 2, 0x1a, 0xe2]);
+        raw.extend(b"6:peers618:");
+        raw.extend(Ipv6Addr::LOCALHOST.octets());
+        raw.extend([0x1a, 0xe3]);
+        raw.extend(b"15:warning message4:slowe");
+
+        let info = TrackerInfo::from_bencode(&raw).unwrap();
+        let peers: Vec<String> = info.peers.iter().map(PeerInfo::to_string).collect();
+        assert_eq!(peers, ["10.0.0.1:6881", "192.168.1.2:6882", "[::1]:6883"]);
+        assert_eq!(info.interval, 1800);
+        assert_eq!((info.complete, info.incomplete), (Some(5), Some(3)));
+        assert_eq!(info.warning_message.as_deref(), Some("slow"));
+        assert_eq!(info.failure_reason, None);
+    }
+
+    #[test]
+    fn compact_encoding_round_trips() {
+        let peer = |ip: &str, port| PeerInfo {
+            peer_id: None,
+            ip: String::from(ip),
+            port,
+        };
+        let info = TrackerInfo {
+            interval: 300,
+            min_interval: Some(60),
+            tracker_id: None,
+            peers: vec![peer("10.0.0.1", 6881), peer("::1", 6882)],
+            failure_reason: None,
+            warning_message: None,
+            complete: Some(1),
+            incomplete: Some(2),
+        };
+
+        let raw = CompactTrackerInfo(&info).to_bencode().unwrap();
+        let decoded = TrackerInfo::from_bencode(&raw).unwrap();
+        let peers: Vec<String> = decoded.peers.iter().map(PeerInfo::to_string).collect();
+        assert_eq!(peers, ["10.0.0.1:6881", "[::1]:6882"]);
+        assert_eq!(decoded.min_interval, Some(60));
+
+        let decoded = TrackerInfo::from_raw(info.to_raw(9)).unwrap();
+        assert_eq!(decoded.interval, 300);
+        assert_eq!((decoded.complete, decoded.incomplete), (Some(1), Some(2)));
+        assert_eq!(decoded.peers.len(), 1);
+    }
+
+    #[test]
+    fn dictionary_peers_and_failures_are_decoded() {
+        let raw = b"d8:intervali900e12:min intervali60e5:peersld2:ip8:10.0.0.14:porti6881eeee";
+        let info = TrackerInfo::from_bencode(raw).unwrap();
+        assert_eq!(info.peers[0].to_string(), "10.0.0.1:6881");
+        assert_eq!(info.min_interval, Some(60));
+
+        let info = TrackerInfo::from_bencode(b"d14:failure reason11:not allowede").unwrap();
+        assert_eq!(info.failure_reason.as_deref(), Some("not allowed"));
+        assert!(info.peers.is_empty());
+
+        assert!(TrackerInfo::from_bencode(b"d8:intervali900ee").is_err());
+    }
+}
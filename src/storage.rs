@@ -0,0 +1,349 @@
+pub mod disk_io;
+pub mod fs_storage;
+#[cfg(test)]
+pub mod memory_storage;
+pub mod mmap_storage;
+pub mod resume;
+
+use std::{
+    collections::HashMap,
+    io::{self, Error as IOError, ErrorKind},
+    ops::Range,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    thread,
+};
+
+use bitvec::{prelude::Msb0, vec::BitVec};
+use tokio::task;
+
+use crate::parser::{
+    file_info::FilePathInfo,
+    metadata::{Metadata, PieceCheck},
+};
+
+use fs_storage::FsStorage;
+use mmap_storage::MmapStorage;
+use resume::FileState;
+
+// What `allocate` found on disk for a torrent.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum DiskState {
+    // Nothing was there, so every file was freshly allocated.
+    New,
+    // All files were present with the expected sizes.
+    Existing,
+    // Some data was there but doesn't match the metainfo, so the pieces must be rechecked.
+    NeedsRecheck,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum StorageKind {
+    #[default]
+    Fs,
+    Mmap,
+}
+
+// How space for a torrent's files is claimed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum AllocationMode {
+    // Files get their full size up front, but disk space is only used as data is written.
+    #[default]
+    Sparse,
+    // All disk space is reserved up front, so the disk can't fill up mid-download.
+    Full,
+    // Files are only created once data is first written to them.
+    Lazy,
+}
+
+// How a torrent's files are laid out on disk.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct StorageOptions {
+    pub allocation: AllocationMode,
+    // Files being downloaded carry a `.part` suffix until all their pieces are verified.
+    pub part_files: bool,
+    // Files being downloaded are kept here rather than in the download directory.
+    pub incomplete_dir: Option<String>,
+}
+
+// The data of one torrent. Blocks are addressed the same way as on the wire; backends map them
+// onto files. All calls block, so async code goes through `blocking`.
+pub(crate) trait Storage: Send + Sync {
+    fn metadata(&self) -> &Metadata;
+
+    // Checks that the download fits on disk, then creates missing files and resizes existing ones
+    // to match the metainfo, never discarding data that is already there. Fails with
+    // `StorageFull` when there isn't enough free space.
+    fn allocate(&self) -> io::Result<DiskState>;
+
+    // Padding and data that was never written read as zeros.
+    fn read_block(&self, index: u32, begin: u32, len: u32) -> io::Result<Vec<u8>>;
+
+    fn write_block(&self, index: u32, begin: u32, data: &[u8]) -> io::Result<()>;
+
+    fn hash_piece(&self, index: u32) -> io::Result<bool> {
+        let md = self.metadata();
+        let data = self.read_block(index, 0, md.geometry().piece_len(index))?;
+        Ok(md.verify_piece(index, &data, &HashMap::new()) == PieceCheck::Valid)
+    }
+
+    fn flush(&self) -> io::Result<()>;
+
+    // The state of every file in metainfo order, used to notice changes between sessions.
+    fn file_states(&self) -> io::Result<Vec<FileState>>;
+
+    // Gives a file whose pieces are all on disk its final name and place.
+    fn finish_file(&self, file: usize) -> io::Result<()>;
+
+    // Moves the torrent's files under a new download directory.
+    fn move_to(&self, dir: &str) -> io::Result<()>;
+}
+
+pub(crate) fn open(
+    kind: StorageKind,
+    md: Arc<Metadata>,
+    dir: &str,
+    options: &StorageOptions,
+) -> Arc<dyn Storage> {
+    match kind {
+        StorageKind::Fs => Arc::new(FsStorage::new(md, dir, options)),
+        StorageKind::Mmap => Arc::new(MmapStorage::new(md, dir, options)),
+    }
+}
+
+// Runs a storage call on the blocking pool, keeping disk I/O off the async executor.
+pub(crate) async fn blocking<R, F>(storage: &Arc<dyn Storage>, f: F) -> io::Result<R>
+where
+    F: FnOnce(&dyn Storage) -> io::Result<R> + Send + 'static,
+    R: Send + 'static,
+{
+    let storage = storage.clone();
+    task::spawn_blocking(move || f(storage.as_ref()))
+        .await
+        .map_err(IOError::other)?
+}
+
+// Part of a block that lies within a single file: `range` of the block is stored at `offset`
+// in file number `file`.
+pub(crate) struct Slice {
+    pub file: usize,
+    pub offset: u64,
+    pub range: Range<usize>,
+}
+
+// Maps a block onto the files holding it. Padding files are left out, so their part of the
+// block is never stored.
+pub(crate) fn slices(md: &Metadata, index: u32, begin: u32, len: usize) -> io::Result<Vec<Slice>> {
+    let geometry = md.geometry();
+    if u64::from(begin) + len as u64 > u64::from(geometry.piece_len(index)) {
+        return Err(IOError::new(ErrorKind::InvalidInput, "Block out of range"));
+    }
+
+    let start_pos = geometry.piece_offset(index) + u64::from(begin);
+    let end_pos = start_pos + len as u64;
+    let mut res = Vec::new();
+    let mut cur_pos: u64 = 0;
+
+    for (i, file) in md.info.files.iter().enumerate() {
+        if cur_pos >= end_pos {
+            break;
+        }
+        if cur_pos + file.length > start_pos && !file.is_padding() {
+            let start = start_pos.max(cur_pos);
+            let end = end_pos.min(cur_pos + file.length);
+            res.push(Slice {
+                file: i,
+                offset: start - cur_pos,
+                range: (start - start_pos) as usize..(end - start_pos) as usize,
+            });
+        }
+        cur_pos += file.length;
+    }
+
+    Ok(res)
+}
+
+// The pieces holding any of a file's data.
+pub(crate) fn file_pieces(md: &Metadata, file: usize) -> Range<u32> {
+    let piece_length = md.info.piece_length;
+    let start: u64 = md.info.files[..file].iter().map(|f| f.length).sum();
+    let end = start + md.info.files[file].length;
+
+    let first = (start / piece_length).try_into().unwrap();
+    if start == end {
+        return first..first;
+    }
+    first..end.div_ceil(piece_length).try_into().unwrap()
+}
+
+// Multi-file torrents are placed under <dir>/<name>/, single-file torrents directly at <dir>/<name>.
+// Only sanitised names are used, so nothing can be written outside `dir`.
+pub(crate) fn file_path(md: &Metadata, dir: &str, file: &FilePathInfo) -> String {
+    if md.info.single_file {
+        format!("{}/{}", dir, &md.info.disk_name)
+    } else {
+        format!(
+            "{}/{}/{}",
+            dir,
+            &md.info.disk_name,
+            &file.disk_path.join("/")
+        )
+    }
+}
+
+// The file or directory holding all of the torrent's data.
+pub(crate) fn root_path(md: &Metadata, dir: &str) -> String {
+    format!("{}/{}", dir, &md.info.disk_name)
+}
+
+// Hashes every piece, spread over one thread per core with each taking the next unchecked
+// piece. `progress` is called with the number of pieces checked so far and the total after
+// every piece.
+pub(crate) fn recheck<F>(storage: &dyn Storage, progress: F) -> io::Result<BitVec<u8, Msb0>>
+where
+    F: Fn(u32, u32) + Sync,
+{
+    let md = storage.metadata();
+    let num_pieces = md.geometry().num_pieces();
+    let num_threads = thread::available_parallelism().map_or(1, |n| n.get());
+    let next = AtomicU32::new(0);
+    let checked = AtomicU32::new(0);
+
+    let results: Vec<io::Result<Vec<(u32, bool)>>> = thread::scope(|s| {
+        let handles: Vec<_> = (0..num_threads)
+            .map(|_| {
+                s.spawn(|| {
+                    let mut res = Vec::new();
+                    loop {
+                        let index = next.fetch_add(1, Ordering::Relaxed);
+                        if index >= num_pieces {
+                            return Ok(res);
+                        }
+
+                        res.push((index, storage.hash_piece(index)?));
+                        progress(checked.fetch_add(1, Ordering::Relaxed) + 1, num_pieces);
+                    }
+                })
+            })
+            .collect();
+
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    });
+
+    let mut bitfield: BitVec<u8, Msb0> = BitVec::repeat(false, md.num_pieces());
+    for res in results {
+        for (index, valid) in res? {
+            bitfield.set(index.try_into().unwrap(), valid);
+        }
+    }
+
+    Ok(bitfield)
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use bendy::decoding::FromBencode;
+    use sha1::{Digest, Sha1};
+
+    use super::*;
+
+    // Two files of 20000 and 30000 bytes in 16KiB pieces, with the data used to hash them.
+    pub(crate) fn two_file_torrent() -> (Metadata, Vec<u8>) {
+        two_file_torrent_with_pieces(16384)
+    }
+
+    pub(crate) fn two_file_torrent_with_pieces(piece_length: usize) -> (Metadata, Vec<u8>) {
+        let data: Vec<u8> = (0..50_000u32).map(|i| (i % 253) as u8).collect();
+        let pieces: Vec<u8> = data
+            .chunks(piece_length)
+            .flat_map(|piece| Sha1::digest(piece).to_vec())
+            .collect();
+        let mut raw = format!(
+            "d4:infod5:filesld6:lengthi20000e4:pathl1:aeed6:lengthi30000e4:pathl1:beee\
+             4:name3:dir12:piece lengthi{}e6:pieces{}:",
+            piece_length,
+            pieces.len()
+        )
+        .into_bytes();
+        raw.extend_from_slice(&pieces);
+        raw.extend_from_slice(b"ee");
+
+        (Metadata::from_bencode(&raw).unwrap(), data)
+    }
+
+    // One file of 20000 bytes in 16KiB pieces, with the data used to hash it.
+    pub(crate) fn single_file_torrent() -> (Metadata, Vec<u8>) {
+        let data: Vec<u8> = (0..20_000u32).map(|i| (i % 251) as u8).collect();
+        let pieces: Vec<u8> = data
+            .chunks(16384)
+            .flat_map(|piece| Sha1::digest(piece).to_vec())
+            .collect();
+        let mut raw = format!(
+            "d4:infod6:lengthi20000e4:name5:a.bin12:piece lengthi16384e6:pieces{}:",
+            pieces.len()
+        )
+        .into_bytes();
+        raw.extend_from_slice(&pieces);
+        raw.extend_from_slice(b"ee");
+
+        (Metadata::from_bencode(&raw).unwrap(), data)
+    }
+
+    // A directory for one test under the system temp dir, cleared of anything left from earlier
+    // runs.
+    pub(crate) fn temp_dir(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("torrensic-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir.to_str().unwrap().to_owned()
+    }
+
+    #[test]
+    fn slices_span_file_boundaries_and_skip_padding() {
+        let raw = format!(
+            "d4:infod5:filesld6:lengthi20000e4:pathl1:aeed4:attr1:p6:lengthi12768e\
+             4:pathl4:.pad5:12768eed6:lengthi100e4:pathl1:beee4:name3:dir\
+             12:piece lengthi32768e6:pieces40:{}ee",
+            "a".repeat(40)
+        );
+        let md = Metadata::from_bencode(raw.as_bytes()).unwrap();
+
+        let s = slices(&md, 0, 16384, 16384).unwrap();
+        assert_eq!(s.len(), 1);
+        assert_eq!(
+            (s[0].file, s[0].offset, s[0].range.clone()),
+            (0, 16384, 0..3616)
+        );
+
+        let s = slices(&md, 1, 0, 100).unwrap();
+        assert_eq!((s[0].file, s[0].offset, s[0].range.clone()), (2, 0, 0..100));
+
+        assert!(slices(&md, 1, 0, 101).is_err());
+
+        assert_eq!(file_pieces(&md, 0), 0..1);
+        assert_eq!(file_pieces(&md, 2), 1..2);
+    }
+
+    #[test]
+    fn recheck_reports_progress_and_bad_pieces() {
+        let (md, data) = two_file_torrent();
+        let storage = memory_storage::MemoryStorage::new(Arc::new(md));
+        storage.allocate().unwrap();
+        for (index, piece) in data.chunks(16384).enumerate() {
+            storage.write_block(index as u32, 0, piece).unwrap();
+        }
+        storage.write_block(2, 100, &[0xff]).unwrap();
+
+        let calls = AtomicU32::new(0);
+        let bitfield = recheck(&storage, |checked, total| {
+            assert!(checked <= total);
+            calls.fetch_add(1, Ordering::Relaxed);
+        })
+        .unwrap();
+
+        assert_eq!(calls.into_inner(), 4);
+        assert_eq!(bitfield.count_ones(), 3);
+        assert!(!bitfield[2]);
+    }
+}
@@ -0,0 +1,625 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    io::{self, Error as IOError, ErrorKind},
+    sync::{mpsc, Arc, Mutex},
+    thread,
+};
+
+use bitvec::{prelude::Msb0, vec::BitVec};
+use tokio::sync::{oneshot, watch};
+
+use crate::{parser::geometry::Geometry, utils::lru_cache::LruCache};
+
+use super::{
+    file_pieces,
+    resume::{self, PartialPiece, ResumeData},
+    slices, Storage,
+};
+
+// Blocking workers shared by all disk jobs of a torrent.
+const NUM_WORKERS: usize = 4;
+// Verified pieces held back before they are written out together.
+const WRITE_CACHE_SIZE: usize = 16 * 1024 * 1024;
+// Pieces kept around for answering requests from peers.
+const READ_CACHE_SIZE: usize = 32 * 1024 * 1024;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+// Disk access for one torrent. Verified pieces are cached and written in batches, in piece
+// order. Reads are served from the write cache or an LRU cache of whole pieces before touching
+// the disk. Blocks of unfinished pieces are kept in memory and only stored along with the resume
+// data. All disk work runs on a small pool of worker threads, so a slow disk never blocks the
+// async executor. Files whose pieces are all on disk are finished, moving them to their final
+// name. A failed write is published through `errors` instead of failing the peer that
+// delivered the piece, and cached pieces are kept until a `retry` gets them onto disk.
+pub(crate) struct DiskIo {
+    inner: Arc<Inner>,
+    tx_job: mpsc::Sender<Job>,
+}
+
+struct Inner {
+    storage: Arc<dyn Storage>,
+    geometry: Geometry,
+    cache: Mutex<Cache>,
+    // Held while a batch is written, so batches never overlap.
+    flushing: Mutex<()>,
+    // The last write error, until a retry succeeds.
+    tx_error: watch::Sender<Option<String>>,
+}
+
+struct Cache {
+    dirty: BTreeMap<u32, Arc<Vec<u8>>>,
+    dirty_bytes: usize,
+    clean: LruCache<u32, Arc<Vec<u8>>>,
+    partial: HashMap<u32, Partial>,
+    // Pieces known to be on disk.
+    bitfield: BitVec<u8, Msb0>,
+}
+
+// Blocks received for a piece that isn't complete yet.
+struct Partial {
+    // Every block received, whether in storage or only in `data`.
+    blocks: BitVec<u8, Msb0>,
+    // Blocks that are only in `data`.
+    unsaved: BitVec<u8, Msb0>,
+    data: Vec<u8>,
+}
+
+impl Partial {
+    fn new(geometry: &Geometry, index: u32) -> Self {
+        let num_blocks: usize = geometry.num_blocks(index).try_into().unwrap();
+        Partial {
+            blocks: BitVec::repeat(false, num_blocks),
+            unsaved: BitVec::repeat(false, num_blocks),
+            data: vec![0; geometry.piece_len(index).try_into().unwrap()],
+        }
+    }
+
+    fn stored(&self) -> BitVec<u8, Msb0> {
+        self.blocks.clone() & !self.unsaved.clone()
+    }
+}
+
+impl DiskIo {
+    pub(crate) fn new(storage: Arc<dyn Storage>, resume: &ResumeData) -> Self {
+        let geometry = storage.metadata().geometry();
+        let piece_length: usize = geometry.piece_length().try_into().unwrap();
+        let partial = resume
+            .partial
+            .iter()
+            .map(|p| {
+                let mut partial = Partial::new(&geometry, p.index);
+                partial.blocks = p.blocks.clone();
+                (p.index, partial)
+            })
+            .collect();
+
+        let inner = Arc::new(Inner {
+            storage,
+            geometry,
+            cache: Mutex::new(Cache {
+                dirty: BTreeMap::new(),
+                dirty_bytes: 0,
+                clean: LruCache::new(READ_CACHE_SIZE / piece_length.max(1)),
+                partial,
+                bitfield: resume.bitfield.clone(),
+            }),
+            flushing: Mutex::new(()),
+            tx_error: watch::channel(None).0,
+        });
+
+        let (tx_job, rx_job) = mpsc::channel::<Job>();
+        let rx_job = Arc::new(Mutex::new(rx_job));
+        for _ in 0..NUM_WORKERS {
+            let rx_job = rx_job.clone();
+            // Workers exit once the DiskIo and every queued job are gone.
+            thread::spawn(move || loop {
+                let job = rx_job.lock().unwrap().recv();
+                match job {
+                    Ok(job) => job(),
+                    Err(_) => return,
+                }
+            });
+        }
+
+        let disk_io = DiskIo { inner, tx_job };
+        // Files may have been completed without being finished in an earlier session.
+        disk_io.finish_all();
+        disk_io
+    }
+
+    // Finishes every complete file in the background.
+    fn finish_all(&self) {
+        let inner = self.inner.clone();
+        let _ = self.tx_job.send(Box::new(move || {
+            let num_files = inner.storage.metadata().info.files.len();
+            let _ = inner.reported(inner.finish_files(0..num_files));
+        }));
+    }
+
+    // Pieces known to be on disk.
+    pub(crate) fn bitfield(&self) -> BitVec<u8, Msb0> {
+        self.inner.cache.lock().unwrap().bitfield.clone()
+    }
+
+    // Whether the piece is verified and on disk, and so can be uploaded.
+    pub(crate) fn has_piece(&self, index: u32) -> bool {
+        let cache = self.inner.cache.lock().unwrap();
+        usize::try_from(index).is_ok_and(|i| cache.bitfield.get(i).is_some_and(|b| *b))
+    }
+
+    pub(crate) fn storage(&self) -> &Arc<dyn Storage> {
+        &self.inner.storage
+    }
+
+    // Set while writing to disk fails, e.g. because it is full.
+    pub(crate) fn errors(&self) -> watch::Receiver<Option<String>> {
+        self.inner.tx_error.subscribe()
+    }
+
+    // Records an error from outside the workers, such as a failed allocation.
+    pub(crate) fn report_error(&self, e: &io::Error) {
+        self.inner.tx_error.send_replace(Some(e.to_string()));
+    }
+
+    // Allocates again and writes out everything cached, clearing the error if that succeeds.
+    pub(crate) async fn retry(&self) -> io::Result<()> {
+        self.run(|inner| {
+            inner.reported(inner.storage.allocate())?;
+            inner.flush()?;
+            inner.tx_error.send_replace(None);
+            Ok(())
+        })
+        .await
+    }
+
+    // Runs `f` on a worker and waits for its result.
+    async fn run<R, F>(&self, f: F) -> io::Result<R>
+    where
+        F: FnOnce(&Inner) -> io::Result<R> + Send + 'static,
+        R: Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        let inner = self.inner.clone();
+        self.tx_job
+            .send(Box::new(move || {
+                let _ = tx.send(f(&inner));
+            }))
+            .map_err(|_| IOError::new(ErrorKind::BrokenPipe, "Disk workers stopped"))?;
+
+        rx.await
+            .map_err(|_| IOError::new(ErrorKind::BrokenPipe, "Disk job dropped"))?
+    }
+
+    // Keeps a received block of an unverified piece, so it survives a restart.
+    pub(crate) fn write_block(&self, index: u32, block_index: u32, data: &[u8]) {
+        let geometry = &self.inner.geometry;
+        let mut cache = self.inner.cache.lock().unwrap();
+        let partial = cache
+            .partial
+            .entry(index)
+            .or_insert_with(|| Partial::new(geometry, index));
+
+        let b: usize = block_index.try_into().unwrap();
+        if b >= partial.blocks.len() {
+            return;
+        }
+        let begin: usize = geometry.block_offset(block_index).try_into().unwrap();
+        let len = data
+            .len()
+            .min(geometry.block_len(index, block_index).try_into().unwrap());
+        partial.data[begin..begin + len].copy_from_slice(&data[..len]);
+        partial.blocks.set(b, true);
+        partial.unsaved.set(b, true);
+    }
+
+    // Blocks of an unfinished piece received earlier, as a piece-sized buffer and the blocks
+    // that are filled in.
+    pub(crate) async fn partial_piece(
+        &self,
+        index: u32,
+    ) -> io::Result<Option<(Vec<u8>, BitVec<u8, Msb0>)>> {
+        let (mut data, blocks, stored) = {
+            let cache = self.inner.cache.lock().unwrap();
+            match cache.partial.get(&index) {
+                Some(p) => (p.data.clone(), p.blocks.clone(), p.stored()),
+                None => return Ok(None),
+            }
+        };
+
+        // Blocks saved in an earlier session are read back from storage.
+        if stored.any() {
+            let piece_len = self.inner.geometry.piece_len(index);
+            let on_disk = self
+                .run(move |inner| inner.storage.read_block(index, 0, piece_len))
+                .await?;
+            for b in stored.iter_ones() {
+                let range = self.inner.block_range(index, b.try_into().unwrap());
+                data[range.clone()].copy_from_slice(&on_disk[range]);
+            }
+        }
+
+        Ok(Some((data, blocks)))
+    }
+
+    // Forgets the blocks of a piece that failed verification.
+    pub(crate) fn discard_partial(&self, index: u32) {
+        self.inner.cache.lock().unwrap().partial.remove(&index);
+    }
+
+    // Queues a verified piece for writing. Only waits for the disk once the write cache is full.
+    pub(crate) async fn write_piece(&self, index: u32, data: Vec<u8>) {
+        let full = {
+            let mut cache = self.inner.cache.lock().unwrap();
+            cache.partial.remove(&index);
+            cache.clean.remove(&index);
+            cache.dirty_bytes += data.len();
+            if let Some(old) = cache.dirty.insert(index, Arc::new(data)) {
+                cache.dirty_bytes -= old.len();
+            }
+            cache.dirty_bytes >= WRITE_CACHE_SIZE
+        };
+
+        // A failed flush is reported through `errors`, and the pieces stay cached.
+        if full {
+            let _ = self.flush().await;
+        }
+    }
+
+    pub(crate) async fn read_block(&self, index: u32, begin: u32, len: u32) -> io::Result<Vec<u8>> {
+        let piece_len = self.inner.geometry.piece_len(index);
+        if u64::from(begin) + u64::from(len) > u64::from(piece_len) {
+            return Err(IOError::new(ErrorKind::InvalidInput, "Block out of range"));
+        }
+
+        let piece = match self.inner.cached_piece(index) {
+            Some(v) => v,
+            None => {
+                self.run(move |inner| {
+                    let piece = Arc::new(inner.storage.read_block(index, 0, piece_len)?);
+                    inner
+                        .cache
+                        .lock()
+                        .unwrap()
+                        .clean
+                        .insert(index, piece.clone());
+                    Ok(piece)
+                })
+                .await?
+            }
+        };
+
+        let begin: usize = begin.try_into().unwrap();
+        let len: usize = len.try_into().unwrap();
+        Ok(piece[begin..begin + len].to_vec())
+    }
+
+    // Writes out every cached piece.
+    pub(crate) async fn flush(&self) -> io::Result<()> {
+        self.run(|inner| inner.flush()).await
+    }
+
+    // For callers already on a blocking thread.
+    pub(crate) fn flush_blocking(&self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    // Replaces the pieces known to be on disk, as after a recheck.
+    pub(crate) fn replace_bitfield(&self, bitfield: BitVec<u8, Msb0>) {
+        self.inner.cache.lock().unwrap().bitfield = bitfield;
+        self.finish_all();
+    }
+
+    // Moves the torrent's data under another download directory, after writing out everything
+    // cached. Peers can carry on reading and writing meanwhile.
+    pub(crate) async fn move_to(&self, dir: &str) -> io::Result<()> {
+        let dir = dir.to_owned();
+        self.run(move |inner| {
+            let _flushing = inner.flushing.lock().unwrap();
+            inner.reported(inner.write_dirty())?;
+            inner.reported(inner.storage.move_to(&dir))
+        })
+        .await
+    }
+
+    // Writes out everything cached, then records the state of the torrent in `state_dir`.
+    pub(crate) async fn save_resume(
+        &self,
+        state_dir: &str,
+        uploaded: u64,
+        downloaded: u64,
+    ) -> io::Result<()> {
+        let state_dir = state_dir.to_owned();
+        self.run(move |inner| inner.save_resume(&state_dir, uploaded, downloaded))
+            .await
+    }
+}
+
+impl Inner {
+    fn cached_piece(&self, index: u32) -> Option<Arc<Vec<u8>>> {
+        let mut cache = self.cache.lock().unwrap();
+        match cache.dirty.get(&index) {
+            Some(v) => Some(v.clone()),
+            None => cache.clean.get(&index).cloned(),
+        }
+    }
+
+    fn block_range(&self, index: u32, block_index: u32) -> std::ops::Range<usize> {
+        let begin: usize = self.geometry.block_offset(block_index).try_into().unwrap();
+        let len: usize = self
+            .geometry
+            .block_len(index, block_index)
+            .try_into()
+            .unwrap();
+        begin..begin + len
+    }
+
+    // Passes `res` on, publishing it first if it is an error.
+    fn reported<R>(&self, res: io::Result<R>) -> io::Result<R> {
+        if let Err(e) = &res {
+            self.tx_error.send_replace(Some(e.to_string()));
+        }
+        res
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        let _flushing = self.flushing.lock().unwrap();
+        self.reported(self.write_dirty())
+    }
+
+    fn write_dirty(&self) -> io::Result<()> {
+        let batch: Vec<(u32, Arc<Vec<u8>>)> = {
+            let cache = self.cache.lock().unwrap();
+            cache.dirty.iter().map(|(&i, v)| (i, v.clone())).collect()
+        };
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        // Pieces stay readable from the cache until they are on disk.
+        for (index, data) in &batch {
+            self.storage.write_block(*index, 0, data)?;
+        }
+        self.storage.flush()?;
+
+        let mut files = BTreeSet::new();
+        for (index, data) in &batch {
+            for slice in slices(self.storage.metadata(), *index, 0, data.len())? {
+                files.insert(slice.file);
+            }
+        }
+
+        let mut cache = self.cache.lock().unwrap();
+        for (index, data) in batch {
+            cache.bitfield.set(index.try_into().unwrap(), true);
+            // A piece rewritten since the batch was taken stays dirty.
+            if cache
+                .dirty
+                .get(&index)
+                .is_some_and(|v| Arc::ptr_eq(v, &data))
+            {
+                cache.dirty.remove(&index);
+                cache.dirty_bytes -= data.len();
+                cache.clean.insert(index, data);
+            }
+        }
+        drop(cache);
+
+        self.finish_files(files)
+    }
+
+    // Finishes those of `files` whose pieces are all on disk.
+    fn finish_files(&self, files: impl IntoIterator<Item = usize>) -> io::Result<()> {
+        let md = self.storage.metadata();
+        let bitfield = self.cache.lock().unwrap().bitfield.clone();
+
+        for file in files {
+            let pieces = file_pieces(md, file);
+            let pieces = pieces.start.try_into().unwrap()..pieces.end.try_into().unwrap();
+            if !md.info.files[file].is_padding() && bitfield[pieces].all() {
+                self.storage.finish_file(file)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn save_resume(&self, state_dir: &str, uploaded: u64, downloaded: u64) -> io::Result<()> {
+        self.flush()?;
+        let _flushing = self.flushing.lock().unwrap();
+
+        // Unsaved blocks of unfinished pieces go to storage first, so the resume data never
+        // claims blocks that aren't there.
+        let blocks: Vec<(u32, u32, Vec<u8>)> = {
+            let cache = self.cache.lock().unwrap();
+            let mut blocks = Vec::new();
+            for (&index, p) in &cache.partial {
+                for b in p.unsaved.iter_ones() {
+                    let b: u32 = b.try_into().unwrap();
+                    blocks.push((index, b, p.data[self.block_range(index, b)].to_vec()));
+                }
+            }
+            blocks
+        };
+        self.reported(blocks.iter().try_for_each(|(index, b, data)| {
+            self.storage
+                .write_block(*index, self.geometry.block_offset(*b), data)
+        }))?;
+        self.reported(self.storage.flush())?;
+
+        let files = self.storage.file_states()?;
+        let md = self.storage.metadata();
+        let data = {
+            let mut cache = self.cache.lock().unwrap();
+            for (index, b, _) in &blocks {
+                if let Some(p) = cache.partial.get_mut(index) {
+                    p.unsaved.set((*b).try_into().unwrap(), false);
+                }
+            }
+
+            ResumeData {
+                info_hash: md.info_hash.clone(),
+                bitfield: cache.bitfield.clone(),
+                partial: cache
+                    .partial
+                    .iter()
+                    .map(|(&index, p)| PartialPiece {
+                        index,
+                        blocks: p.stored(),
+                    })
+                    .filter(|p| p.blocks.any())
+                    .collect(),
+                files,
+                uploaded,
+                downloaded,
+            }
+        };
+
+        resume::save(state_dir, md, &data)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, path::Path};
+
+    use super::*;
+    use crate::storage::{
+        fs_storage::FsStorage,
+        test::{temp_dir, two_file_torrent, two_file_torrent_with_pieces},
+        StorageOptions,
+    };
+
+    #[tokio::test]
+    async fn pieces_are_cached_until_flushed() {
+        let (md, data) = two_file_torrent();
+        let dir = temp_dir("diskio");
+        let md = Arc::new(md);
+        let storage: Arc<dyn Storage> =
+            Arc::new(FsStorage::new(md.clone(), &dir, &StorageOptions::default()));
+        storage.allocate().unwrap();
+        let disk_io = DiskIo::new(storage, &ResumeData::new(&md));
+
+        disk_io.write_piece(0, data[..16384].to_vec()).await;
+        disk_io.write_piece(1, data[16384..32768].to_vec()).await;
+
+        // Nothing has reached the disk, but reads already see the pieces.
+        assert_eq!(fs::read(format!("{dir}/dir/a")).unwrap(), vec![0; 20000]);
+        assert!(disk_io.bitfield().not_any());
+        assert!(!disk_io.has_piece(0));
+        assert_eq!(
+            disk_io.read_block(1, 3000, 1000).await.unwrap(),
+            &data[19384..20384]
+        );
+
+        disk_io.flush().await.unwrap();
+        assert_eq!(fs::read(format!("{dir}/dir/a")).unwrap(), &data[..20000]);
+        assert_eq!(
+            disk_io.bitfield().iter_ones().collect::<Vec<_>>(),
+            vec![0, 1]
+        );
+        assert!(disk_io.has_piece(1) && !disk_io.has_piece(2) && !disk_io.has_piece(100));
+
+        // Served from the read cache, then from disk for a piece never cached.
+        assert_eq!(
+            disk_io.read_block(0, 0, 16384).await.unwrap(),
+            &data[..16384]
+        );
+        assert_eq!(disk_io.read_block(3, 0, 100).await.unwrap(), vec![0; 100]);
+        assert!(disk_io.read_block(3, 0, 20000).await.is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn failed_writes_are_reported_until_a_retry_succeeds() {
+        let (md, data) = two_file_torrent();
+        let dir = temp_dir("diskio-retry");
+        let md = Arc::new(md);
+        let storage: Arc<dyn Storage> =
+            Arc::new(FsStorage::new(md.clone(), &dir, &StorageOptions::default()));
+        storage.allocate().unwrap();
+        let disk_io = DiskIo::new(storage, &ResumeData::new(&md));
+        let rx_error = disk_io.errors();
+
+        // The files vanish from under the torrent, so the next flush can't write.
+        fs::remove_dir_all(&dir).unwrap();
+        disk_io.write_piece(0, data[..16384].to_vec()).await;
+        assert!(disk_io.flush().await.is_err());
+        assert!(rx_error.borrow().is_some());
+        assert!(disk_io.bitfield().not_any());
+
+        // The piece was kept, and a retry puts it on disk.
+        disk_io.retry().await.unwrap();
+        assert!(rx_error.borrow().is_none());
+        assert_eq!(disk_io.bitfield().iter_ones().collect::<Vec<_>>(), vec![0]);
+        assert_eq!(
+            fs::read(format!("{dir}/dir/a")).unwrap()[..16384],
+            data[..16384]
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn complete_files_are_finished_and_can_move() {
+        let (md, data) = two_file_torrent();
+        let root = temp_dir("diskio-finish");
+        let dir = format!("{root}/downloads");
+        let md = Arc::new(md);
+        let options = StorageOptions {
+            part_files: true,
+            ..Default::default()
+        };
+        let storage: Arc<dyn Storage> = Arc::new(FsStorage::new(md.clone(), &dir, &options));
+        storage.allocate().unwrap();
+        let disk_io = DiskIo::new(storage, &ResumeData::new(&md));
+
+        disk_io.write_piece(0, data[..16384].to_vec()).await;
+        disk_io.write_piece(1, data[16384..32768].to_vec()).await;
+        disk_io.flush().await.unwrap();
+        assert_eq!(fs::read(format!("{dir}/dir/a")).unwrap(), &data[..20000]);
+        assert!(Path::new(&format!("{dir}/dir/b.part")).exists());
+
+        // Moving mid-download takes cached pieces and unfinished files along.
+        let moved = format!("{root}/completed");
+        disk_io.write_piece(2, data[32768..49152].to_vec()).await;
+        disk_io.move_to(&moved).await.unwrap();
+        assert!(!Path::new(&format!("{dir}/dir")).exists());
+        disk_io.write_piece(3, data[49152..].to_vec()).await;
+        disk_io.flush().await.unwrap();
+        assert_eq!(fs::read(format!("{moved}/dir/b")).unwrap(), &data[20000..]);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn partial_pieces_survive_a_restart() {
+        let (md, data) = two_file_torrent_with_pieces(32768);
+        let dir = temp_dir("partial");
+        let state_dir = format!("{dir}/state");
+        let md = Arc::new(md);
+        let storage: Arc<dyn Storage> =
+            Arc::new(FsStorage::new(md.clone(), &dir, &StorageOptions::default()));
+        storage.allocate().unwrap();
+
+        let disk_io = DiskIo::new(storage.clone(), &ResumeData::new(&md));
+        disk_io.write_piece(1, data[32768..].to_vec()).await;
+        disk_io.write_block(0, 0, &data[..16384]);
+        disk_io.save_resume(&state_dir, 10, 20).await.unwrap();
+        drop(disk_io);
+
+        let resume = resume::load(&state_dir, &md).unwrap().unwrap();
+        assert_eq!(resume.bitfield.iter_ones().collect::<Vec<_>>(), vec![1]);
+        assert_eq!(resume.files, storage.file_states().unwrap());
+        assert_eq!((resume.uploaded, resume.downloaded), (10, 20));
+
+        let disk_io = DiskIo::new(storage, &resume);
+        let (piece, blocks) = disk_io.partial_piece(0).await.unwrap().unwrap();
+        assert_eq!(blocks.iter_ones().collect::<Vec<_>>(), vec![0]);
+        assert_eq!(&piece[..16384], &data[..16384]);
+        assert_eq!(&piece[16384..], vec![0; 16384]);
+        assert!(disk_io.partial_piece(1).await.unwrap().is_none());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
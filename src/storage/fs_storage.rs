@@ -0,0 +1,765 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::Path,
+    sync::{Arc, Mutex, RwLock},
+    time::UNIX_EPOCH,
+};
+
+use crate::parser::{file_info::FilePathInfo, metadata::Metadata};
+
+use super::{
+    file_path, resume::FileState, root_path, slices, AllocationMode, DiskState, Storage,
+    StorageOptions,
+};
+
+// Open handles are kept up to this many files, so large torrents don't run out of descriptors.
+const MAX_OPEN_FILES: usize = 64;
+
+// Plain files on disk, read and written through cached handles. Unfinished files may live under
+// another name or directory, and are moved into place by `finish_file`.
+pub(crate) struct FsStorage {
+    md: Arc<Metadata>,
+    dir: RwLock<String>,
+    // Where unfinished files are kept, if not in `dir`.
+    incomplete_dir: Option<String>,
+    part_files: bool,
+    // Also held while files are renamed or moved, so nothing is opened at a stale path.
+    handles: Mutex<HashMap<usize, File>>,
+    // Files are created by their first write rather than by `allocate`.
+    lazy: bool,
+    // Disk space is reserved whenever a file grows, instead of leaving it sparse.
+    preallocate: bool,
+}
+
+impl FsStorage {
+    pub(crate) fn new(md: Arc<Metadata>, dir: &str, options: &StorageOptions) -> Self {
+        FsStorage {
+            md,
+            dir: RwLock::new(dir.to_owned()),
+            incomplete_dir: options.incomplete_dir.clone(),
+            part_files: options.part_files,
+            handles: Mutex::new(HashMap::new()),
+            lazy: options.allocation == AllocationMode::Lazy,
+            preallocate: options.allocation == AllocationMode::Full,
+        }
+    }
+
+    // Reserves disk space even where the mode would leave files sparse, for backends that can't
+    // recover from the disk filling up.
+    pub(crate) fn always_preallocate(mut self) -> Self {
+        self.preallocate = true;
+        self
+    }
+
+    // Where a file is once finished.
+    fn final_path(&self, file: usize) -> String {
+        file_path(
+            &self.md,
+            &self.dir.read().unwrap(),
+            &self.md.info.files[file],
+        )
+    }
+
+    // Where a file is while it is being downloaded.
+    fn part_path(&self, file: usize) -> String {
+        self.part_path_in(&self.dir.read().unwrap(), file)
+    }
+
+    // Where a file is while it is being downloaded, were the torrent in `dir`.
+    fn part_path_in(&self, dir: &str, file: usize) -> String {
+        let dir = self.incomplete_dir.as_deref().unwrap_or(dir);
+        let path = file_path(&self.md, dir, &self.md.info.files[file]);
+        if self.part_files {
+            format!("{}.part", path)
+        } else {
+            path
+        }
+    }
+
+    // Where a file currently is: at its final path once that exists, and otherwise at its part
+    // path. Data from before part files were used is found at the final path too.
+    pub(crate) fn path(&self, file: usize) -> String {
+        let path = self.final_path(file);
+        if Path::new(&path).exists() {
+            path
+        } else {
+            self.part_path(file)
+        }
+    }
+
+    // Runs `f` on the cached handle for the file, opening it first if needed. A missing file is
+    // created with `create`, and otherwise None is returned.
+    fn with_file<R>(
+        &self,
+        file: usize,
+        create: bool,
+        f: impl FnOnce(&mut File) -> io::Result<R>,
+    ) -> io::Result<Option<R>> {
+        let mut handles = self.handles.lock().unwrap();
+
+        if !handles.contains_key(&file) {
+            // Creating while holding `handles` keeps two writers from both creating the file.
+            let handle = match File::options().read(true).write(true).open(self.path(file)) {
+                Ok(v) => v,
+                Err(e) if e.kind() == io::ErrorKind::NotFound && create => self.create(file)?,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+                Err(e) => return Err(e),
+            };
+            if handles.len() >= MAX_OPEN_FILES {
+                let evict = *handles.keys().next().unwrap();
+                handles.remove(&evict);
+            }
+            handles.insert(file, handle);
+        }
+
+        f(handles.get_mut(&file).unwrap()).map(Some)
+    }
+
+    // Creates a file at its full size, along with any missing directories. Empty files are
+    // finished from the start.
+    fn create(&self, file: usize) -> io::Result<File> {
+        let info = &self.md.info.files[file];
+        let path_str = if info.length == 0 {
+            self.final_path(file)
+        } else {
+            self.part_path(file)
+        };
+        let path = Path::new(&path_str);
+        fs::create_dir_all(path.parent().unwrap())?;
+
+        let f = create_file(path, info)?;
+        self.resize(&f, 0, info.length)?;
+        set_executable(&f, info)?;
+        Ok(f)
+    }
+
+    fn resize(&self, f: &File, from: u64, to: u64) -> io::Result<()> {
+        f.set_len(to)?;
+        if self.preallocate && to > from {
+            preallocate(f, from, to - from)?;
+        }
+        Ok(())
+    }
+
+    // Fails when the data still missing from disk is more than the free space left for it, in
+    // the directory unfinished files are allocated in.
+    fn check_free_space(&self) -> io::Result<()> {
+        let dir = match &self.incomplete_dir {
+            Some(dir) => dir.clone(),
+            None => self.dir.read().unwrap().clone(),
+        };
+        let free = match free_space(Path::new(&dir))? {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+
+        let mut needed: u64 = 0;
+        for (i, file) in self.md.info.files.iter().enumerate() {
+            if file.is_padding() {
+                continue;
+            }
+            let used = match fs::metadata(self.path(i)) {
+                Ok(meta) => allocated_len(&meta),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+                Err(e) => return Err(e),
+            };
+            needed = needed.saturating_add(file.length.saturating_sub(used));
+        }
+
+        if needed > free {
+            return Err(io::Error::new(
+                io::ErrorKind::StorageFull,
+                format!(
+                    "Not enough disk space in {}: {} bytes needed, {} free",
+                    dir, needed, free
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Storage for FsStorage {
+    fn metadata(&self) -> &Metadata {
+        &self.md
+    }
+
+    fn allocate(&self) -> io::Result<DiskState> {
+        // Sizes may change below, so start from fresh handles.
+        self.handles.lock().unwrap().clear();
+        self.check_free_space()?;
+
+        let mut found_data = false;
+        let mut mismatch = false;
+
+        // Padding files are never stored, as their contents are known to be zero.
+        for (i, file) in self.md.info.files.iter().enumerate() {
+            if file.is_padding() {
+                continue;
+            }
+            let path_str = &self.path(i);
+            let path = Path::new(path_str);
+
+            match fs::metadata(path) {
+                Ok(meta) => {
+                    found_data = true;
+                    let f = File::options().write(true).open(path)?;
+                    if meta.len() != file.length {
+                        mismatch = true;
+                        self.resize(&f, meta.len(), file.length)?;
+                    }
+                    set_executable(&f, file)?;
+                }
+                // Files not written yet are expected to be missing.
+                Err(e) if e.kind() == io::ErrorKind::NotFound && self.lazy => {}
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    mismatch = true;
+                    self.create(i)?;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(match (found_data, mismatch) {
+            (false, _) => DiskState::New,
+            (true, false) => DiskState::Existing,
+            (true, true) => DiskState::NeedsRecheck,
+        })
+    }
+
+    fn read_block(&self, index: u32, begin: u32, len: u32) -> io::Result<Vec<u8>> {
+        let mut data = vec![0; len.try_into().unwrap()];
+
+        for slice in slices(&self.md, index, begin, data.len())? {
+            let buf = &mut data[slice.range];
+            self.with_file(slice.file, false, |f| {
+                f.seek(SeekFrom::Start(slice.offset))?;
+                // A short file leaves the rest of the buffer zeroed.
+                let mut buf = buf;
+                while !buf.is_empty() {
+                    match f.read(buf)? {
+                        0 => break,
+                        n => buf = &mut buf[n..],
+                    }
+                }
+                Ok(())
+            })?;
+        }
+
+        Ok(data)
+    }
+
+    fn write_block(&self, index: u32, begin: u32, data: &[u8]) -> io::Result<()> {
+        for slice in slices(&self.md, index, begin, data.len())? {
+            let written = self.with_file(slice.file, self.lazy, |f| {
+                f.seek(SeekFrom::Start(slice.offset))?;
+                f.write_all(&data[slice.range])
+            })?;
+            if written.is_none() {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("Missing file {}", self.path(slice.file)),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        for f in self.handles.lock().unwrap().values() {
+            f.sync_data()?;
+        }
+        Ok(())
+    }
+
+    fn file_states(&self) -> io::Result<Vec<FileState>> {
+        let mut states = Vec::with_capacity(self.md.info.files.len());
+
+        for (i, file) in self.md.info.files.iter().enumerate() {
+            let meta = match fs::metadata(self.path(i)) {
+                Ok(v) if !file.is_padding() => v,
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {
+                    states.push(FileState::default());
+                    continue;
+                }
+            };
+            let mtime = meta
+                .modified()?
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            states.push(FileState {
+                length: meta.len(),
+                mtime: mtime.as_nanos().try_into().unwrap_or(u64::MAX),
+            });
+        }
+
+        Ok(states)
+    }
+
+    fn finish_file(&self, file: usize) -> io::Result<()> {
+        let mut handles = self.handles.lock().unwrap();
+        let from = self.part_path(file);
+        let to = self.final_path(file);
+        if from == to || !Path::new(&from).exists() {
+            return Ok(());
+        }
+
+        if let Some(f) = handles.remove(&file) {
+            f.sync_data()?;
+        }
+        fs::create_dir_all(Path::new(&to).parent().unwrap())?;
+        move_path(Path::new(&from), Path::new(&to))?;
+
+        // Directories left empty in the incomplete directory are cleared out.
+        if let Some(incomplete_dir) = &self.incomplete_dir {
+            for dir in Path::new(&from).ancestors().skip(1) {
+                if dir == Path::new(incomplete_dir) || fs::remove_dir(dir).is_err() {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Unfinished files in a separate incomplete directory stay where they are.
+    fn move_to(&self, dir: &str) -> io::Result<()> {
+        let mut handles = self.handles.lock().unwrap();
+        for f in handles.values() {
+            f.sync_data()?;
+        }
+        handles.clear();
+
+        let mut cur_dir = self.dir.write().unwrap();
+        let from = root_path(&self.md, &cur_dir);
+        let to = root_path(&self.md, dir);
+
+        if from != to && Path::new(&from).exists() {
+            fs::create_dir_all(dir)?;
+            move_path(Path::new(&from), Path::new(&to))?;
+        }
+
+        // Unfinished files outside the root move too, such as `<name>.part` of a single-file
+        // torrent. Those inside it have already moved with it.
+        for i in 0..self.md.info.files.len() {
+            let from = self.part_path_in(&cur_dir, i);
+            let to = self.part_path_in(dir, i);
+            if from != to && Path::new(&from).exists() {
+                fs::create_dir_all(dir)?;
+                move_path(Path::new(&from), Path::new(&to))?;
+            }
+        }
+
+        *cur_dir = dir.to_owned();
+        Ok(())
+    }
+}
+
+// Renames fail across filesystems, in which case the data is copied instead.
+fn move_path(from: &Path, to: &Path) -> io::Result<()> {
+    if fs::rename(from, to).is_err() {
+        copy_all(from, to)?;
+        remove_all(from)?;
+    }
+    Ok(())
+}
+
+fn copy_all(from: &Path, to: &Path) -> io::Result<()> {
+    if from.is_dir() {
+        fs::create_dir_all(to)?;
+        for entry in fs::read_dir(from)? {
+            let entry = entry?;
+            copy_all(&entry.path(), &to.join(entry.file_name()))?;
+        }
+    } else {
+        fs::copy(from, to)?;
+    }
+    Ok(())
+}
+
+fn remove_all(path: &Path) -> io::Result<()> {
+    if path.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }
+}
+
+#[cfg(windows)]
+fn create_file(path: &Path, file: &FilePathInfo) -> io::Result<File> {
+    use std::os::windows::fs::OpenOptionsExt;
+
+    const FILE_ATTRIBUTE_HIDDEN: u32 = 0x2;
+
+    let mut options = File::options();
+    options.read(true).write(true).create(true).truncate(true);
+    if file.is_hidden() {
+        options.attributes(FILE_ATTRIBUTE_HIDDEN);
+    }
+    options.open(path)
+}
+
+// Hidden files have no attribute of their own elsewhere; they are hidden by a leading dot, which
+// the name already carries if the creator wanted it.
+#[cfg(not(windows))]
+fn create_file(path: &Path, _file: &FilePathInfo) -> io::Result<File> {
+    File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)
+}
+
+#[cfg(target_os = "linux")]
+fn preallocate(f: &File, offset: u64, len: u64) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let offset: libc::off_t = offset.try_into().map_err(io::Error::other)?;
+    let len: libc::off_t = len.try_into().map_err(io::Error::other)?;
+    // Safety: the descriptor stays open for as long as `f` is borrowed.
+    let res = unsafe { libc::posix_fallocate(f.as_raw_fd(), offset, len) };
+    match res {
+        0 => Ok(()),
+        errno => Err(io::Error::from_raw_os_error(errno)),
+    }
+}
+
+// Without fallocate, the space is claimed by writing zeros over it.
+#[cfg(not(target_os = "linux"))]
+fn preallocate(mut f: &File, offset: u64, len: u64) -> io::Result<()> {
+    let zeros = vec![0; 1 << 20];
+    f.seek(SeekFrom::Start(offset))?;
+    let mut left = len;
+    while left > 0 {
+        let n = left.min(zeros.len() as u64) as usize;
+        f.write_all(&zeros[..n])?;
+        left -= n as u64;
+    }
+    Ok(())
+}
+
+// Bytes available to unprivileged users on the filesystem holding `dir`. As `dir` may not exist
+// yet, its closest existing ancestor is asked instead.
+#[cfg(unix)]
+#[allow(clippy::unnecessary_cast)]
+fn free_space(dir: &Path) -> io::Result<Option<u64>> {
+    use std::{ffi::CString, mem::MaybeUninit, os::unix::ffi::OsStrExt};
+
+    let existing = dir
+        .ancestors()
+        .find(|p| p.exists())
+        .unwrap_or(Path::new("."));
+    let path = CString::new(existing.as_os_str().as_bytes()).map_err(io::Error::other)?;
+    let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+
+    // Safety: `path` is NUL-terminated and `stat` is only read once statvfs has filled it in.
+    let stat = unsafe {
+        if libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        stat.assume_init()
+    };
+    // The field types vary between platforms.
+    Ok(Some(
+        (stat.f_bavail as u64).saturating_mul(stat.f_frsize as u64),
+    ))
+}
+
+#[cfg(not(unix))]
+fn free_space(_dir: &Path) -> io::Result<Option<u64>> {
+    Ok(None)
+}
+
+// Bytes a file takes up on disk, which is less than its length while it is sparse.
+#[cfg(unix)]
+fn allocated_len(meta: &fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+
+    // Blocks are always counted in 512-byte units.
+    meta.blocks().saturating_mul(512).min(meta.len())
+}
+
+#[cfg(not(unix))]
+fn allocated_len(meta: &fs::Metadata) -> u64 {
+    meta.len()
+}
+
+#[cfg(unix)]
+fn set_executable(f: &File, file: &FilePathInfo) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    if file.is_executable() {
+        let mut perms = f.metadata()?.permissions();
+        // Grant execute wherever read is already granted.
+        perms.set_mode(perms.mode() | (perms.mode() & 0o444) >> 2);
+        f.set_permissions(perms)?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_executable(_f: &File, _file: &FilePathInfo) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use bendy::decoding::FromBencode;
+
+    use super::*;
+    use crate::storage::test::{single_file_torrent, temp_dir, two_file_torrent};
+
+    fn options(allocation: AllocationMode) -> StorageOptions {
+        StorageOptions {
+            allocation,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn padding_is_not_stored_and_attributes_are_applied() {
+        let raw = format!(
+            "d4:infod5:filesld4:attr1:x6:lengthi20000e4:pathl3:runeed4:attr1:p6:lengthi12768e\
+             4:pathl4:.pad5:12768eed6:lengthi100e4:pathl5:b.bineee4:name3:dir\
+             12:piece lengthi32768e6:pieces40:{}ee",
+            "a".repeat(40)
+        );
+        let dir = temp_dir("pad");
+        let storage = FsStorage::new(
+            Arc::new(Metadata::from_bencode(raw.as_bytes()).unwrap()),
+            &dir,
+            &StorageOptions::default(),
+        );
+
+        storage.allocate().unwrap();
+        assert!(!Path::new(&format!("{dir}/dir/.pad/12768")).exists());
+        assert_eq!(fs::metadata(format!("{dir}/dir/run")).unwrap().len(), 20000);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(format!("{dir}/dir/run"))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_ne!(mode & 0o100, 0);
+        }
+
+        // A full first piece, whose padding must not be written anywhere.
+        storage.write_block(0, 0, &[1; 32768]).unwrap();
+        storage.flush().unwrap();
+        assert_eq!(fs::read(format!("{dir}/dir/run")).unwrap(), vec![1; 20000]);
+        assert!(!Path::new(&format!("{dir}/dir/.pad")).exists());
+        assert_eq!(
+            storage.read_block(0, 19990, 20).unwrap(),
+            [&[1; 10][..], &[0; 10]].concat()
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn hostile_paths_stay_inside_download_dir() {
+        let raw = format!(
+            "d4:infod5:filesld6:lengthi10e4:pathl2:..2:..6:escapeeed6:lengthi10e\
+             4:pathl11:/tmp/escapeeee4:name2:..12:piece lengthi16384e6:pieces20:{}ee",
+            "a".repeat(20)
+        );
+        let root = temp_dir("hostile");
+        let dir = format!("{root}/downloads");
+        let storage = FsStorage::new(
+            Arc::new(Metadata::from_bencode(raw.as_bytes()).unwrap()),
+            &dir,
+            &StorageOptions::default(),
+        );
+
+        storage.allocate().unwrap();
+        assert!(Path::new(&format!("{dir}/_/_/_/escape")).exists());
+        assert!(Path::new(&format!("{dir}/_/_tmp_escape")).exists());
+        assert!(!Path::new(&format!("{root}/escape")).exists());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn allocate_keeps_existing_data_and_reports_mismatch() {
+        let (md, data) = two_file_torrent();
+        let dir = temp_dir("startup");
+        let storage = FsStorage::new(Arc::new(md), &dir, &StorageOptions::default());
+
+        assert_eq!(storage.allocate().unwrap(), DiskState::New);
+        for (index, piece) in data.chunks(16384).enumerate() {
+            storage.write_block(index as u32, 0, piece).unwrap();
+        }
+        storage.flush().unwrap();
+
+        // A second start finds everything in place and leaves it alone.
+        assert_eq!(storage.allocate().unwrap(), DiskState::Existing);
+        assert_eq!(fs::read(format!("{dir}/dir/a")).unwrap(), &data[..20000]);
+
+        // A file of the wrong size is resized rather than wiped, and the data still verifies.
+        File::options()
+            .write(true)
+            .open(format!("{dir}/dir/b"))
+            .unwrap()
+            .set_len(40000)
+            .unwrap();
+        assert_eq!(storage.allocate().unwrap(), DiskState::NeedsRecheck);
+        assert_eq!(fs::metadata(format!("{dir}/dir/b")).unwrap().len(), 30000);
+        assert!((0..4).all(|i| storage.hash_piece(i).unwrap()));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn lazy_files_are_created_by_their_first_write() {
+        let (md, data) = two_file_torrent();
+        let dir = temp_dir("lazy");
+        let storage = FsStorage::new(Arc::new(md), &dir, &options(AllocationMode::Lazy));
+
+        assert_eq!(storage.allocate().unwrap(), DiskState::New);
+        assert!(!Path::new(&format!("{dir}/dir")).exists());
+
+        storage.write_block(0, 0, &data[..16384]).unwrap();
+        assert_eq!(fs::metadata(format!("{dir}/dir/a")).unwrap().len(), 20000);
+        assert!(!Path::new(&format!("{dir}/dir/b")).exists());
+        assert_eq!(storage.read_block(3, 0, 100).unwrap(), vec![0; 100]);
+
+        // Files still waiting for data don't call for a recheck.
+        assert_eq!(storage.allocate().unwrap(), DiskState::Existing);
+        assert!(storage.hash_piece(0).unwrap());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn full_allocation_reserves_disk_space() {
+        let (md, _) = two_file_torrent();
+        let dir = temp_dir("full");
+        let storage = FsStorage::new(Arc::new(md), &dir, &options(AllocationMode::Full));
+
+        storage.allocate().unwrap();
+        let meta = fs::metadata(format!("{dir}/dir/b")).unwrap();
+        assert_eq!(meta.len(), 30000);
+        assert_eq!(allocated_len(&meta), 30000);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn allocate_fails_without_enough_free_space() {
+        // A single exabyte-sized file.
+        let raw = format!(
+            "d4:infod6:lengthi{}e4:name3:big12:piece lengthi{}e6:pieces20480:{}ee",
+            1u64 << 60,
+            1u64 << 50,
+            "a".repeat(20480)
+        );
+        let dir = temp_dir("nospace");
+        let storage = FsStorage::new(
+            Arc::new(Metadata::from_bencode(raw.as_bytes()).unwrap()),
+            &dir,
+            &StorageOptions::default(),
+        );
+
+        let err = storage.allocate().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::StorageFull);
+        assert!(!Path::new(&dir).exists());
+
+        // Unfinished files are allocated in the incomplete directory, so its disk is checked.
+        let incomplete = format!("{dir}/incomplete");
+        let options = StorageOptions {
+            incomplete_dir: Some(incomplete.clone()),
+            ..Default::default()
+        };
+        let storage = FsStorage::new(
+            Arc::new(Metadata::from_bencode(raw.as_bytes()).unwrap()),
+            &dir,
+            &options,
+        );
+        let err = storage.allocate().unwrap_err();
+        assert!(err.to_string().contains(&incomplete), "{err}");
+    }
+
+    #[test]
+    fn part_files_are_finished_into_place() {
+        let (md, data) = two_file_torrent();
+        let root = temp_dir("part");
+        let dir = format!("{root}/downloads");
+        let incomplete = format!("{root}/incomplete");
+        let options = StorageOptions {
+            part_files: true,
+            incomplete_dir: Some(incomplete.clone()),
+            ..Default::default()
+        };
+        let storage = FsStorage::new(Arc::new(md), &dir, &options);
+
+        storage.allocate().unwrap();
+        assert!(Path::new(&format!("{incomplete}/dir/a.part")).exists());
+        assert!(!Path::new(&format!("{dir}/dir")).exists());
+
+        for (index, piece) in data.chunks(16384).enumerate() {
+            storage.write_block(index as u32, 0, piece).unwrap();
+        }
+        storage.finish_file(0).unwrap();
+        assert_eq!(fs::read(format!("{dir}/dir/a")).unwrap(), &data[..20000]);
+        assert!(!Path::new(&format!("{incomplete}/dir/a.part")).exists());
+        assert!(storage.hash_piece(1).unwrap());
+
+        // Finishing the last file leaves nothing behind in the incomplete directory.
+        storage.finish_file(1).unwrap();
+        assert!(!Path::new(&format!("{incomplete}/dir")).exists());
+        assert_eq!(storage.allocate().unwrap(), DiskState::Existing);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn move_to_relocates_data() {
+        let (md, data) = two_file_torrent();
+        let from = temp_dir("move-from");
+        let to = temp_dir("move-to");
+        let storage = FsStorage::new(Arc::new(md), &from, &StorageOptions::default());
+
+        storage.allocate().unwrap();
+        storage.write_block(0, 0, &data[..16384]).unwrap();
+        storage.move_to(&to).unwrap();
+
+        assert!(!Path::new(&format!("{from}/dir")).exists());
+        assert!(storage.hash_piece(0).unwrap());
+        storage.write_block(1, 0, &data[16384..32768]).unwrap();
+        assert!(storage.hash_piece(1).unwrap());
+
+        let _ = fs::remove_dir_all(&from);
+        fs::remove_dir_all(&to).unwrap();
+    }
+
+    #[test]
+    fn move_to_relocates_single_file_part_files() {
+        let (md, data) = single_file_torrent();
+        let from = temp_dir("move-single-from");
+        let to = temp_dir("move-single-to");
+        let options = StorageOptions {
+            part_files: true,
+            ..Default::default()
+        };
+        let storage = FsStorage::new(Arc::new(md), &from, &options);
+
+        storage.allocate().unwrap();
+        storage.write_block(0, 0, &data[..16384]).unwrap();
+        storage.move_to(&to).unwrap();
+
+        assert!(!Path::new(&format!("{from}/a.bin.part")).exists());
+        assert!(Path::new(&format!("{to}/a.bin.part")).exists());
+        assert!(storage.hash_piece(0).unwrap());
+        storage.write_block(1, 0, &data[16384..]).unwrap();
+        assert!(storage.hash_piece(1).unwrap());
+
+        let _ = fs::remove_dir_all(&from);
+        fs::remove_dir_all(&to).unwrap();
+    }
+}
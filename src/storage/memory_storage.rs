@@ -0,0 +1,103 @@
+use std::{
+    io,
+    sync::{Arc, Mutex},
+};
+
+use crate::parser::metadata::Metadata;
+
+use super::{resume::FileState, slices, DiskState, Storage};
+
+// Keeps each file in memory, for tests that shouldn't touch the disk.
+pub(crate) struct MemoryStorage {
+    md: Arc<Metadata>,
+    files: Mutex<Vec<Vec<u8>>>,
+}
+
+impl MemoryStorage {
+    pub(crate) fn new(md: Arc<Metadata>) -> Self {
+        MemoryStorage {
+            md,
+            files: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn metadata(&self) -> &Metadata {
+        &self.md
+    }
+
+    fn allocate(&self) -> io::Result<DiskState> {
+        let mut files = self.files.lock().unwrap();
+        let state = if files.is_empty() {
+            DiskState::New
+        } else {
+            DiskState::Existing
+        };
+
+        files.resize(self.md.info.files.len(), Vec::new());
+        for (data, file) in files.iter_mut().zip(&self.md.info.files) {
+            if !file.is_padding() {
+                data.resize(file.length.try_into().unwrap(), 0);
+            }
+        }
+
+        Ok(state)
+    }
+
+    fn read_block(&self, index: u32, begin: u32, len: u32) -> io::Result<Vec<u8>> {
+        let mut data = vec![0; len.try_into().unwrap()];
+        let files = self.files.lock().unwrap();
+
+        for slice in slices(&self.md, index, begin, data.len())? {
+            if let Some(file) = files.get(slice.file) {
+                let offset: usize = slice.offset.try_into().unwrap();
+                let buf = &mut data[slice.range];
+                buf.copy_from_slice(&file[offset..offset + buf.len()]);
+            }
+        }
+
+        Ok(data)
+    }
+
+    fn write_block(&self, index: u32, begin: u32, data: &[u8]) -> io::Result<()> {
+        let mut files = self.files.lock().unwrap();
+
+        for slice in slices(&self.md, index, begin, data.len())? {
+            let file = files
+                .get_mut(slice.file)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Not allocated"))?;
+            let offset: usize = slice.offset.try_into().unwrap();
+            let buf = &data[slice.range];
+            file[offset..offset + buf.len()].copy_from_slice(buf);
+        }
+
+        Ok(())
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    // Nothing outlives the process, so there are no times to compare.
+    fn file_states(&self) -> io::Result<Vec<FileState>> {
+        Ok(self
+            .files
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|data| FileState {
+                length: data.len() as u64,
+                mtime: 0,
+            })
+            .collect())
+    }
+
+    fn finish_file(&self, _file: usize) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn move_to(&self, _dir: &str) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,163 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    fs::File,
+    io,
+    sync::{Arc, Mutex},
+};
+
+use memmap2::MmapMut;
+
+use crate::parser::metadata::Metadata;
+
+use super::{fs_storage::FsStorage, resume::FileState, slices, DiskState, Storage, StorageOptions};
+
+// Files mapped into memory, so blocks are copied in and out without a syscall each. Allocation
+// and moving are shared with the plain filesystem backend. Disk space is always reserved up
+// front, as a write to a mapping that finds the disk full kills the process instead of failing.
+pub(crate) struct MmapStorage {
+    fs: FsStorage,
+    // Also held while files are renamed or moved, so nothing is mapped at a stale path.
+    maps: Mutex<HashMap<usize, MmapMut>>,
+}
+
+impl MmapStorage {
+    pub(crate) fn new(md: Arc<Metadata>, dir: &str, options: &StorageOptions) -> Self {
+        MmapStorage {
+            fs: FsStorage::new(md, dir, options).always_preallocate(),
+            maps: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Runs `f` on the file's mapping, mapping it first if needed. Returns None when the file
+    // doesn't exist or doesn't have its full size yet, as touching a mapping past the end of its
+    // file is fatal.
+    fn with_map<R>(&self, file: usize, f: impl FnOnce(&mut MmapMut) -> R) -> io::Result<Option<R>> {
+        let mut maps = self.maps.lock().unwrap();
+
+        let map = match maps.entry(file) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => {
+                let md = self.fs.metadata();
+                let handle = match File::options()
+                    .read(true)
+                    .write(true)
+                    .open(self.fs.path(file))
+                {
+                    Ok(v) => v,
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+                    Err(e) => return Err(e),
+                };
+                if handle.metadata()?.len() != md.info.files[file].length {
+                    return Ok(None);
+                }
+
+                // Safety: the file is only resized by `allocate` and moved by `finish_file` and
+                // `move_to`, all of which drop its mapping first.
+                e.insert(unsafe { MmapMut::map_mut(&handle)? })
+            }
+        };
+
+        Ok(Some(f(map)))
+    }
+}
+
+impl Storage for MmapStorage {
+    fn metadata(&self) -> &Metadata {
+        self.fs.metadata()
+    }
+
+    fn allocate(&self) -> io::Result<DiskState> {
+        self.maps.lock().unwrap().clear();
+        self.fs.allocate()
+    }
+
+    fn read_block(&self, index: u32, begin: u32, len: u32) -> io::Result<Vec<u8>> {
+        let mut data = vec![0; len.try_into().unwrap()];
+
+        for slice in slices(self.metadata(), index, begin, data.len())? {
+            let offset: usize = slice.offset.try_into().unwrap();
+            let buf = &mut data[slice.range];
+            self.with_map(slice.file, |map| {
+                buf.copy_from_slice(&map[offset..offset + buf.len()]);
+            })?;
+        }
+
+        Ok(data)
+    }
+
+    fn write_block(&self, index: u32, begin: u32, data: &[u8]) -> io::Result<()> {
+        for slice in slices(self.metadata(), index, begin, data.len())? {
+            let offset: usize = slice.offset.try_into().unwrap();
+            let buf = &data[slice.range.clone()];
+            let written = self.with_map(slice.file, |map| {
+                map[offset..offset + buf.len()].copy_from_slice(buf);
+            })?;
+            // Files that can't be mapped yet, such as lazily allocated ones, are written through
+            // the file backend, which creates them.
+            if written.is_none() {
+                let begin = begin + u32::try_from(slice.range.start).unwrap();
+                self.fs.write_block(index, begin, buf)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        for map in self.maps.lock().unwrap().values() {
+            map.flush()?;
+        }
+        self.fs.flush()
+    }
+
+    fn file_states(&self) -> io::Result<Vec<FileState>> {
+        self.fs.file_states()
+    }
+
+    fn finish_file(&self, file: usize) -> io::Result<()> {
+        let mut maps = self.maps.lock().unwrap();
+        if let Some(map) = maps.remove(&file) {
+            map.flush()?;
+        }
+        self.fs.finish_file(file)
+    }
+
+    fn move_to(&self, dir: &str) -> io::Result<()> {
+        let mut maps = self.maps.lock().unwrap();
+        for map in maps.values() {
+            map.flush()?;
+        }
+        maps.clear();
+        self.fs.move_to(dir)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use super::*;
+    use crate::storage::test::{temp_dir, two_file_torrent};
+
+    #[test]
+    fn blocks_round_trip_through_mappings() {
+        let (md, data) = two_file_torrent();
+        let dir = temp_dir("mmap");
+        let storage = MmapStorage::new(Arc::new(md), &dir, &StorageOptions::default());
+
+        storage.allocate().unwrap();
+        for (index, piece) in data.chunks(16384).enumerate() {
+            storage.write_block(index as u32, 0, piece).unwrap();
+        }
+        storage.flush().unwrap();
+
+        assert_eq!(fs::read(format!("{dir}/dir/b")).unwrap(), &data[20000..]);
+        assert_eq!(
+            storage.read_block(1, 3000, 1000).unwrap(),
+            &data[19384..20384]
+        );
+        assert!((0..4).all(|i| storage.hash_piece(i).unwrap()));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
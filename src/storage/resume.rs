@@ -0,0 +1,367 @@
+use std::{
+    fs::{self, File},
+    io::{self, Error as IOError, ErrorKind, Write},
+    path::Path,
+};
+
+use bendy::{
+    decoding::{Error as DecError, FromBencode, Object, ResultExt},
+    encoding::{AsString, Error as EncError, SingleItemEncoder, ToBencode},
+};
+use bitvec::{prelude::Msb0, vec::BitVec};
+
+use crate::parser::metadata::Metadata;
+
+use super::DiskState;
+
+// Size and modification time of a stored file, in nanoseconds since the Unix epoch. Both are
+// zero for padding and missing files.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct FileState {
+    pub length: u64,
+    pub mtime: u64,
+}
+
+// Blocks of an unverified piece that are already in storage.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct PartialPiece {
+    pub index: u32,
+    pub blocks: BitVec<u8, Msb0>,
+}
+
+// Everything needed to carry on with a torrent in a later session without a recheck, kept apart
+// from the downloaded data in the state directory.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct ResumeData {
+    pub info_hash: Vec<u8>,
+    pub bitfield: BitVec<u8, Msb0>,
+    pub partial: Vec<PartialPiece>,
+    // The state of every file when this was saved. Any difference on startup means the data was
+    // touched in between, so the bitfield can't be trusted.
+    pub files: Vec<FileState>,
+    pub uploaded: u64,
+    pub downloaded: u64,
+}
+
+impl ResumeData {
+    // A torrent with nothing downloaded.
+    pub(crate) fn new(md: &Metadata) -> Self {
+        ResumeData {
+            info_hash: md.info_hash.clone(),
+            bitfield: BitVec::repeat(false, md.num_pieces()),
+            partial: Vec::new(),
+            files: Vec::new(),
+            uploaded: 0,
+            downloaded: 0,
+        }
+    }
+}
+
+// Picks what to carry on from, given what `allocate` found on disk and the saved resume data.
+// Returns the resume data to use and whether the data on disk must be rechecked first.
+pub(crate) fn validate(
+    md: &Metadata,
+    disk_state: &DiskState,
+    saved: Option<ResumeData>,
+    files: &[FileState],
+) -> (ResumeData, bool) {
+    match (disk_state, saved) {
+        (DiskState::New, _) => (ResumeData::new(md), false),
+        (DiskState::Existing, Some(saved)) if saved.files == files => (saved, false),
+        (_, saved) => {
+            // Transfer totals don't depend on what is on disk, so they are kept.
+            let mut fresh = ResumeData::new(md);
+            if let Some(saved) = saved {
+                fresh.uploaded = saved.uploaded;
+                fresh.downloaded = saved.downloaded;
+            }
+            (fresh, true)
+        }
+    }
+}
+
+fn resume_path(state_dir: &str, md: &Metadata) -> String {
+    format!("{}/{}.resume", state_dir, hex::encode(&md.info_hash))
+}
+
+// Reads the torrent's resume file. Missing or mismatched files are treated as no resume data,
+// which makes the caller fall back to a recheck. Corrupt files are an `InvalidData` error.
+pub(crate) fn load(state_dir: &str, md: &Metadata) -> io::Result<Option<ResumeData>> {
+    let raw = match fs::read(resume_path(state_dir, md)) {
+        Ok(v) => v,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    let mut data = ResumeData::from_bencode(&raw).map_err(|e| {
+        IOError::new(
+            ErrorKind::InvalidData,
+            format!("Corrupt resume file: {}", e),
+        )
+    })?;
+
+    let geometry = md.geometry();
+    if data.info_hash != md.info_hash || data.bitfield.len() < md.num_pieces() {
+        return Ok(None);
+    }
+    data.bitfield.truncate(md.num_pieces());
+    data.partial.retain_mut(|p| {
+        if p.index >= geometry.num_pieces() {
+            return false;
+        }
+        let num_blocks: usize = geometry.num_blocks(p.index).try_into().unwrap();
+        p.blocks.resize(num_blocks, false);
+        true
+    });
+
+    Ok(Some(data))
+}
+
+// Replaces the resume file atomically: the new contents are written and synced to a temporary
+// file first, so a crash leaves either the old file or the new one.
+pub(crate) fn save(state_dir: &str, md: &Metadata, data: &ResumeData) -> io::Result<()> {
+    let raw = data
+        .to_bencode()
+        .map_err(|e| IOError::new(ErrorKind::InvalidData, e.to_string()))?;
+
+    fs::create_dir_all(state_dir)?;
+    let path = resume_path(state_dir, md);
+    let tmp_path = format!("{}.tmp", path);
+
+    let mut f = File::create(&tmp_path)?;
+    f.write_all(&raw)?;
+    f.sync_all()?;
+    fs::rename(&tmp_path, &path)?;
+
+    // Makes the rename itself durable where directories can be synced.
+    if let Ok(dir) = File::open(Path::new(state_dir)) {
+        let _ = dir.sync_all();
+    }
+    Ok(())
+}
+
+/////////////////
+// Decoding
+
+fn decode_bits(object: Object) -> Result<BitVec<u8, Msb0>, DecError> {
+    let raw = object.try_into_bytes()?;
+    Ok(BitVec::from_slice(raw))
+}
+
+impl FromBencode for FileState {
+    const EXPECTED_RECURSION_DEPTH: usize = 1;
+
+    fn decode_bencode_object(object: Object) -> Result<Self, DecError>
+    where
+        Self: Sized,
+    {
+        let mut state = FileState::default();
+        let mut dict = object.try_into_dictionary()?;
+
+        while let Some(pair) = dict.next_pair()? {
+            match pair {
+                (b"length", val) => {
+                    state.length = u64::decode_bencode_object(val).context("length")?;
+                }
+                (b"mtime", val) => {
+                    state.mtime = u64::decode_bencode_object(val).context("mtime")?;
+                }
+                _ => continue,
+            }
+        }
+
+        Ok(state)
+    }
+}
+
+impl FromBencode for PartialPiece {
+    const EXPECTED_RECURSION_DEPTH: usize = 1;
+
+    fn decode_bencode_object(object: Object) -> Result<Self, DecError>
+    where
+        Self: Sized,
+    {
+        let mut index: Option<u32> = None;
+        let mut blocks: Option<BitVec<u8, Msb0>> = None;
+        let mut dict = object.try_into_dictionary()?;
+
+        while let Some(pair) = dict.next_pair()? {
+            match pair {
+                (b"blocks", val) => blocks = Some(decode_bits(val).context("blocks")?),
+                (b"index", val) => index = Some(u32::decode_bencode_object(val).context("index")?),
+                _ => continue,
+            }
+        }
+
+        Ok(PartialPiece {
+            index: index.ok_or_else(|| DecError::missing_field("index"))?,
+            blocks: blocks.ok_or_else(|| DecError::missing_field("blocks"))?,
+        })
+    }
+}
+
+impl FromBencode for ResumeData {
+    const EXPECTED_RECURSION_DEPTH: usize = 3;
+
+    fn decode_bencode_object(object: Object) -> Result<Self, DecError>
+    where
+        Self: Sized,
+    {
+        let mut info_hash: Option<Vec<u8>> = None;
+        let mut bitfield: Option<BitVec<u8, Msb0>> = None;
+        let mut partial = Vec::new();
+        let mut files = Vec::new();
+        let mut uploaded = 0;
+        let mut downloaded = 0;
+        let mut dict = object.try_into_dictionary()?;
+
+        while let Some(pair) = dict.next_pair()? {
+            match pair {
+                (b"bitfield", val) => bitfield = Some(decode_bits(val).context("bitfield")?),
+                (b"downloaded", val) => {
+                    downloaded = u64::decode_bencode_object(val).context("downloaded")?;
+                }
+                (b"files", val) => files = Vec::decode_bencode_object(val).context("files")?,
+                (b"info hash", val) => {
+                    info_hash = Some(val.try_into_bytes().context("info hash")?.to_vec());
+                }
+                (b"partial", val) => {
+                    partial = Vec::decode_bencode_object(val).context("partial")?;
+                }
+                (b"uploaded", val) => {
+                    uploaded = u64::decode_bencode_object(val).context("uploaded")?;
+                }
+                _ => continue,
+            }
+        }
+
+        Ok(ResumeData {
+            info_hash: info_hash.ok_or_else(|| DecError::missing_field("info hash"))?,
+            bitfield: bitfield.ok_or_else(|| DecError::missing_field("bitfield"))?,
+            partial,
+            files,
+            uploaded,
+            downloaded,
+        })
+    }
+}
+
+/////////////////
+// Encoding
+
+impl ToBencode for FileState {
+    const MAX_DEPTH: usize = 1;
+
+    fn encode(&self, encoder: SingleItemEncoder) -> Result<(), EncError> {
+        encoder.emit_dict(|mut e| {
+            e.emit_pair(b"length", self.length)?;
+            e.emit_pair(b"mtime", self.mtime)
+        })
+    }
+}
+
+impl ToBencode for PartialPiece {
+    const MAX_DEPTH: usize = 1;
+
+    fn encode(&self, encoder: SingleItemEncoder) -> Result<(), EncError> {
+        encoder.emit_dict(|mut e| {
+            e.emit_pair(b"blocks", AsString(self.blocks.as_raw_slice()))?;
+            e.emit_pair(b"index", self.index)
+        })
+    }
+}
+
+impl ToBencode for ResumeData {
+    const MAX_DEPTH: usize = 3;
+
+    fn encode(&self, encoder: SingleItemEncoder) -> Result<(), EncError> {
+        encoder.emit_dict(|mut e| {
+            e.emit_pair(b"bitfield", AsString(self.bitfield.as_raw_slice()))?;
+            e.emit_pair(b"downloaded", self.downloaded)?;
+            e.emit_pair(b"files", &self.files)?;
+            e.emit_pair(b"info hash", AsString(&self.info_hash))?;
+            e.emit_pair(b"partial", &self.partial)?;
+            e.emit_pair(b"uploaded", self.uploaded)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage::test::{temp_dir, two_file_torrent};
+
+    #[test]
+    fn resume_data_round_trips_through_state_dir() {
+        let (md, _) = two_file_torrent();
+        let dir = temp_dir("resume");
+
+        assert_eq!(load(&dir, &md).unwrap(), None);
+
+        let mut data = ResumeData::new(&md);
+        data.bitfield.set(1, true);
+        data.partial.push(PartialPiece {
+            index: 3,
+            blocks: BitVec::repeat(true, 1),
+        });
+        data.files = vec![
+            FileState {
+                length: 20000,
+                mtime: 1_700_000_000_123_456_789,
+            },
+            FileState::default(),
+        ];
+        data.uploaded = 5;
+        data.downloaded = 16384;
+        save(&dir, &md, &data).unwrap();
+
+        assert_eq!(load(&dir, &md).unwrap(), Some(data));
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        // Resume data for a different torrent is ignored.
+        let path = resume_path(&dir, &md);
+        let mut other = ResumeData::new(&md);
+        other.info_hash = vec![0; 20];
+        fs::write(&path, other.to_bencode().unwrap()).unwrap();
+        assert_eq!(load(&dir, &md).unwrap(), None);
+
+        fs::write(&path, b"not bencode").unwrap();
+        assert_eq!(load(&dir, &md).unwrap_err().kind(), ErrorKind::InvalidData);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn changed_files_force_a_recheck() {
+        let (md, _) = two_file_torrent();
+        let files = vec![
+            FileState {
+                length: 20000,
+                mtime: 1,
+            },
+            FileState {
+                length: 30000,
+                mtime: 2,
+            },
+        ];
+        let mut saved = ResumeData::new(&md);
+        saved.bitfield.set(0, true);
+        saved.files = files.clone();
+        saved.uploaded = 7;
+
+        let (data, recheck) = validate(&md, &DiskState::Existing, Some(saved), &files);
+        assert!(!recheck);
+        assert!(data.bitfield[0]);
+
+        let mut touched = files.clone();
+        touched[1].mtime = 3;
+        let (data, recheck) = validate(&md, &DiskState::Existing, Some(data), &touched);
+        assert!(recheck);
+        assert!(data.bitfield.not_any());
+        assert_eq!(data.uploaded, 7);
+
+        assert!(validate(&md, &DiskState::Existing, None, &files).1);
+        assert!(validate(&md, &DiskState::NeedsRecheck, Some(data), &files).1);
+        assert!(!validate(&md, &DiskState::New, None, &files).1);
+    }
+}
@@ -1,12 +1,17 @@
 use crate::parser::{metadata::Metadata, tracker_info::PeerInfo};
 
 pub mod tracker_acquirer;
+pub mod tracker_session;
+pub mod tracker_tiers;
+pub mod udp_tracker;
 pub mod magnet_acquirer;
 
 pub(crate) struct TorrentInfo
 {
     pub md: Metadata,
-    pub peers: Vec<PeerInfo>
+    // Peers known without announcing. Trackers are announced to by the torrent's tracker
+    // session instead.
+    pub peers: Vec<PeerInfo>,
 }
 
 pub(crate) trait TorrentInfoAcquirer
@@ -1,189 +1,271 @@
-use crate::parser::{
-    metadata::{get_urlenc_info_hash, read_metadata, Metadata},
-    tracker_info::TrackerInfo,
+use crate::{
+    client::{peer_id, stats::TransferStats},
+    parser::{
+        metadata::{get_urlenc_info_hash, read_metadata, Metadata},
+        scrape_info::{ScrapeInfo, ScrapeResponse},
+        tracker_info::TrackerInfo,
+    },
 };
 
 use bendy::decoding::FromBencode;
-use byteorder::{BigEndian, ReadBytesExt};
-use rand::Rng;
 use reqwest::Client;
 use std::io::Error as IOError;
-use std::{io::ErrorKind, time::Duration};
-use tokio::{net::UdpSocket, time::timeout};
+use std::{collections::HashMap, io::ErrorKind, sync::OnceLock};
 use urlencoding::encode_binary;
 
-use super::{TorrentInfo, TorrentInfoAcquirer};
+use super::{
+    udp_tracker::{url_data_option, UdpTracker, UdpTrackerClient, ACTION_ANNOUNCE, ACTION_SCRAPE},
+    TorrentInfo, TorrentInfoAcquirer,
+};
+
+// How many peers to ask each tracker for.
+const NUM_WANT: u16 = 50;
+// Info hashes per scrape request. UDP answers for more would not fit a typical packet.
+const HTTP_SCRAPE_BATCH: usize = 50;
+const UDP_SCRAPE_BATCH: usize = 74;
+
+static ANNOUNCE_KEY: OnceLock<u32> = OnceLock::new();
+
+// Sent with every announce in this session, so trackers can recognise us if our IP changes.
+fn announce_key() -> u32 {
+    *ANNOUNCE_KEY.get_or_init(rand::random)
+}
+
+// Why an announce is sent, numbered as in UDP announces.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum AnnounceEvent {
+    // A regular re-announce.
+    None = 0,
+    Completed = 1,
+    Started = 2,
+    Stopped = 3,
+}
+
+impl AnnounceEvent {
+    // The value of the HTTP `event` parameter, which regular re-announces leave out.
+    fn as_param(&self) -> Option<&'static str> {
+        match self {
+            AnnounceEvent::None => None,
+            AnnounceEvent::Completed => Some("completed"),
+            AnnounceEvent::Started => Some("started"),
+            AnnounceEvent::Stopped => Some("stopped"),
+        }
+    }
+
+    // Unknown events are treated as regular re-announces, like a missing `event`.
+    pub(crate) fn from_param(param: &str) -> Self {
+        match param {
+            "completed" => AnnounceEvent::Completed,
+            "started" => AnnounceEvent::Started,
+            "stopped" => AnnounceEvent::Stopped,
+            _ => AnnounceEvent::None,
+        }
+    }
+
+    pub(crate) fn from_u32(event: u32) -> Self {
+        match event {
+            1 => AnnounceEvent::Completed,
+            2 => AnnounceEvent::Started,
+            3 => AnnounceEvent::Stopped,
+            _ => AnnounceEvent::None,
+        }
+    }
+}
+
+// By convention, a tracker supports scrape if the last part of its announce URL starts with
+// "announce", which is swapped for "scrape".
+pub(crate) fn scrape_url(announce_url: &str) -> Option<String> {
+    let (path, query) = match announce_url.find('?') {
+        Some(i) => announce_url.split_at(i),
+        None => (announce_url, ""),
+    };
+    let name_start = path.rfind('/')? + 1;
+    let rest = path[name_start..].strip_prefix("announce")?;
+    Some(format!("{}scrape{}{}", &path[..name_start], rest, query))
+}
 
 pub(crate) struct TrackerAcquirer {}
 
 impl TrackerAcquirer {
-    pub(crate) async fn req_tracker_info(
+    // Announces to a single tracker. Transfer totals and what is left are read from `stats` as
+    // the announce is sent.
+    pub(crate) async fn announce(
+        tracker: &String,
         md: &Metadata,
+        stats: &TransferStats,
+        event: AnnounceEvent,
     ) -> Result<TrackerInfo, Box<dyn std::error::Error>> {
-        for tracker in &md.announce_list {
-            let tracker = &tracker[0];
-            let req = if tracker.starts_with("http") {
-                Self::req_http_tracker_info(tracker, md).await
-            } else {
-                Self::req_udp_tracker_info(tracker, md).await
-            };
-
-            match req {
-                Ok(tracker_info) => return Ok(tracker_info),
-                Err(_) => continue,
-            }
+        if tracker.starts_with("http") {
+            Self::req_http_tracker_info(tracker, md, stats, event).await
+        } else {
+            Self::req_udp_tracker_info(tracker, md, stats, event).await
         }
-        return Err(Box::new(IOError::new(
-            ErrorKind::NotConnected,
-            "Failed to retrieve tracker info",
-        )));
     }
 
     async fn req_http_tracker_info(
-        tracker_url: &String,
+        tracker_url: &str,
         md: &Metadata,
+        stats: &TransferStats,
+        event: AnnounceEvent,
     ) -> Result<TrackerInfo, Box<dyn std::error::Error>> {
-        let hash = get_urlenc_info_hash(&md).unwrap();
-        let peer_id = encode_binary(b"-TO0000-0123456789AB");
-        let port = String::from("3000");
-        let url = format!("{tracker_url}?info_hash={hash}&peer_id={peer_id}");
+        let url = Self::announce_url(tracker_url, md, stats, event);
+        let res = Client::new().get(url).send().await?.bytes().await?;
 
-        let client = Client::new();
+        let tracker_info = TrackerInfo::from_bencode(&res)
+            .map_err(|e| IOError::new(ErrorKind::InvalidData, e.to_string()))?;
+        if let Some(reason) = tracker_info.failure_reason {
+            return Err(Box::new(IOError::new(
+                ErrorKind::PermissionDenied,
+                format!("Tracker refused announce: {reason}"),
+            )));
+        }
 
-        let res = client
-            .get(url)
-            .query(&[("port", &port)])
-            .send()
-            .await?
-            .bytes()
-            .await?;
+        Ok(tracker_info)
+    }
 
-        let tracker_info = TrackerInfo::from_bencode(&res).unwrap();
+    // Our parameters go after any the announce URL already has, such as a private tracker's
+    // passkey, which is left as written. The URL is still parsed when it is sent, which resolves
+    // dot segments in its path and escapes characters not allowed in it.
+    fn announce_url(
+        tracker_url: &str,
+        md: &Metadata,
+        stats: &TransferStats,
+        event: AnnounceEvent,
+    ) -> String {
+        let mut params = vec![
+            format!("info_hash={}", get_urlenc_info_hash(md)),
+            format!("peer_id={}", encode_binary(peer_id::session().as_bytes())),
+            String::from("port=3000"),
+            format!("uploaded={}", stats.uploaded()),
+            format!("downloaded={}", stats.downloaded()),
+            format!("left={}", stats.left()),
+            String::from("compact=1"),
+            String::from("no_peer_id=1"),
+            format!("numwant={NUM_WANT}"),
+            format!("key={:08x}", announce_key()),
+        ];
+        if let Some(event) = event.as_param() {
+            params.push(format!("event={event}"));
+        }
 
-        Ok(tracker_info)
+        let separator = if tracker_url.contains('?') { '&' } else { '?' };
+        format!("{tracker_url}{separator}{}", params.join("&"))
     }
 
     async fn req_udp_tracker_info(
         tracker_url: &String,
         md: &Metadata,
+        stats: &TransferStats,
+        event: AnnounceEvent,
     ) -> Result<TrackerInfo, Box<dyn std::error::Error>> {
-        let url = url::Url::parse(tracker_url).unwrap();
-        let addr = match url.socket_addrs(|| None) {
-            Ok(v) => v[0],
-            Err(_) => {
-                return Err(Box::new(IOError::new(
-                    ErrorKind::InvalidInput,
-                    "Invalid tracker url: {tracker_url}",
-                )));
-            }
-        };
-
-        let mut timeout_duration = 1000;
-
-        let socket = UdpSocket::bind("0.0.0.0:3000").await?;
-        socket.connect(addr).await?;
+        let tracker = UdpTracker::resolve(tracker_url).await?;
+        let client = UdpTrackerClient::shared().await?;
 
-        let mut rng = rand::thread_rng();
-        let trans_id: u32 = rng.gen();
-        let connect_msg = Self::connect_msg(trans_id);
-        let _ = socket.send(&connect_msg).await?;
+        let url_data = url_data_option(&tracker.url_data);
+        let res = client
+            .request(tracker.addr, ACTION_ANNOUNCE, |conn_id, trans_id| {
+                let msg = Self::announce_msg(md, stats, conn_id, trans_id, None, event);
+                [msg, url_data.clone()].concat()
+            })
+            .await?;
 
-        let mut buf = [0; 16];
-        let resp = socket.recv(&mut buf);
+        let res = TrackerInfo::from_raw(res).map_err(|_| {
+            IOError::new(
+                ErrorKind::InvalidData,
+                "Invalid announce response from tracker",
+            )
+        })?;
+        Ok(res)
+    }
 
-        match timeout(Duration::from_millis(timeout_duration), resp).await {
-            Err(_) => {
-                return Err(Box::new(IOError::new(
-                    ErrorKind::TimedOut,
-                    "Timeout when attempting to perform UDP tracker handshake with {tracker_url} after {duration}ms",
-                )));
+    // Asks a tracker for swarm stats of each info hash, in as few requests as it allows. Info
+    // hashes the tracker doesn't know are left out of the result.
+    pub(crate) async fn scrape(
+        tracker: &str,
+        info_hashes: &[Vec<u8>],
+    ) -> Result<HashMap<Vec<u8>, ScrapeInfo>, Box<dyn std::error::Error>> {
+        let mut files = HashMap::new();
+        if tracker.starts_with("http") {
+            let url = scrape_url(tracker).ok_or_else(|| {
+                IOError::new(ErrorKind::Unsupported, "Tracker doesn't support scrape")
+            })?;
+            for batch in info_hashes.chunks(HTTP_SCRAPE_BATCH) {
+                files.extend(Self::req_http_scrape(&url, batch).await?);
             }
-            Ok(fut) => fut?,
-        };
-
-        let mut action_recv = &buf[..4];
-        let mut trans_id_recv = &buf[4..8];
-        let mut conn_id_recv = &buf[8..];
-
-        let action_recv = action_recv.read_u32::<BigEndian>()?;
-        let trans_id_recv = trans_id_recv.read_u32::<BigEndian>()?;
-        let conn_id_recv = conn_id_recv.read_u64::<BigEndian>()?;
+        } else {
+            let tracker = UdpTracker::resolve(tracker).await?;
+            let client = UdpTrackerClient::shared().await?;
+            for batch in info_hashes.chunks(UDP_SCRAPE_BATCH) {
+                let res = client
+                    .request(tracker.addr, ACTION_SCRAPE, |conn_id, trans_id| {
+                        Self::scrape_msg(conn_id, trans_id, batch)
+                    })
+                    .await?;
+                files.extend(ScrapeResponse::from_udp(&res, batch).files);
+            }
+        }
+        Ok(files)
+    }
 
-        if action_recv != 0 || trans_id_recv != trans_id {
+    async fn req_http_scrape(
+        url: &str,
+        info_hashes: &[Vec<u8>],
+    ) -> Result<HashMap<Vec<u8>, ScrapeInfo>, Box<dyn std::error::Error>> {
+        let hashes: Vec<String> = info_hashes
+            .iter()
+            .map(|hash| format!("info_hash={}", encode_binary(hash)))
+            .collect();
+        let separator = if url.contains('?') { '&' } else { '?' };
+        let url = format!("{url}{separator}{}", hashes.join("&"));
+
+        let res = Client::new().get(url).send().await?.bytes().await?;
+        let res = ScrapeResponse::from_bencode(&res)
+            .map_err(|e| IOError::new(ErrorKind::InvalidData, e.to_string()))?;
+        if let Some(reason) = res.failure_reason {
             return Err(Box::new(IOError::new(
-                ErrorKind::InvalidData,
-                "Invalid response from server",
+                ErrorKind::PermissionDenied,
+                format!("Tracker refused scrape: {reason}"),
             )));
         }
 
-        let announce_msg = Self::announce_msg(md, conn_id_recv, trans_id, None);
-
-        loop {
-            let _ = socket.send(&announce_msg).await?;
-
-            let mut buf = [0; 1024];
-            let resp = socket.recv(&mut buf);
-            match timeout(Duration::from_millis(timeout_duration), resp).await {
-                Err(_) => {
-                    if timeout_duration >= 20000 {
-                        break;
-                    }
-
-                    println!("Failed to receive announce response from tracker after {timeout_duration}ms, retrying...");
-                    timeout_duration *= 2;
-                    continue;
-                }
-                Ok(fut) => fut?,
-            };
-
-            let res = TrackerInfo::from_raw(buf.to_vec()).unwrap();
-            return Ok(res);
-        }
-
-        return Err(Box::new(IOError::new(
-            ErrorKind::NotConnected,
-            "Failed to Connect",
-        )));
+        Ok(res.files)
     }
 
-    fn connect_msg(trans_id: u32) -> Vec<u8> {
-        let proto_id: u64 = 0x41727101980; // magic bytes
-        let action: u32 = 0;
-
+    fn scrape_msg(conn_id: u64, trans_id: u32, info_hashes: &[Vec<u8>]) -> Vec<u8> {
         [
-            proto_id.to_be_bytes().to_vec(),
-            action.to_be_bytes().to_vec(),
+            conn_id.to_be_bytes().to_vec(),
+            ACTION_SCRAPE.to_be_bytes().to_vec(),
             trans_id.to_be_bytes().to_vec(),
+            info_hashes.concat(),
         ]
         .concat()
     }
 
-    fn announce_msg(
+    pub(crate) fn announce_msg(
         md: &Metadata,
+        stats: &TransferStats,
         conn_id: u64,
         trans_id: u32,
         peer_id: Option<Vec<u8>>,
+        event: AnnounceEvent,
     ) -> Vec<u8> {
-        let action: u32 = 1;
         let info_hash = &md.info_hash;
         let peer_id = match peer_id {
-            None => b"-TO0000-0123456789AB".to_vec(),
+            None => peer_id::session().as_bytes().to_vec(),
             Some(v) => v,
         };
-        let downloaded: u64 = 0;
-
-        let _num_pieces: u64 = md.info.pieces.len().try_into().unwrap();
-        let left: u64 = (md.info.piece_length as u64 * _num_pieces).into();
-        let uploaded: u64 = 0;
-        let event: u32 = 0;
+        let downloaded = stats.downloaded();
+        let left = stats.left();
+        let uploaded = stats.uploaded();
+        let event = event as u32;
         let ip: u32 = 0;
-        let key: u32 = 12345;
-        let num_want: i32 = -1;
+        let key = announce_key();
+        let num_want = i32::from(NUM_WANT);
         let port: u16 = 3000;
 
         [
             conn_id.to_be_bytes().to_vec(),
-            action.to_be_bytes().to_vec(),
+            ACTION_ANNOUNCE.to_be_bytes().to_vec(),
             trans_id.to_be_bytes().to_vec(),
             info_hash.to_vec(),
             peer_id.to_vec(),
@@ -205,12 +287,148 @@ impl TorrentInfoAcquirer for TrackerAcquirer {
         &self,
         torrent_file: String,
     ) -> Result<TorrentInfo, Box<dyn std::error::Error>> {
-        let md = read_metadata(&torrent_file).unwrap();
-        let tracker_info = TrackerAcquirer::req_tracker_info(&md).await?;
+        let md = read_metadata(&torrent_file).map_err(|e| e.to_string())?;
 
         Ok(TorrentInfo {
             md,
-            peers: tracker_info.peers,
+            peers: Vec::new(),
         })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+    use crate::storage::test::{temp_dir, two_file_torrent};
+
+    #[test]
+    fn scrape_url_follows_the_announce_url() {
+        assert_eq!(
+            scrape_url("http://t.example/announce").as_deref(),
+            Some("http://t.example/scrape")
+        );
+        assert_eq!(
+            scrape_url("http://t.example/x/announce.php?passkey=a/b").as_deref(),
+            Some("http://t.example/x/scrape.php?passkey=a/b")
+        );
+        assert_eq!(scrape_url("http://t.example/a"), None);
+        assert_eq!(scrape_url("http://t.example/announce/x"), None);
+    }
+
+    #[test]
+    fn announce_url_keeps_the_trackers_own_query() {
+        let (md, _) = two_file_torrent();
+        let stats = TransferStats::new(1, 2, 3);
+        let url = TrackerAcquirer::announce_url(
+            "http://t.example/a/announce?passkey=a%2Fb~c",
+            &md,
+            &stats,
+            AnnounceEvent::Started,
+        );
+        assert!(url.starts_with("http://t.example/a/announce?passkey=a%2Fb~c&info_hash="));
+        assert!(url.contains("&uploaded=1&downloaded=2&left=3&"));
+        assert!(url.ends_with("&event=started"));
+
+        let url = TrackerAcquirer::announce_url(
+            "http://t.example/announce",
+            &md,
+            &stats,
+            AnnounceEvent::None,
+        );
+        assert!(url.starts_with("http://t.example/announce?info_hash="));
+        assert!(!url.contains("event="));
+    }
+
+    // What a tracker receives, rather than the URL we build.
+    #[tokio::test]
+    async fn http_announce_sends_the_trackers_own_query() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let tracker = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut head = Vec::new();
+            let mut buf = [0; 1024];
+            while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+                let len = stream.read(&mut buf).await.unwrap();
+                head.extend(&buf[..len]);
+            }
+            let body = b"d8:intervali1800e5:peers0:e";
+            let reply = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            );
+            stream
+                .write_all(&[reply.as_bytes(), body].concat())
+                .await
+                .unwrap();
+            String::from_utf8_lossy(&head).into_owned()
+        });
+
+        let (md, _) = two_file_torrent();
+        let stats = TransferStats::new(1, 2, 3);
+        let url = format!("http://{addr}/x/announce?passkey=a%2Fb~c");
+        TrackerAcquirer::announce(&url, &md, &stats, AnnounceEvent::None)
+            .await
+            .unwrap();
+
+        let head = tracker.await.unwrap();
+        assert!(
+            head.starts_with("GET /x/announce?passkey=a%2Fb~c&info_hash="),
+            "{head}"
+        );
+    }
+
+    #[tokio::test]
+    async fn acquire_fails_on_unreadable_metafiles() {
+        let dir = temp_dir("acquire_unreadable");
+        std::fs::create_dir_all(&dir).unwrap();
+        let missing = format!("{dir}/missing.torrent");
+        assert!(TrackerAcquirer {}.acquire(missing).await.is_err());
+
+        let corrupt = format!("{dir}/corrupt.torrent");
+        std::fs::write(&corrupt, b"d4:info").unwrap();
+        assert!(TrackerAcquirer {}.acquire(corrupt).await.is_err());
+    }
+
+    #[test]
+    fn udp_scrape_batches_info_hashes() {
+        let msg = TrackerAcquirer::scrape_msg(7, 9, &[vec![1; 20], vec![2; 20]]);
+        assert_eq!(msg.len(), 56);
+        assert_eq!(&msg[8..12], &2u32.to_be_bytes());
+        assert_eq!(&msg[16..36], &[1; 20]);
+        assert_eq!(&msg[36..], &[2; 20]);
+    }
+
+    #[test]
+    fn udp_announce_reports_transfer_stats() {
+        let (md, _) = two_file_torrent();
+        let stats = TransferStats::new(1000, 16384, 33616);
+        let peer_id = vec![b'-'; 20];
+        let msg = TrackerAcquirer::announce_msg(
+            &md,
+            &stats,
+            7,
+            9,
+            Some(peer_id.clone()),
+            AnnounceEvent::Completed,
+        );
+
+        let u64_at = |at: usize| u64::from_be_bytes(msg[at..at + 8].try_into().unwrap());
+        let u32_at = |at: usize| u32::from_be_bytes(msg[at..at + 4].try_into().unwrap());
+        assert_eq!(msg.len(), 98);
+        assert_eq!(u64_at(0), 7);
+        assert_eq!(u32_at(8), 1);
+        assert_eq!(u32_at(12), 9);
+        assert_eq!(&msg[16..36], &md.info_hash[..]);
+        assert_eq!(&msg[36..56], &peer_id[..]);
+        assert_eq!(u64_at(56), 16384);
+        assert_eq!(u64_at(64), 33616);
+        assert_eq!(u64_at(72), 1000);
+        assert_eq!(u32_at(80), 1);
+    }
+}
@@ -0,0 +1,531 @@
+use std::{
+    collections::HashSet,
+    future::Future,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    sync::{mpsc, oneshot, watch},
+    task::JoinSet,
+    time,
+};
+
+use crate::{
+    client::{
+        admin_message::{AdminMessage, NewPeers},
+        stats::TransferStats,
+    },
+    parser::{
+        metadata::Metadata,
+        scrape_info::ScrapeInfo,
+        tracker_info::{PeerInfo, TrackerInfo},
+    },
+};
+
+use super::{
+    tracker_acquirer::{AnnounceEvent, TrackerAcquirer},
+    tracker_tiers::TrackerTiers,
+};
+
+// Announces are never sent more often than this, whatever a tracker asks for.
+const MIN_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(60);
+// How long to wait before trying again when no tracker answered.
+const RETRY_INTERVAL: Duration = Duration::from_secs(300);
+// How long shutdown waits for the `stopped` announce.
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum TrackerState {
+    NotContacted,
+    Working,
+    Failing,
+}
+
+// How a single tracker fared in its last announce, as shown in the UI.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct TrackerStatus {
+    pub url: String,
+    pub state: TrackerState,
+    pub last_error: Option<String>,
+    // The warning message of the last answer, if it had one.
+    pub warning: Option<String>,
+    // From the last scrape, unless the tracker doesn't support it.
+    pub swarm: Option<ScrapeInfo>,
+    // Unset once the session stops.
+    pub next_announce: Option<Instant>,
+}
+
+enum TrackerMessage {
+    Start,
+    Completed,
+    Stop(oneshot::Sender<()>),
+}
+
+// Lets the rest of the client drive a torrent's tracker session.
+#[derive(Clone)]
+pub(crate) struct TrackerHandle {
+    tx: mpsc::Sender<TrackerMessage>,
+    rx_status: watch::Receiver<Vec<TrackerStatus>>,
+}
+
+impl TrackerHandle {
+    // Every tracker of the torrent, in metafile order.
+    pub(crate) fn statuses(&self) -> watch::Receiver<Vec<TrackerStatus>> {
+        self.rx_status.clone()
+    }
+
+    // Announces `started`, once what is left to download is known. Later calls do nothing.
+    pub(crate) fn start(&self) {
+        let _ = self.tx.try_send(TrackerMessage::Start);
+    }
+
+    // Announces `completed` once the last piece is verified.
+    pub(crate) fn completed(&self) {
+        let _ = self.tx.try_send(TrackerMessage::Completed);
+    }
+
+    // Announces `stopped` and ends the session, giving up on a tracker that doesn't answer in
+    // time.
+    pub(crate) async fn stop(&self) {
+        let (tx, rx) = oneshot::channel();
+        if self.tx.send(TrackerMessage::Stop(tx)).await.is_ok() {
+            let _ = time::timeout(STOP_TIMEOUT, rx).await;
+        }
+    }
+}
+
+// Keeps announcing a torrent for as long as it runs. Peers from every announce are passed on to
+// the manager, which connects to those it doesn't know yet. Trackers are tried tier by tier
+// until one answers, or all announced to at once with `announce_to_all`. Every tracker is also
+// scraped in the background after each announce, and among those that never answered, the ones
+// with bigger swarms are tried first.
+pub(crate) struct TrackerSession {
+    md: Arc<Metadata>,
+    stats: Arc<TransferStats>,
+    tiers: TrackerTiers,
+    announce_to_all: bool,
+    tx_admin_message: mpsc::Sender<AdminMessage>,
+    tx_status: watch::Sender<Vec<TrackerStatus>>,
+    rx: mpsc::Receiver<TrackerMessage>,
+    tx_scrape: mpsc::Sender<Vec<(String, Option<ScrapeInfo>)>>,
+    rx_scrape: mpsc::Receiver<Vec<(String, Option<ScrapeInfo>)>>,
+    next_announce: time::Instant,
+}
+
+impl TrackerSession {
+    // Keeps announcing in the background from when the handle is told to start, without waiting
+    // for any tracker to answer.
+    pub(crate) fn start(
+        md: Arc<Metadata>,
+        stats: Arc<TransferStats>,
+        tx_admin_message: mpsc::Sender<AdminMessage>,
+        announce_to_all: bool,
+    ) -> TrackerHandle {
+        let (tx, rx) = mpsc::channel(8);
+        let (tx_scrape, rx_scrape) = mpsc::channel(1);
+        let trackers = md.trackers();
+        let statuses = trackers
+            .iter()
+            .flatten()
+            .map(|url| TrackerStatus {
+                url: url.clone(),
+                state: TrackerState::NotContacted,
+                last_error: None,
+                warning: None,
+                swarm: None,
+                next_announce: None,
+            })
+            .collect();
+        let (tx_status, rx_status) = watch::channel(statuses);
+        let session = TrackerSession {
+            md,
+            stats,
+            tiers: TrackerTiers::new(trackers),
+            announce_to_all,
+            tx_admin_message,
+            tx_status,
+            rx,
+            tx_scrape,
+            rx_scrape,
+            next_announce: time::Instant::now(),
+        };
+
+        tokio::spawn(session.run());
+        TrackerHandle { tx, rx_status }
+    }
+
+    // Nothing is announced until the handle says to start, when `started` goes out first. If no
+    // tracker answers, it is retried until one does.
+    async fn run(mut self) {
+        // Set once the handle says to start.
+        let mut ready = false;
+        // Events that failed to reach a tracker are sent again with the next announce.
+        let mut started = false;
+        let mut completed = false;
+
+        loop {
+            let event = tokio::select! {
+                _ = time::sleep_until(self.next_announce), if ready => match (started, completed) {
+                    (false, _) => AnnounceEvent::Started,
+                    (true, true) => AnnounceEvent::Completed,
+                    (true, false) => AnnounceEvent::None,
+                },
+                msg = self.rx.recv() => match msg {
+                    Some(TrackerMessage::Start) => {
+                        if !ready {
+                            ready = true;
+                            self.next_announce = time::Instant::now();
+                        }
+                        continue;
+                    }
+                    Some(TrackerMessage::Completed) if started => AnnounceEvent::Completed,
+                    Some(TrackerMessage::Completed) => {
+                        completed = true;
+                        continue;
+                    }
+                    Some(TrackerMessage::Stop(ack)) => {
+                        if started {
+                            self.announce(AnnounceEvent::Stopped).await;
+                        }
+                        let _ = ack.send(());
+                        return;
+                    }
+                    None => return,
+                },
+                Some(results) = self.rx_scrape.recv() => {
+                    self.apply_scrape(results);
+                    continue;
+                }
+            };
+
+            let peers = self.announce(event).await;
+            self.spawn_scrape();
+            let answered = peers.is_some();
+            self.pass_on(peers.unwrap_or_default()).await;
+            match event {
+                AnnounceEvent::Started => {
+                    started = answered;
+                    // A torrent that completed before any tracker answered says so right away.
+                    if started && completed {
+                        self.next_announce = time::Instant::now();
+                    }
+                }
+                AnnounceEvent::Completed => completed = !answered,
+                _ => {}
+            }
+        }
+    }
+
+    // Returns the peers from every tracker that answered, if any did. Announcing to all
+    // trackers waits for the slowest interval among them, so none is announced to too often.
+    async fn announce(&mut self, event: AnnounceEvent) -> Option<Vec<PeerInfo>> {
+        let results = if self.announce_to_all {
+            self.announce_all(event).await
+        } else {
+            self.announce_tiers(event).await
+        };
+
+        let mut peers: Option<Vec<PeerInfo>> = None;
+        let mut interval = None;
+        for (_, res) in &results {
+            if let Ok(info) = res {
+                interval = interval.max(Some(announce_interval(info)));
+                peers
+                    .get_or_insert_with(Vec::new)
+                    .extend(info.peers.iter().cloned());
+            }
+        }
+        self.next_announce = time::Instant::now() + interval.unwrap_or(RETRY_INTERVAL);
+        self.update_statuses(event, &results);
+
+        let mut seen = HashSet::new();
+        peers.map(|mut peers| {
+            peers.retain(|peer| seen.insert(peer.to_string()));
+            peers
+        })
+    }
+
+    // Tries trackers in tier order up to the first that answers, which is promoted.
+    async fn announce_tiers(
+        &mut self,
+        event: AnnounceEvent,
+    ) -> Vec<(String, Result<TrackerInfo, String>)> {
+        let mut results = Vec::new();
+        for (tier, index, url) in self.tiers.order() {
+            let res = TrackerAcquirer::announce(&url, &self.md, &self.stats, event)
+                .await
+                .map_err(|e| e.to_string());
+            let answered = res.is_ok();
+            results.push((url, res));
+            if answered {
+                self.tiers.promote(tier, index);
+                break;
+            }
+        }
+        results
+    }
+
+    async fn announce_all(
+        &self,
+        event: AnnounceEvent,
+    ) -> Vec<(String, Result<TrackerInfo, String>)> {
+        let urls = self.tiers.order().into_iter().map(|(_, _, url)| url);
+        all_trackers(urls, |url| {
+            let md = self.md.clone();
+            let stats = self.stats.clone();
+            async move {
+                TrackerAcquirer::announce(&url, &md, &stats, event)
+                    .await
+                    .map_err(|e| e.to_string())
+            }
+        })
+        .await
+    }
+
+    // Scrapes every tracker at once, without holding up announces. Results come back through
+    // `rx_scrape`, and a scrape still running when the next is due is left to finish first.
+    fn spawn_scrape(&self) {
+        let tx_scrape = match self.tx_scrape.clone().try_reserve_owned() {
+            Ok(v) => v,
+            Err(_) => return,
+        };
+        let urls: Vec<String> = self
+            .tiers
+            .order()
+            .into_iter()
+            .map(|(_, _, url)| url)
+            .collect();
+        let info_hash = self.md.info_hash.clone();
+
+        tokio::spawn(async move {
+            let results = all_trackers(urls, |url| {
+                let info_hash = info_hash.clone();
+                async move {
+                    let res = TrackerAcquirer::scrape(&url, std::slice::from_ref(&info_hash)).await;
+                    res.ok().and_then(|mut files| files.remove(&info_hash))
+                }
+            })
+            .await;
+            tx_scrape.send(results);
+        });
+    }
+
+    fn apply_scrape(&mut self, results: Vec<(String, Option<ScrapeInfo>)>) {
+        let swarm_size = |url: &str| {
+            results
+                .iter()
+                .find(|(u, _)| u == url)
+                .and_then(|(_, swarm)| swarm.as_ref())
+                .map(ScrapeInfo::swarm_size)
+        };
+        self.tiers.rank(swarm_size);
+        self.tx_status.send_modify(|statuses| {
+            for status in statuses.iter_mut() {
+                if let Some((_, swarm)) = results.iter().find(|(url, _)| *url == status.url) {
+                    status.swarm = *swarm;
+                }
+            }
+        });
+    }
+
+    fn update_statuses(
+        &self,
+        event: AnnounceEvent,
+        results: &[(String, Result<TrackerInfo, String>)],
+    ) {
+        let next_announce = match event {
+            AnnounceEvent::Stopped => None,
+            _ => Some(self.next_announce.into_std()),
+        };
+        self.tx_status.send_modify(|statuses| {
+            for status in statuses.iter_mut() {
+                status.next_announce = next_announce;
+                let res = match results.iter().find(|(url, _)| *url == status.url) {
+                    Some((_, res)) => res,
+                    None => continue,
+                };
+                match res {
+                    Ok(info) => {
+                        status.state = TrackerState::Working;
+                        status.last_error = None;
+                        status.warning = info.warning_message.clone();
+                    }
+                    Err(e) => {
+                        status.state = TrackerState::Failing;
+                        status.last_error = Some(e.clone());
+                    }
+                }
+            }
+        });
+    }
+
+    async fn pass_on(&self, peers: Vec<PeerInfo>) {
+        if peers.is_empty() {
+            return;
+        }
+        let _ = self
+            .tx_admin_message
+            .send(AdminMessage::NewPeers(NewPeers { peers }))
+            .await;
+    }
+}
+
+// Runs `f` for every tracker at once, collecting the results as they come in.
+async fn all_trackers<T, F, Fut>(urls: impl IntoIterator<Item = String>, f: F) -> Vec<(String, T)>
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = T> + Send + 'static,
+    T: Send + 'static,
+{
+    let mut tasks = JoinSet::new();
+    for url in urls {
+        let fut = f(url.clone());
+        tasks.spawn(async move { (url, fut.await) });
+    }
+
+    let mut results = Vec::new();
+    while let Some(res) = tasks.join_next().await {
+        if let Ok(res) = res {
+            results.push(res);
+        }
+    }
+    results
+}
+
+// The tracker's `min interval` is a hard floor, while `interval` is only what it would like.
+pub(crate) fn announce_interval(info: &TrackerInfo) -> Duration {
+    let secs = info.interval.max(info.min_interval.unwrap_or(0));
+    Duration::from_secs(secs.into()).max(MIN_ANNOUNCE_INTERVAL)
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+    use crate::storage::test::two_file_torrent;
+
+    // An HTTP tracker that passes on the event of every announce, answering with one peer, or
+    // with a failure while `failing` is set. Scrapes always fail.
+    async fn stub_tracker(failing: Arc<AtomicBool>) -> (String, mpsc::UnboundedReceiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut head = Vec::new();
+                let mut buf = [0; 1024];
+                while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+                    let len = stream.read(&mut buf).await.unwrap();
+                    if len == 0 {
+                        break;
+                    }
+                    head.extend(&buf[..len]);
+                }
+
+                let head = String::from_utf8_lossy(&head).into_owned();
+                let target = head.split(' ').nth(1).unwrap_or_default();
+                let body: &[u8] = if !target.starts_with("/announce") {
+                    b"d14:failure reason2:noe"
+                } else {
+                    let event = target
+                        .split(['?', '&'])
+                        .find_map(|param| param.strip_prefix("event="))
+                        .unwrap_or_default();
+                    let body = if failing.load(Ordering::Relaxed) {
+                        &b"d14:failure reason4:downe"[..]
+                    } else {
+                        b"d8:intervali1800e5:peers6:\x01\x02\x03\x04\x1a\xe1e"
+                    };
+                    let _ = tx.send(String::from(event));
+                    body
+                };
+                let reply = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                );
+                let _ = stream.write_all(&[reply.as_bytes(), body].concat()).await;
+            }
+        });
+        (url, rx)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn announces_through_the_torrent_lifecycle() {
+        let failing = Arc::new(AtomicBool::new(true));
+        let (url, mut events) = stub_tracker(failing.clone()).await;
+        let (mut md, _) = two_file_torrent();
+        md.announce = Some(url);
+        let stats = Arc::new(TransferStats::new(0, 0, 50_000));
+        let (tx_admin_message, mut rx_admin_message) = mpsc::channel(8);
+        let tracker = TrackerSession::start(Arc::new(md), stats, tx_admin_message, false);
+
+        // Nothing is announced before the session is told to start.
+        assert!(time::timeout(RETRY_INTERVAL, events.recv()).await.is_err());
+        let start = time::Instant::now();
+        tracker.start();
+
+        // `started` goes out first, and again after a while until a tracker answers.
+        assert_eq!(events.recv().await.unwrap(), "started");
+        failing.store(false, Ordering::Relaxed);
+        assert_eq!(events.recv().await.unwrap(), "started");
+        assert!(start.elapsed() >= RETRY_INTERVAL);
+        let Some(AdminMessage::NewPeers(NewPeers { peers })) = rx_admin_message.recv().await else {
+            panic!("expected new peers");
+        };
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].to_string(), "1.2.3.4:6881");
+
+        // Then again at the interval the tracker asked for.
+        assert_eq!(events.recv().await.unwrap(), "");
+        assert!(start.elapsed() >= RETRY_INTERVAL + Duration::from_secs(1800));
+
+        // Completion is sent straight away, again with the next announce if it fails, and only
+        // until a tracker has been told.
+        failing.store(true, Ordering::Relaxed);
+        tracker.completed();
+        assert_eq!(events.recv().await.unwrap(), "completed");
+        failing.store(false, Ordering::Relaxed);
+        assert_eq!(events.recv().await.unwrap(), "completed");
+        assert_eq!(events.recv().await.unwrap(), "");
+
+        tracker.stop().await;
+        assert_eq!(events.recv().await.unwrap(), "stopped");
+        let statuses = tracker.statuses().borrow().clone();
+        assert_eq!(statuses[0].state, TrackerState::Working);
+        assert_eq!(statuses[0].next_announce, None);
+    }
+
+    fn info(interval: u32, min_interval: Option<u32>) -> TrackerInfo {
+        TrackerInfo {
+            interval,
+            min_interval,
+            tracker_id: None,
+            peers: Vec::new(),
+            failure_reason: None,
+            warning_message: None,
+            complete: None,
+            incomplete: None,
+        }
+    }
+
+    #[test]
+    fn announce_interval_respects_min_interval() {
+        assert_eq!(
+            announce_interval(&info(1800, None)),
+            Duration::from_secs(1800)
+        );
+        assert_eq!(
+            announce_interval(&info(900, Some(1200))),
+            Duration::from_secs(1200)
+        );
+        assert_eq!(announce_interval(&info(5, None)), MIN_ANNOUNCE_INTERVAL);
+    }
+}
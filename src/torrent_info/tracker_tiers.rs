@@ -0,0 +1,148 @@
+use std::{cmp::Reverse, collections::HashSet};
+
+use rand::seq::SliceRandom;
+
+// A torrent's trackers in the order they are tried, as described in BEP 12. Each tier is
+// shuffled once, and a tracker that answers moves to the front of its tier so it is tried first
+// next time. Later tiers are only used when every tracker in the earlier ones fails.
+#[derive(Debug)]
+pub(crate) struct TrackerTiers {
+    tiers: Vec<Vec<String>>,
+    // Trackers that have answered an announce.
+    answered: HashSet<String>,
+}
+
+impl TrackerTiers {
+    pub(crate) fn new(tiers: Vec<Vec<String>>) -> Self {
+        let mut rng = rand::thread_rng();
+        let tiers = tiers
+            .into_iter()
+            .filter(|tier| !tier.is_empty())
+            .map(|mut tier| {
+                tier.shuffle(&mut rng);
+                tier
+            })
+            .collect();
+
+        TrackerTiers {
+            tiers,
+            answered: HashSet::new(),
+        }
+    }
+
+    // Every tracker with its tier and position in it, in the order they should be tried.
+    pub(crate) fn order(&self) -> Vec<(usize, usize, String)> {
+        self.tiers
+            .iter()
+            .enumerate()
+            .flat_map(|(tier, urls)| {
+                urls.iter()
+                    .enumerate()
+                    .map(move |(index, url)| (tier, index, url.clone()))
+            })
+            .collect()
+    }
+
+    // Orders the trackers of each tier that have never answered by swarm size, biggest first,
+    // behind those that have, which keep their order so the last to answer stays first. Trackers
+    // with no known swarm count as one peer, so they still go ahead of swarms known to be empty.
+    pub(crate) fn rank(&mut self, swarm_size: impl Fn(&str) -> Option<u32>) {
+        for urls in self.tiers.iter_mut() {
+            urls.sort_by_key(|url| {
+                if self.answered.contains(url) {
+                    None
+                } else {
+                    Some(Reverse(swarm_size(url).unwrap_or(1)))
+                }
+            });
+        }
+    }
+
+    pub(crate) fn promote(&mut self, tier: usize, index: usize) {
+        if let Some(urls) = self.tiers.get_mut(tier) {
+            if index < urls.len() {
+                let url = urls.remove(index);
+                self.answered.insert(url.clone());
+                urls.insert(0, url);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn urls(tiers: &TrackerTiers) -> Vec<String> {
+        tiers.order().into_iter().map(|(_, _, url)| url).collect()
+    }
+
+    #[test]
+    fn tiers_are_shuffled_within_and_promote_working_trackers() {
+        let mut tiers = TrackerTiers::new(vec![
+            vec![String::from("a"), String::from("b"), String::from("c")],
+            vec![],
+            vec![String::from("d")],
+        ]);
+
+        let order = urls(&tiers);
+        assert_eq!(order.len(), 4);
+        let mut first_tier = order[..3].to_vec();
+        first_tier.sort();
+        assert_eq!(first_tier, ["a", "b", "c"]);
+        assert_eq!(order[3], "d");
+
+        let (tier, index, url) = tiers.order()[2].clone();
+        tiers.promote(tier, index);
+        assert_eq!(urls(&tiers)[0], url);
+        assert_eq!(tiers.order()[3], (1, 0, String::from("d")));
+    }
+
+    #[test]
+    fn bigger_swarms_are_tried_first() {
+        let mut tiers = TrackerTiers {
+            tiers: vec![vec![
+                String::from("empty"),
+                String::from("unknown"),
+                String::from("big"),
+                String::from("small"),
+            ]],
+            answered: HashSet::new(),
+        };
+        tiers.rank(|url| match url {
+            "empty" => Some(0),
+            "big" => Some(40),
+            "small" => Some(3),
+            _ => None,
+        });
+        assert_eq!(urls(&tiers), ["big", "small", "unknown", "empty"]);
+    }
+
+    #[test]
+    fn trackers_that_answered_stay_ahead_of_bigger_swarms() {
+        let mut tiers = TrackerTiers {
+            tiers: vec![vec![
+                String::from("dead"),
+                String::from("big"),
+                String::from("no-scrape"),
+                String::from("empty"),
+            ]],
+            answered: HashSet::new(),
+        };
+        let swarm_size = |url: &str| match url {
+            "big" => Some(40),
+            "empty" => Some(0),
+            _ => None,
+        };
+
+        tiers.promote(0, 3);
+        tiers.promote(0, 3);
+        assert_eq!(urls(&tiers), ["no-scrape", "empty", "dead", "big"]);
+        tiers.rank(swarm_size);
+        assert_eq!(urls(&tiers), ["no-scrape", "empty", "big", "dead"]);
+
+        // Order stays the same from one scrape to the next.
+        tiers.rank(swarm_size);
+        assert_eq!(urls(&tiers), ["no-scrape", "empty", "big", "dead"]);
+    }
+}
@@ -0,0 +1,374 @@
+use std::{
+    collections::HashMap,
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    sync::{Arc, Mutex, Weak},
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    net::{self, UdpSocket},
+    sync::{oneshot, OnceCell},
+    time,
+};
+
+pub(crate) const ACTION_CONNECT: u32 = 0;
+pub(crate) const ACTION_ANNOUNCE: u32 = 1;
+pub(crate) const ACTION_SCRAPE: u32 = 2;
+pub(crate) const ACTION_ERROR: u32 = 3;
+
+pub(crate) const PROTOCOL_ID: u64 = 0x41727101980;
+// Connection IDs may be reused for this long after they were handed out.
+const CONNECTION_ID_LIFETIME: Duration = Duration::from_secs(60);
+// Requests are sent again after 15·2ⁿ seconds without a reply, up to n = 8 as in BEP 15.
+const BASE_TIMEOUT: Duration = Duration::from_secs(15);
+const MAX_RETRIES: u32 = 8;
+
+static SHARED: OnceCell<Arc<UdpTrackerClient>> = OnceCell::const_new();
+
+// A UDP tracker as given by its announce URL.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct UdpTracker {
+    pub addr: SocketAddr,
+    // The path and query of the URL, passed on as described in BEP 41.
+    pub url_data: String,
+}
+
+impl UdpTracker {
+    pub(crate) async fn resolve(tracker_url: &str) -> io::Result<Self> {
+        let invalid = || {
+            io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid tracker url: {tracker_url}"),
+            )
+        };
+        let url = url::Url::parse(tracker_url).map_err(|_| invalid())?;
+        let host = url.host_str().ok_or_else(invalid)?;
+        let port = url.port().ok_or_else(invalid)?;
+
+        // The shared socket only speaks IPv4.
+        let addr = net::lookup_host((host, port))
+            .await?
+            .find(SocketAddr::is_ipv4)
+            .ok_or_else(|| {
+                io::Error::new(
+                    ErrorKind::AddrNotAvailable,
+                    format!("No IPv4 address for {host}"),
+                )
+            })?;
+
+        // Taken as written rather than from the parsed URL, which normalises the path and
+        // escaping that private trackers may rely on.
+        let rest = tracker_url.split_once("://").map_or("", |(_, rest)| rest);
+        let rest = rest.split('#').next().unwrap_or_default();
+        let mut url_data = match rest.find(['/', '?']) {
+            Some(i) => String::from(&rest[i..]),
+            None => String::new(),
+        };
+        if url_data == "/" {
+            url_data.clear();
+        }
+
+        Ok(UdpTracker { addr, url_data })
+    }
+}
+
+// The URLData option of BEP 41, split into chunks of at most 255 bytes and ended with
+// EndOfOptions. Trackers that don't know about options ignore the extra bytes.
+pub(crate) fn url_data_option(url_data: &str) -> Vec<u8> {
+    let mut option = Vec::new();
+    for chunk in url_data.as_bytes().chunks(255) {
+        option.push(0x2);
+        option.push(chunk.len() as u8);
+        option.extend(chunk);
+    }
+    if !option.is_empty() {
+        option.push(0x0);
+    }
+    option
+}
+
+struct Pending {
+    addr: SocketAddr,
+    tx: oneshot::Sender<Vec<u8>>,
+}
+
+// Talks to every UDP tracker through one socket, telling replies apart by transaction ID.
+pub(crate) struct UdpTrackerClient {
+    socket: Arc<UdpSocket>,
+    pending: Mutex<HashMap<u32, Pending>>,
+    // Connection IDs by tracker, with when they were handed out.
+    connections: Mutex<HashMap<SocketAddr, (u64, Instant)>>,
+}
+
+impl UdpTrackerClient {
+    // The client shared by all torrents, bound on first use.
+    pub(crate) async fn shared() -> io::Result<Arc<Self>> {
+        SHARED
+            .get_or_try_init(|| Self::bind("0.0.0.0:0"))
+            .await
+            .cloned()
+    }
+
+    pub(crate) async fn bind(addr: &str) -> io::Result<Arc<Self>> {
+        let client = Arc::new(UdpTrackerClient {
+            socket: Arc::new(UdpSocket::bind(addr).await?),
+            pending: Mutex::new(HashMap::new()),
+            connections: Mutex::new(HashMap::new()),
+        });
+        tokio::spawn(receive(Arc::downgrade(&client)));
+        Ok(client)
+    }
+
+    // Sends the message built by `msg` from a connection ID and transaction ID, and returns the
+    // reply. Connects first unless a connection ID for the tracker is still fresh.
+    pub(crate) async fn request(
+        &self,
+        addr: SocketAddr,
+        action: u32,
+        msg: impl Fn(u64, u32) -> Vec<u8>,
+    ) -> io::Result<Vec<u8>> {
+        for n in 0..=MAX_RETRIES {
+            let timeout = BASE_TIMEOUT * 2u32.pow(n);
+            let conn_id = match self.connection_id(addr) {
+                Some(v) => v,
+                None => {
+                    let reply = match self
+                        .exchange(addr, ACTION_CONNECT, timeout, connect_msg)
+                        .await
+                    {
+                        Err(e) if e.kind() == ErrorKind::TimedOut => continue,
+                        res => res?,
+                    };
+                    let conn_id = u64::from_be_bytes(reply[8..16].try_into().unwrap());
+                    self.connections
+                        .lock()
+                        .unwrap()
+                        .insert(addr, (conn_id, Instant::now()));
+                    conn_id
+                }
+            };
+
+            match self
+                .exchange(addr, action, timeout, |trans_id| msg(conn_id, trans_id))
+                .await
+            {
+                Err(e) if e.kind() == ErrorKind::TimedOut => continue,
+                res => return res,
+            }
+        }
+
+        Err(io::Error::new(
+            ErrorKind::TimedOut,
+            format!("No reply from tracker at {addr}"),
+        ))
+    }
+
+    fn connection_id(&self, addr: SocketAddr) -> Option<u64> {
+        let mut connections = self.connections.lock().unwrap();
+        match connections.get(&addr) {
+            Some((conn_id, at)) if at.elapsed() < CONNECTION_ID_LIFETIME => Some(*conn_id),
+            Some(_) => {
+                connections.remove(&addr);
+                None
+            }
+            None => None,
+        }
+    }
+
+    // Sends a single request and waits for its reply, which is checked against the action.
+    async fn exchange(
+        &self,
+        addr: SocketAddr,
+        action: u32,
+        timeout: Duration,
+        msg: impl Fn(u32) -> Vec<u8>,
+    ) -> io::Result<Vec<u8>> {
+        let (tx, rx) = oneshot::channel();
+        let trans_id = {
+            let mut pending = self.pending.lock().unwrap();
+            let mut trans_id: u32 = rand::random();
+            while pending.contains_key(&trans_id) {
+                trans_id = rand::random();
+            }
+            pending.insert(trans_id, Pending { addr, tx });
+            trans_id
+        };
+
+        let res = match self.socket.send_to(&msg(trans_id), addr).await {
+            Ok(_) => time::timeout(timeout, rx).await,
+            Err(e) => {
+                self.pending.lock().unwrap().remove(&trans_id);
+                return Err(e);
+            }
+        };
+        let reply = match res {
+            Ok(Ok(reply)) => reply,
+            _ => {
+                self.pending.lock().unwrap().remove(&trans_id);
+                return Err(io::Error::new(
+                    ErrorKind::TimedOut,
+                    format!("Tracker at {addr} didn't reply in {}s", timeout.as_secs()),
+                ));
+            }
+        };
+
+        check_reply(reply, action)
+    }
+}
+
+// Hands replies to the requests waiting for them, for as long as the client exists.
+async fn receive(client: Weak<UdpTrackerClient>) {
+    let socket = match client.upgrade() {
+        Some(client) => client.socket.clone(),
+        None => return,
+    };
+    let mut buf = [0; 2048];
+
+    loop {
+        let (len, from) = match socket.recv_from(&mut buf).await {
+            Ok(v) => v,
+            Err(_) => continue,
+        };
+        let client = match client.upgrade() {
+            Some(v) => v,
+            None => return,
+        };
+        if len < 8 {
+            continue;
+        }
+
+        let trans_id = u32::from_be_bytes(buf[4..8].try_into().unwrap());
+        let mut pending = client.pending.lock().unwrap();
+        // Replies from anywhere but the tracker asked are ignored.
+        if pending.get(&trans_id).is_some_and(|p| p.addr == from) {
+            let waiting = pending.remove(&trans_id).unwrap();
+            let _ = waiting.tx.send(buf[..len].to_vec());
+        }
+    }
+}
+
+fn connect_msg(trans_id: u32) -> Vec<u8> {
+    [
+        PROTOCOL_ID.to_be_bytes().to_vec(),
+        ACTION_CONNECT.to_be_bytes().to_vec(),
+        trans_id.to_be_bytes().to_vec(),
+    ]
+    .concat()
+}
+
+// Turns error replies into errors, and makes sure other replies are long enough for their
+// action.
+fn check_reply(reply: Vec<u8>, action: u32) -> io::Result<Vec<u8>> {
+    let reply_action = u32::from_be_bytes(reply[..4].try_into().unwrap());
+    if reply_action == ACTION_ERROR {
+        let msg = String::from_utf8_lossy(&reply[8..]);
+        return Err(io::Error::other(format!("Tracker error: {msg}")));
+    }
+
+    let min_len = match action {
+        ACTION_CONNECT => 16,
+        ACTION_ANNOUNCE => 20,
+        _ => 8,
+    };
+    if reply_action != action || reply.len() < min_len {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "Invalid response from tracker",
+        ));
+    }
+    Ok(reply)
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+
+    #[test]
+    fn url_data_is_split_into_options() {
+        assert!(url_data_option("").is_empty());
+        assert_eq!(url_data_option("/a?b"), b"\x02\x04/a?b\x00");
+
+        let long = "x".repeat(300);
+        let option = url_data_option(&long);
+        assert_eq!(option.len(), 2 + 255 + 2 + 45 + 1);
+        assert_eq!(&option[..2], &[2, 255]);
+        assert_eq!(&option[257..259], &[2, 45]);
+    }
+
+    #[tokio::test]
+    async fn resolves_tracker_urls() {
+        let tracker = UdpTracker::resolve("udp://127.0.0.1:6969/announce?key=1")
+            .await
+            .unwrap();
+        assert_eq!(tracker.addr, "127.0.0.1:6969".parse().unwrap());
+        assert_eq!(tracker.url_data, "/announce?key=1");
+
+        let tracker = UdpTracker::resolve("udp://127.0.0.1:6969/a/../announce?key='x'%7e#frag")
+            .await
+            .unwrap();
+        assert_eq!(tracker.url_data, "/a/../announce?key='x'%7e");
+
+        let tracker = UdpTracker::resolve("udp://127.0.0.1:6969").await.unwrap();
+        assert_eq!(tracker.url_data, "");
+        assert!(UdpTracker::resolve("udp://127.0.0.1").await.is_err());
+    }
+
+    // Answers connects with a fixed connection ID, and announces with an error unless they
+    // carry it.
+    async fn fake_tracker(connects: Arc<AtomicU32>) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 1024];
+            loop {
+                let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+                let msg = &buf[..len];
+                let trans_id = &msg[12..16];
+                let reply = if msg[8..12] == ACTION_CONNECT.to_be_bytes() {
+                    connects.fetch_add(1, Ordering::Relaxed);
+                    [&0u32.to_be_bytes()[..], trans_id, &77u64.to_be_bytes()].concat()
+                } else if msg[..8] == 77u64.to_be_bytes() {
+                    [&1u32.to_be_bytes()[..], trans_id, &[0; 12]].concat()
+                } else {
+                    [&3u32.to_be_bytes()[..], trans_id, b"bad connection id"].concat()
+                };
+                socket.send_to(&reply, from).await.unwrap();
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn connection_ids_are_reused_and_errors_reported() {
+        let connects = Arc::new(AtomicU32::new(0));
+        let addr = fake_tracker(connects.clone()).await;
+        let client = UdpTrackerClient::bind("127.0.0.1:0").await.unwrap();
+
+        let announce = |conn_id: u64, trans_id: u32| {
+            [
+                &conn_id.to_be_bytes()[..],
+                &ACTION_ANNOUNCE.to_be_bytes(),
+                &trans_id.to_be_bytes(),
+            ]
+            .concat()
+        };
+        for _ in 0..2 {
+            let reply = client
+                .request(addr, ACTION_ANNOUNCE, announce)
+                .await
+                .unwrap();
+            assert_eq!(reply.len(), 20);
+        }
+        assert_eq!(connects.load(Ordering::Relaxed), 1);
+
+        let wrong_id = |_: u64, trans_id: u32| announce(1, trans_id);
+        let err = client
+            .request(addr, ACTION_ANNOUNCE, wrong_id)
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Tracker error: bad connection id");
+    }
+}
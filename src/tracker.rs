@@ -0,0 +1,163 @@
+pub mod http;
+pub mod peer_store;
+pub mod udp;
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use crate::{
+    config::TrackerOptions,
+    parser::{scrape_info::ScrapeInfo, tracker_info::TrackerInfo},
+};
+
+use peer_store::{AnnounceRequest, PeerStore};
+
+// What a request to the tracker asks for, going by the last part of its path.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Route {
+    Announce,
+    Scrape,
+    Unknown,
+}
+
+// A tracker for private swarms, shared by its HTTP and UDP front ends.
+pub(crate) struct Tracker {
+    store: Mutex<PeerStore>,
+    // Only these torrents are tracked, unless empty.
+    allowed: HashSet<Vec<u8>>,
+    // Requests must carry one of these as the first part of their path, unless empty.
+    passkeys: HashSet<String>,
+}
+
+impl Tracker {
+    pub(crate) fn new(opts: &TrackerOptions) -> Arc<Self> {
+        Arc::new(Tracker {
+            store: Mutex::new(PeerStore::new()),
+            allowed: opts.allow.iter().cloned().collect(),
+            passkeys: opts.passkeys.iter().cloned().collect(),
+        })
+    }
+
+    // Checks the passkey in a request path such as `/<passkey>/announce`, and works out what the
+    // request is for.
+    pub(crate) fn route(&self, path: &str) -> Result<Route, String> {
+        let parts: Vec<&str> = path.split('/').filter(|part| !part.is_empty()).collect();
+        if !self.passkeys.is_empty() {
+            match parts.first() {
+                Some(key) if parts.len() > 1 && self.passkeys.contains(*key) => {}
+                _ => return Err(String::from("Invalid passkey")),
+            }
+        }
+
+        match parts.last() {
+            Some(name) if name.starts_with("announce") => Ok(Route::Announce),
+            Some(name) if name.starts_with("scrape") => Ok(Route::Scrape),
+            // UDP requests carry no path unless the client supports BEP 41.
+            None => Ok(Route::Announce),
+            Some(_) => Ok(Route::Unknown),
+        }
+    }
+
+    pub(crate) fn announce(&self, req: &AnnounceRequest) -> Result<TrackerInfo, String> {
+        if !self.is_allowed(&req.info_hash) {
+            return Err(String::from("Torrent not allowed on this tracker"));
+        }
+        Ok(self.store.lock().unwrap().announce(req, Instant::now()))
+    }
+
+    // Torrents that aren't tracked are left out.
+    pub(crate) fn scrape(&self, info_hashes: &[Vec<u8>]) -> HashMap<Vec<u8>, ScrapeInfo> {
+        let mut store = self.store.lock().unwrap();
+        let now = Instant::now();
+        info_hashes
+            .iter()
+            .filter(|info_hash| self.is_allowed(info_hash))
+            .map(|info_hash| (info_hash.clone(), store.scrape(info_hash, now)))
+            .collect()
+    }
+
+    fn is_allowed(&self, info_hash: &[u8]) -> bool {
+        self.allowed.is_empty() || self.allowed.contains(info_hash)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::net::{TcpListener, UdpSocket};
+
+    use super::*;
+    use crate::{
+        client::stats::TransferStats,
+        storage::test::two_file_torrent,
+        torrent_info::tracker_acquirer::{AnnounceEvent, TrackerAcquirer},
+    };
+
+    async fn start(opts: TrackerOptions) -> (String, String) {
+        let tracker = Tracker::new(&opts);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let http = listener.local_addr().unwrap();
+        let udp = socket.local_addr().unwrap();
+        tokio::spawn(http::serve(tracker.clone(), listener));
+        tokio::spawn(udp::serve(tracker, socket));
+        (format!("http://{http}"), format!("udp://{udp}"))
+    }
+
+    #[test]
+    fn routes_check_passkeys() {
+        let open = Tracker::new(&TrackerOptions::default());
+        assert_eq!(open.route("/announce"), Ok(Route::Announce));
+        assert_eq!(open.route("/scrape.php"), Ok(Route::Scrape));
+        assert_eq!(open.route(""), Ok(Route::Announce));
+        assert_eq!(open.route("/other"), Ok(Route::Unknown));
+
+        let private = Tracker::new(&TrackerOptions {
+            passkeys: vec![String::from("key")],
+            ..Default::default()
+        });
+        assert_eq!(private.route("/key/announce"), Ok(Route::Announce));
+        assert!(private.route("/announce").is_err());
+        assert!(private.route("/nope/announce").is_err());
+    }
+
+    #[tokio::test]
+    async fn announces_and_scrapes_over_http_and_udp() {
+        let (md, _) = two_file_torrent();
+        let (http, udp) = start(TrackerOptions {
+            allow: vec![md.info_hash.clone()],
+            passkeys: vec![String::from("key")],
+            ..Default::default()
+        })
+        .await;
+        let stats = TransferStats::new(0, 0, 100);
+
+        for base in [&http, &udp] {
+            let url = format!("{base}/key/announce");
+            let info = TrackerAcquirer::announce(&url, &md, &stats, AnnounceEvent::Started)
+                .await
+                .unwrap();
+            assert_eq!(info.interval, peer_store::ANNOUNCE_INTERVAL);
+            assert_eq!(info.incomplete, Some(1));
+
+            let files = TrackerAcquirer::scrape(&url, std::slice::from_ref(&md.info_hash))
+                .await
+                .unwrap();
+            assert_eq!(files[&md.info_hash].incomplete, 1);
+
+            let url = format!("{base}/wrong/announce");
+            let res = TrackerAcquirer::announce(&url, &md, &stats, AnnounceEvent::None).await;
+            let err = res.err().unwrap().to_string();
+            assert!(err.contains("Invalid passkey"), "{err}");
+        }
+
+        let mut other = two_file_torrent().0;
+        other.info_hash = vec![0; 20];
+        let url = format!("{udp}/key/announce");
+        let res = TrackerAcquirer::announce(&url, &other, &stats, AnnounceEvent::None).await;
+        let err = res.err().unwrap().to_string();
+        assert!(err.contains("not allowed"), "{err}");
+    }
+}
@@ -0,0 +1,175 @@
+use std::{
+    io::{self, ErrorKind},
+    net::IpAddr,
+    sync::Arc,
+};
+
+use bendy::encoding::ToBencode;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+use super::{peer_store::AnnounceRequest, Route, Tracker};
+use crate::{
+    parser::{
+        scrape_info::ScrapeResponse,
+        tracker_info::{CompactTrackerInfo, TrackerInfo},
+    },
+    torrent_info::tracker_acquirer::AnnounceEvent,
+};
+
+// Requests are only ever a GET line and a few headers.
+const MAX_REQUEST_LEN: usize = 8192;
+
+// Answers announces and scrapes over HTTP until the listener fails.
+pub(crate) async fn serve(tracker: Arc<Tracker>, listener: TcpListener) -> io::Result<()> {
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let tracker = tracker.clone();
+        tokio::spawn(async move {
+            let _ = handle(&tracker, stream, addr.ip()).await;
+        });
+    }
+}
+
+async fn handle(tracker: &Tracker, mut stream: TcpStream, ip: IpAddr) -> io::Result<()> {
+    let target = read_target(&mut stream).await?;
+    let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+    let params = query_params(query);
+
+    let (status, body) = match tracker.route(path) {
+        Ok(Route::Announce) => ("200 OK", announce(tracker, &params, ip)),
+        Ok(Route::Scrape) => ("200 OK", scrape(tracker, &params)),
+        Ok(Route::Unknown) => ("404 Not Found", Vec::new()),
+        Err(reason) => ("200 OK", failure(reason)),
+    };
+
+    let head = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\
+        Connection: close\r\n\r\n",
+        body.len()
+    );
+    stream
+        .write_all(&[head.into_bytes(), body].concat())
+        .await?;
+    stream.shutdown().await
+}
+
+// Reads the request head and returns the target of its GET line.
+async fn read_target(stream: &mut TcpStream) -> io::Result<String> {
+    let invalid = || io::Error::new(ErrorKind::InvalidData, "Invalid HTTP request");
+    let mut buf = Vec::new();
+    let mut chunk = [0; 1024];
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        let len = stream.read(&mut chunk).await?;
+        if len == 0 || buf.len() + len > MAX_REQUEST_LEN {
+            return Err(invalid());
+        }
+        buf.extend(&chunk[..len]);
+    }
+
+    let line = buf.split(|b| *b == b'\r').next().ok_or_else(invalid)?;
+    let line = std::str::from_utf8(line).map_err(|_| invalid())?;
+    match line.split(' ').collect::<Vec<_>>()[..] {
+        ["GET", target, _] => Ok(String::from(target)),
+        _ => Err(invalid()),
+    }
+}
+
+// Query parameters with their values decoded, in order. Info hashes may appear more than once.
+fn query_params(query: &str) -> Vec<(String, Vec<u8>)> {
+    query
+        .split('&')
+        .filter(|param| !param.is_empty())
+        .map(|param| {
+            let (key, value) = param.split_once('=').unwrap_or((param, ""));
+            let value = urlencoding::decode_binary(value.as_bytes()).into_owned();
+            (String::from(key), value)
+        })
+        .collect()
+}
+
+fn param<'a>(params: &'a [(String, Vec<u8>)], key: &str) -> Option<&'a [u8]> {
+    params
+        .iter()
+        .find(|(k, _)| k == key)
+        .map(|(_, value)| value.as_slice())
+}
+
+fn number<T: std::str::FromStr>(params: &[(String, Vec<u8>)], key: &str) -> Option<T> {
+    std::str::from_utf8(param(params, key)?).ok()?.parse().ok()
+}
+
+// The peer's address is always taken from the connection, so peers can't announce others.
+fn announce(tracker: &Tracker, params: &[(String, Vec<u8>)], ip: IpAddr) -> Vec<u8> {
+    let info_hash = param(params, "info_hash").filter(|hash| hash.len() == 20);
+    let peer_id = param(params, "peer_id").filter(|id| id.len() == 20);
+    let (info_hash, peer_id, port) = match (info_hash, peer_id, number(params, "port")) {
+        (Some(info_hash), Some(peer_id), Some(port)) => (info_hash, peer_id, port),
+        _ => return failure(String::from("Invalid announce")),
+    };
+
+    let event = param(params, "event")
+        .map(|event| AnnounceEvent::from_param(&String::from_utf8_lossy(event)))
+        .unwrap_or(AnnounceEvent::None);
+    let req = AnnounceRequest {
+        info_hash: info_hash.to_vec(),
+        peer_id: peer_id.to_vec(),
+        ip,
+        port,
+        left: number(params, "left").unwrap_or(0),
+        event,
+        num_want: number(params, "numwant"),
+    };
+
+    match tracker.announce(&req) {
+        Ok(info) if param(params, "compact") == Some(b"1") => {
+            CompactTrackerInfo(&info).to_bencode().unwrap_or_default()
+        }
+        Ok(info) => info.to_bencode().unwrap_or_default(),
+        Err(reason) => failure(reason),
+    }
+}
+
+fn scrape(tracker: &Tracker, params: &[(String, Vec<u8>)]) -> Vec<u8> {
+    let info_hashes: Vec<Vec<u8>> = params
+        .iter()
+        .filter(|(key, value)| key == "info_hash" && value.len() == 20)
+        .map(|(_, value)| value.clone())
+        .collect();
+
+    let res = ScrapeResponse {
+        files: tracker.scrape(&info_hashes),
+        failure_reason: None,
+    };
+    res.to_bencode().unwrap_or_default()
+}
+
+fn failure(reason: String) -> Vec<u8> {
+    let info = TrackerInfo {
+        interval: 0,
+        min_interval: None,
+        tracker_id: None,
+        peers: Vec::new(),
+        failure_reason: Some(reason),
+        warning_message: None,
+        complete: None,
+        incomplete: None,
+    };
+    info.to_bencode().unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn query_params_are_decoded() {
+        let params = query_params("info_hash=%01%02a&port=6881&info_hash=b&compact");
+        assert_eq!(param(&params, "info_hash"), Some(&[1, 2, b'a'][..]));
+        assert_eq!(number::<u16>(&params, "port"), Some(6881));
+        assert_eq!(param(&params, "compact"), Some(&[][..]));
+        assert_eq!(params.iter().filter(|(k, _)| k == "info_hash").count(), 2);
+    }
+}
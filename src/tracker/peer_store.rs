@@ -0,0 +1,200 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use rand::seq::IteratorRandom;
+
+use crate::{
+    parser::{
+        scrape_info::ScrapeInfo,
+        tracker_info::{PeerInfo, TrackerInfo},
+    },
+    torrent_info::tracker_acquirer::AnnounceEvent,
+};
+
+// What clients are told about announcing again.
+pub(crate) const ANNOUNCE_INTERVAL: u32 = 300;
+pub(crate) const MIN_ANNOUNCE_INTERVAL: u32 = 60;
+// Peers are forgotten after missing this many announces in a row.
+const MISSED_ANNOUNCES: u32 = 2;
+// Peers handed out when the client doesn't say how many it wants, and at most otherwise.
+const DEFAULT_NUM_WANT: usize = 50;
+const MAX_NUM_WANT: usize = 200;
+
+// An announce as received over HTTP or UDP.
+#[derive(Debug)]
+pub(crate) struct AnnounceRequest {
+    pub info_hash: Vec<u8>,
+    pub peer_id: Vec<u8>,
+    pub ip: IpAddr,
+    pub port: u16,
+    pub left: u64,
+    pub event: AnnounceEvent,
+    pub num_want: Option<usize>,
+}
+
+struct StoredPeer {
+    ip: IpAddr,
+    port: u16,
+    left: u64,
+    seen: Instant,
+}
+
+#[derive(Default)]
+struct Swarm {
+    // By peer ID.
+    peers: HashMap<Vec<u8>, StoredPeer>,
+    // Completed events received.
+    downloaded: u32,
+}
+
+impl Swarm {
+    fn expire(&mut self, now: Instant, timeout: Duration) {
+        self.peers
+            .retain(|_, peer| now.saturating_duration_since(peer.seen) < timeout);
+    }
+
+    fn scrape_info(&self) -> ScrapeInfo {
+        let complete = self.peers.values().filter(|peer| peer.left == 0).count();
+        ScrapeInfo {
+            complete: complete as u32,
+            downloaded: self.downloaded,
+            incomplete: (self.peers.len() - complete) as u32,
+        }
+    }
+}
+
+// Every peer a tracker knows of, kept in memory by torrent.
+pub(crate) struct PeerStore {
+    swarms: HashMap<Vec<u8>, Swarm>,
+    timeout: Duration,
+}
+
+impl PeerStore {
+    pub(crate) fn new() -> Self {
+        PeerStore {
+            swarms: HashMap::new(),
+            timeout: Duration::from_secs((ANNOUNCE_INTERVAL * MISSED_ANNOUNCES).into()),
+        }
+    }
+
+    // Records the announcing peer and answers with a random selection of the others.
+    pub(crate) fn announce(&mut self, req: &AnnounceRequest, now: Instant) -> TrackerInfo {
+        let swarm = self.swarms.entry(req.info_hash.clone()).or_default();
+        swarm.expire(now, self.timeout);
+
+        match req.event {
+            AnnounceEvent::Stopped => {
+                swarm.peers.remove(&req.peer_id);
+            }
+            event => {
+                let peer = StoredPeer {
+                    ip: req.ip,
+                    port: req.port,
+                    left: req.left,
+                    seen: now,
+                };
+                let previous = swarm.peers.insert(req.peer_id.clone(), peer);
+                // Completions are counted once per peer, even if announced again.
+                let was_seed = previous.is_some_and(|p| p.left == 0);
+                if event == AnnounceEvent::Completed && !was_seed {
+                    swarm.downloaded += 1;
+                }
+            }
+        }
+
+        let num_want = req.num_want.unwrap_or(DEFAULT_NUM_WANT).min(MAX_NUM_WANT);
+        let peers = swarm
+            .peers
+            .iter()
+            .filter(|(peer_id, _)| **peer_id != req.peer_id)
+            .choose_multiple(&mut rand::thread_rng(), num_want)
+            .into_iter()
+            .map(|(_, peer)| PeerInfo {
+                peer_id: None,
+                ip: peer.ip.to_string(),
+                port: peer.port,
+            })
+            .collect();
+
+        let info = swarm.scrape_info();
+        TrackerInfo {
+            interval: ANNOUNCE_INTERVAL,
+            min_interval: Some(MIN_ANNOUNCE_INTERVAL),
+            tracker_id: None,
+            peers,
+            failure_reason: None,
+            warning_message: None,
+            complete: Some(info.complete),
+            incomplete: Some(info.incomplete),
+        }
+    }
+
+    pub(crate) fn scrape(&mut self, info_hash: &[u8], now: Instant) -> ScrapeInfo {
+        match self.swarms.get_mut(info_hash) {
+            Some(swarm) => {
+                swarm.expire(now, self.timeout);
+                swarm.scrape_info()
+            }
+            None => ScrapeInfo::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn request(peer: u8, left: u64, event: AnnounceEvent) -> AnnounceRequest {
+        AnnounceRequest {
+            info_hash: vec![1; 20],
+            peer_id: vec![peer; 20],
+            ip: IpAddr::from([10, 0, 0, peer]),
+            port: 6881,
+            left,
+            event,
+            num_want: None,
+        }
+    }
+
+    #[test]
+    fn announces_track_the_swarm() {
+        let mut store = PeerStore::new();
+        let now = Instant::now();
+
+        let info = store.announce(&request(1, 100, AnnounceEvent::Started), now);
+        assert!(info.peers.is_empty());
+        assert_eq!((info.complete, info.incomplete), (Some(0), Some(1)));
+
+        let info = store.announce(&request(2, 0, AnnounceEvent::Started), now);
+        assert_eq!(info.peers.len(), 1);
+        assert_eq!(info.peers[0].to_string(), "10.0.0.1:6881");
+
+        store.announce(&request(1, 0, AnnounceEvent::Completed), now);
+        store.announce(&request(1, 0, AnnounceEvent::Completed), now);
+        let expected = ScrapeInfo {
+            complete: 2,
+            downloaded: 1,
+            incomplete: 0,
+        };
+        assert_eq!(store.scrape(&[1; 20], now), expected);
+
+        store.announce(&request(2, 0, AnnounceEvent::Stopped), now);
+        assert_eq!(store.scrape(&[1; 20], now).complete, 1);
+        assert_eq!(store.scrape(&[2; 20], now), ScrapeInfo::default());
+    }
+
+    #[test]
+    fn silent_peers_expire() {
+        let mut store = PeerStore::new();
+        let now = Instant::now();
+        store.announce(&request(1, 100, AnnounceEvent::Started), now);
+
+        let later = now + store.timeout;
+        let info = store.announce(&request(2, 100, AnnounceEvent::Started), later);
+        assert!(info.peers.is_empty());
+        assert_eq!(store.scrape(&[1; 20], later).incomplete, 1);
+    }
+}
@@ -0,0 +1,169 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use tokio::net::UdpSocket;
+
+use super::{peer_store::AnnounceRequest, Route, Tracker};
+use crate::{
+    parser::scrape_info::ScrapeResponse,
+    torrent_info::{
+        tracker_acquirer::AnnounceEvent,
+        udp_tracker::{ACTION_ANNOUNCE, ACTION_CONNECT, ACTION_ERROR, ACTION_SCRAPE, PROTOCOL_ID},
+    },
+};
+
+// BEP 15 has clients reuse connection IDs for up to a minute, and trackers accept them for two.
+const CONNECTION_ID_LIFETIME: Duration = Duration::from_secs(120);
+// The most info hashes a scrape reply fits in a single packet.
+const MAX_SCRAPE_HASHES: usize = 74;
+// Announce requests up to the port, after which BEP 41 options may follow.
+const ANNOUNCE_LEN: usize = 98;
+
+// Answers connects, announces and scrapes over UDP until the socket fails.
+pub(crate) async fn serve(tracker: Arc<Tracker>, socket: UdpSocket) -> io::Result<()> {
+    // Connection IDs handed out, with who to and when.
+    let mut connections: HashMap<u64, (IpAddr, Instant)> = HashMap::new();
+    let mut buf = [0; 2048];
+
+    loop {
+        let (len, from) = socket.recv_from(&mut buf).await?;
+        let req = &buf[..len];
+        if len < 16 {
+            continue;
+        }
+
+        let conn_id = u64::from_be_bytes(req[..8].try_into().unwrap());
+        let action = u32::from_be_bytes(req[8..12].try_into().unwrap());
+        let trans_id = u32::from_be_bytes(req[12..16].try_into().unwrap());
+
+        let reply = if action == ACTION_CONNECT {
+            if conn_id != PROTOCOL_ID {
+                continue;
+            }
+            connections.retain(|_, (_, at)| at.elapsed() < CONNECTION_ID_LIFETIME);
+            let conn_id = rand::random();
+            connections.insert(conn_id, (from.ip(), Instant::now()));
+            [
+                ACTION_CONNECT.to_be_bytes().to_vec(),
+                trans_id.to_be_bytes().to_vec(),
+                conn_id.to_be_bytes().to_vec(),
+            ]
+            .concat()
+        } else {
+            let connected = connections
+                .get(&conn_id)
+                .is_some_and(|(ip, at)| *ip == from.ip() && at.elapsed() < CONNECTION_ID_LIFETIME);
+            if !connected {
+                error_msg(trans_id, "Connection ID expired")
+            } else if action == ACTION_ANNOUNCE {
+                announce(&tracker, req, from).unwrap_or_else(|e| error_msg(trans_id, &e))
+            } else if action == ACTION_SCRAPE {
+                scrape(&tracker, req)
+            } else {
+                error_msg(trans_id, "Unknown action")
+            }
+        };
+
+        let _ = socket.send_to(&reply, from).await;
+    }
+}
+
+// Announces are laid out as in `TrackerAcquirer::announce_msg`.
+fn announce(tracker: &Tracker, req: &[u8], from: SocketAddr) -> Result<Vec<u8>, String> {
+    if req.len() < ANNOUNCE_LEN {
+        return Err(String::from("Invalid announce"));
+    }
+    // The passkey can only be sent along by clients that support BEP 41.
+    let url_data = url_data(&req[ANNOUNCE_LEN..]);
+    let path = url_data.split('?').next().unwrap_or_default();
+    if tracker.route(path)? != Route::Announce {
+        return Err(String::from("Invalid announce"));
+    }
+
+    let trans_id = u32::from_be_bytes(req[12..16].try_into().unwrap());
+    let field = |at: usize| u32::from_be_bytes(req[at..at + 4].try_into().unwrap());
+    let num_want = i32::from_be_bytes(req[92..96].try_into().unwrap());
+
+    let req = AnnounceRequest {
+        info_hash: req[16..36].to_vec(),
+        peer_id: req[36..56].to_vec(),
+        // The IP address field is ignored, as over HTTP, so peers can't announce others.
+        ip: from.ip(),
+        port: u16::from_be_bytes(req[96..98].try_into().unwrap()),
+        left: u64::from_be_bytes(req[64..72].try_into().unwrap()),
+        event: AnnounceEvent::from_u32(field(80)),
+        num_want: usize::try_from(num_want).ok(),
+    };
+    Ok(tracker.announce(&req)?.to_raw(trans_id))
+}
+
+// There is no way to pass a passkey along with a scrape, so only the allowlist applies.
+fn scrape(tracker: &Tracker, req: &[u8]) -> Vec<u8> {
+    let trans_id = u32::from_be_bytes(req[12..16].try_into().unwrap());
+    let info_hashes: Vec<Vec<u8>> = req[16..]
+        .chunks_exact(20)
+        .take(MAX_SCRAPE_HASHES)
+        .map(<[u8]>::to_vec)
+        .collect();
+
+    let res = ScrapeResponse {
+        files: tracker.scrape(&info_hashes),
+        failure_reason: None,
+    };
+    res.to_udp(trans_id, &info_hashes)
+}
+
+// Joins the URLData options of BEP 41 back together, skipping options of other types.
+fn url_data(mut options: &[u8]) -> String {
+    let mut url_data = Vec::new();
+    while let Some((&kind, rest)) = options.split_first() {
+        match kind {
+            // EndOfOptions
+            0x0 => break,
+            // NOP
+            0x1 => options = rest,
+            _ => {
+                let Some((&len, rest)) = rest.split_first() else {
+                    break;
+                };
+                let len = usize::from(len).min(rest.len());
+                if kind == 0x2 {
+                    url_data.extend(&rest[..len]);
+                }
+                options = &rest[len..];
+            }
+        }
+    }
+    String::from_utf8_lossy(&url_data).into_owned()
+}
+
+fn error_msg(trans_id: u32, msg: &str) -> Vec<u8> {
+    [
+        ACTION_ERROR.to_be_bytes().to_vec(),
+        trans_id.to_be_bytes().to_vec(),
+        msg.as_bytes().to_vec(),
+    ]
+    .concat()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::torrent_info::udp_tracker::url_data_option;
+
+    #[test]
+    fn url_data_options_are_joined() {
+        assert_eq!(url_data(&[]), "");
+        let long = format!("/key/announce?{}", "x".repeat(300));
+        assert_eq!(url_data(&url_data_option(&long)), long);
+        assert_eq!(
+            url_data(b"\x01\x02\x04/key\x01\x02\x09/announce\x00"),
+            "/key/announce"
+        );
+    }
+}
@@ -5,7 +5,7 @@ use ratatui::{
 
 mod components;
 pub mod controller;
-mod widgets;
+pub(crate) mod widgets;
 mod data;
 
 pub trait Draw {
@@ -12,6 +12,8 @@ use crate::ui::Draw;
 pub(crate) struct TorrentProgress {
     pub(crate) rx_progress: watch::Receiver<(u32, u32)>,
     pub(crate) rx_speed: watch::Receiver<f32>,
+    pub(crate) rx_checking: watch::Receiver<Option<(u32, u32)>>,
+    pub(crate) rx_disk_error: watch::Receiver<Option<String>>,
     pub(crate) name: String,
     pub(crate) selected: bool,
 }
@@ -20,9 +22,15 @@ impl Draw for TorrentProgress {
     fn draw<B: Backend>(&mut self, f: &mut Frame<B>, area: Rect) {
         let progress = self.rx_progress.borrow();
         let (pieces, total) = *progress;
+        let checking = *self.rx_checking.borrow();
+        let disk_error = self.rx_disk_error.borrow().clone();
 
         let speed = *self.rx_speed.borrow();
-        let speed_text = if pieces == total {
+        let speed_text = if let Some(e) = &disk_error {
+            format!("Paused: {} (c to retry)", e)
+        } else if let Some((checked, to_check)) = checking {
+            format!("Checking {:.0}%", TorrentProgress::fraction(checked, to_check) * 100.0)
+        } else if pieces == total {
             "Complete".to_string()
         } else if speed > 500.0 {
             format!("{:.2}MB/s", speed / 1000.0)
@@ -39,9 +47,19 @@ impl Draw for TorrentProgress {
             "{} - {}",
             self.name, speed_text
         ))]);
-        let line_gauge = LineGauge::default()
-            .gauge_style(Style::default().fg(Color::Magenta))
-            .ratio(TorrentProgress::fraction(pieces, total));
+        let text = if disk_error.is_some() {
+            text.fg(Color::Red)
+        } else {
+            text
+        };
+        let line_gauge = match checking {
+            Some((checked, to_check)) => LineGauge::default()
+                .gauge_style(Style::default().fg(Color::Yellow))
+                .ratio(TorrentProgress::fraction(checked, to_check)),
+            None => LineGauge::default()
+                .gauge_style(Style::default().fg(Color::Magenta))
+                .ratio(TorrentProgress::fraction(pieces, total)),
+        };
 
         let border = if self.selected {
             border.bold()
@@ -65,11 +83,13 @@ impl TorrentProgress {
     pub(crate) fn new(
         rx_progress: watch::Receiver<(u32, u32)>,
         rx_speed: watch::Receiver<f32>,
+        rx_checking: watch::Receiver<Option<(u32, u32)>>,
+        rx_disk_error: watch::Receiver<Option<String>>,
         name: String,
         selected: bool,
     ) -> Self {
         let name = if name.len() > 25 { format!("{}...", name[..25].to_string()) } else { name };
-        TorrentProgress { rx_progress, rx_speed, name, selected }
+        TorrentProgress { rx_progress, rx_speed, rx_checking, rx_disk_error, name, selected }
     }
 
     pub(crate) fn set_selected(&mut self, select: bool) {
@@ -12,7 +12,7 @@ use std::{
     sync::Arc,
     time::Duration, collections::HashMap,
 };
-use tokio::sync::watch;
+use tokio::sync::{mpsc, watch};
 
 use ratatui::{
     backend::CrosstermBackend,
@@ -20,14 +20,18 @@ use ratatui::{
     Terminal,
 };
 
-use crate::parser::{metadata::Metadata, tracker_info::PeerInfo};
+use crate::{
+    client::admin_message::AdminMessage,
+    parser::{metadata::Metadata, tracker_info::PeerInfo},
+    torrent_info::tracker_session::TrackerStatus,
+};
 
 use super::{
     components::{title::Title, torrent_progress::TorrentProgress},
     data::{LatLon, get_ip_locations},
     widgets::{
         map_info::MapInfo, panel_tabs::PanelTabs, pieces_info::PiecesInfo,
-        torrent_desc::TorrentDesc, torrent_list::TorrentList,
+        torrent_desc::TorrentDesc, torrent_list::TorrentList, trackers_info::TrackersInfo,
     },
     Draw,
 };
@@ -38,12 +42,17 @@ pub(crate) struct Controller {
     pub(crate) rx_in_progress_pieces: watch::Receiver<Vec<bool>>,
     pub(crate) rx_downloaded_pieces: watch::Receiver<Vec<bool>>,
     pub(crate) rx_speed: watch::Receiver<f32>,
+    pub(crate) rx_checking: watch::Receiver<Option<(u32, u32)>>,
+    pub(crate) rx_disk_error: watch::Receiver<Option<String>>,
+    pub(crate) rx_trackers: watch::Receiver<Vec<TrackerStatus>>,
+    tx_admin_message: mpsc::Sender<AdminMessage>,
     selected_torrent: u16,
     panel_state: PanelState,
     ip_location_map: Arc<HashMap<String, Option<LatLon>>>,
 }
 
 impl Controller {
+    #[allow(clippy::too_many_arguments)]
     pub(crate) async fn new(
         md: Arc<Metadata>,
         peers: Arc<Vec<PeerInfo>>,
@@ -51,6 +60,10 @@ impl Controller {
         rx_in_progress_pieces: watch::Receiver<Vec<bool>>,
         rx_downloaded_pieces: watch::Receiver<Vec<bool>>,
         rx_speed: watch::Receiver<f32>,
+        rx_checking: watch::Receiver<Option<(u32, u32)>>,
+        rx_disk_error: watch::Receiver<Option<String>>,
+        rx_trackers: watch::Receiver<Vec<TrackerStatus>>,
+        tx_admin_message: mpsc::Sender<AdminMessage>,
     ) -> Self {
         let hosts = peers.iter().map(|peer| peer.ip.to_owned()).collect();
         let ip_location_map = get_ip_locations(hosts).await.unwrap();
@@ -61,6 +74,10 @@ impl Controller {
             rx_in_progress_pieces,
             rx_downloaded_pieces,
             rx_speed,
+            rx_checking,
+            rx_disk_error,
+            rx_trackers,
+            tx_admin_message,
             selected_torrent: 0,
             panel_state: PanelState::Hidden,
             ip_location_map: ip_location_map.into()
@@ -80,12 +97,16 @@ impl Controller {
             TorrentProgress::new(
                 self.rx_progress.clone(),
                 self.rx_speed.clone(),
+                self.rx_checking.clone(),
+                self.rx_disk_error.clone(),
                 (&self.md.info.name).to_string(),
                 true,
             ),
             TorrentProgress::new(
                 self.rx_progress.clone(),
                 self.rx_speed.clone(),
+                self.rx_checking.clone(),
+                self.rx_disk_error.clone(),
                 "Torrent 2".to_string(),
                 false,
             ),
@@ -115,6 +136,9 @@ impl Controller {
                     PanelState::MapInfo(panel) => {
                         panel.draw(f, tabs_inner_area);
                     }
+                    PanelState::TrackersInfo(panel) => {
+                        panel.draw(f, tabs_inner_area);
+                    }
                 }
             })?;
 
@@ -133,6 +157,12 @@ impl Controller {
                                 self.selected_torrent =
                                     min(torrent_list_len - 1, self.selected_torrent + 1);
                                 torrent_list.set_torrent(self.selected_torrent);
+                            } else if key.code == KeyCode::Char('r') {
+                                // Force a recheck of the data on disk.
+                                let _ = self.tx_admin_message.try_send(AdminMessage::Recheck);
+                            } else if key.code == KeyCode::Char('c') {
+                                // Continue after a disk error paused the download.
+                                let _ = self.tx_admin_message.try_send(AdminMessage::RetryDisk);
                             } else if key.code == KeyCode::Right || key.code == KeyCode::Enter {
                                 self.panel_state = PanelState::TorrentDesc(TorrentDesc {
                                     md: self.md.clone(),
@@ -182,6 +212,22 @@ impl Controller {
                                     self.rx_downloaded_pieces.clone(),
                                 ));
                                 panel_tabs.set_tab(1);
+                            } else if key.code == KeyCode::Right {
+                                self.panel_state = PanelState::TrackersInfo(TrackersInfo {
+                                    rx_trackers: self.rx_trackers.clone(),
+                                });
+                                panel_tabs.set_tab(3);
+                            }
+                        }
+                        PanelState::TrackersInfo(_) => {
+                            if key.code == KeyCode::Esc {
+                                self.panel_state = PanelState::Hidden;
+                                torrent_list.set_selected(true);
+                                panel_tabs.set_selected(false);
+                            } else if key.code == KeyCode::Left {
+                                self.panel_state =
+                                    PanelState::MapInfo(MapInfo::new(self.ip_location_map.clone()));
+                                panel_tabs.set_tab(2);
                             }
                         }
                     }
@@ -232,4 +278,5 @@ enum PanelState {
     TorrentDesc(TorrentDesc),
     PiecesInfo(PiecesInfo),
     MapInfo(MapInfo),
+    TrackersInfo(TrackersInfo),
 }
@@ -3,3 +3,4 @@ pub mod panel_tabs;
 pub mod pieces_info;
 pub mod torrent_desc;
 pub mod torrent_list;
+pub mod trackers_info;
@@ -7,27 +7,81 @@ use ratatui::{
     Frame,
 };
 
-use crate::{parser::metadata::Metadata, ui::Draw};
+use crate::{
+    parser::metadata::{get_magnet_link, Metadata},
+    ui::Draw,
+    utils::format_timestamp,
+};
 
 pub(crate) struct TorrentDesc {
     pub(crate) md: Arc<Metadata>,
 }
 
+// Human readable summary of a metafile, shared by this panel and the `info` command. Optional
+// fields are left out when the torrent doesn't have them.
+pub(crate) fn desc_lines(md: &Metadata) -> Vec<String> {
+    let mut lines = vec![
+        format!(
+            "Name: {}",
+            md.info.name_utf8.as_ref().unwrap_or(&md.info.name)
+        ),
+        format!(
+            "Total size: {:.2}Mb",
+            (md.geometry().total_length() as f32) / 1000000f32
+        ),
+        format!("Pieces: {}", md.num_pieces()),
+    ];
+
+    let trackers = md.trackers();
+    match trackers.first().and_then(|tier| tier.first()) {
+        Some(tracker) => lines.push(format!("Tracker: {}", tracker)),
+        None => lines.push(String::from("Tracker: none")),
+    }
+    if md.info.is_private() {
+        lines.push(String::from("Private: yes"));
+    }
+    if let Some(comment) = &md.comment {
+        lines.push(format!("Comment: {}", comment));
+    }
+    if let Some(created_by) = &md.created_by {
+        lines.push(format!("Created by: {}", created_by));
+    }
+    if let Some(creation_date) = md.creation_date {
+        lines.push(format!("Created on: {}", format_timestamp(creation_date)));
+    }
+    if let Some(encoding) = &md.encoding {
+        lines.push(format!("Encoding: {}", encoding));
+    }
+    if let Some(source) = &md.info.source {
+        lines.push(format!("Source: {}", source));
+    }
+    if !md.url_list.is_empty() {
+        lines.push(format!("Web seeds: {}", md.url_list.join(", ")));
+    }
+    if !md.httpseeds.is_empty() {
+        lines.push(format!("HTTP seeds: {}", md.httpseeds.join(", ")));
+    }
+    if !md.nodes.is_empty() {
+        let nodes: Vec<String> = md
+            .nodes
+            .iter()
+            .map(|(host, port)| format!("{}:{}", host, port))
+            .collect();
+        lines.push(format!("DHT nodes: {}", nodes.join(", ")));
+    }
+    lines.push(format!("Magnet: {}", get_magnet_link(md)));
+
+    lines
+}
+
 impl Draw for TorrentDesc {
     fn draw<B: Backend>(&mut self, f: &mut Frame<B>, area: Rect) {
-        let lines = vec![
-            Line::from(format!("Name: {}", self.md.info.name)),
-            Line::from(""),
-            Line::from(format!(
-                "Total size: {:.2}Mb",
-                ((u32::try_from(self.md.num_pieces()).unwrap() * self.md.info.piece_length) as f32)
-                    / 1000000f32
-            )),
-            Line::from(""),
-            Line::from(format!("Pieces: {}", self.md.num_pieces())),
-            Line::from(""),
-            Line::from(format!("Tracker: {}", self.md.announce_list[0].join(""))),
-        ];
+        let mut lines = Vec::new();
+        for line in desc_lines(&self.md) {
+            lines.push(Line::from(line));
+            lines.push(Line::from(""));
+        }
+        lines.pop();
 
         let text = Paragraph::new(lines);
         f.render_widget(text, area);
@@ -0,0 +1,74 @@
+use std::time::Instant;
+
+use ratatui::{
+    prelude::{Backend, Rect},
+    style::{Color, Style},
+    text::{Line, Span},
+    widgets::{Paragraph, Wrap},
+    Frame,
+};
+use tokio::sync::watch;
+
+use crate::{
+    parser::scrape_info::ScrapeInfo,
+    torrent_info::tracker_session::{TrackerState, TrackerStatus},
+    ui::Draw,
+};
+
+// Shared with the `scrape` command.
+pub(crate) fn swarm_line(swarm: &ScrapeInfo) -> String {
+    format!(
+        "{} seeders, {} leechers, {} downloaded",
+        swarm.complete, swarm.incomplete, swarm.downloaded
+    )
+}
+
+pub(crate) struct TrackersInfo {
+    pub(crate) rx_trackers: watch::Receiver<Vec<TrackerStatus>>,
+}
+
+// The tracker's URL, how its last announce went and its swarm, if it could be scraped.
+fn status_lines(status: &TrackerStatus, now: Instant) -> Vec<Line<'static>> {
+    let (text, color) = match (&status.state, &status.last_error) {
+        (TrackerState::NotContacted, _) => (String::from("Not contacted"), Color::Gray),
+        (TrackerState::Working, _) => match &status.warning {
+            Some(warning) => (format!("Working, warning: {}", warning), Color::Yellow),
+            None => (String::from("Working"), Color::LightGreen),
+        },
+        (TrackerState::Failing, Some(e)) => (format!("Failing: {}", e), Color::LightRed),
+        (TrackerState::Failing, None) => (String::from("Failing"), Color::LightRed),
+    };
+    let text = match status.next_announce {
+        Some(next) => {
+            let secs = next.saturating_duration_since(now).as_secs();
+            format!("{}, next announce in {}m {}s", text, secs / 60, secs % 60)
+        }
+        None => text,
+    };
+
+    let mut lines = vec![
+        Line::from(status.url.clone()),
+        Line::from(Span::styled(text, Style::default().fg(color))),
+    ];
+    if let Some(swarm) = &status.swarm {
+        lines.push(Line::from(swarm_line(swarm)));
+    }
+    lines
+}
+
+impl Draw for TrackersInfo {
+    fn draw<B: Backend>(&mut self, f: &mut Frame<B>, area: Rect) {
+        let now = Instant::now();
+        let mut lines = Vec::new();
+        for status in self.rx_trackers.borrow().iter() {
+            lines.extend(status_lines(status, now));
+            lines.push(Line::from(""));
+        }
+        if lines.is_empty() {
+            lines.push(Line::from("No trackers"));
+        }
+
+        let text = Paragraph::new(lines).wrap(Wrap { trim: true });
+        f.render_widget(text, area);
+    }
+}
@@ -2,6 +2,7 @@ use std::net::{Ipv4Addr, SocketAddrV4};
 
 use byteorder::{BigEndian, ReadBytesExt};
 
+pub mod lru_cache;
 pub mod ring_buffer;
 
 
@@ -9,6 +10,33 @@ pub(crate) fn count_ones(v: &Vec<bool>) -> u32 {
     return v.iter().filter(|&&x| x).count().try_into().unwrap();
 }
 
+// Formats seconds since the Unix epoch as a UTC date and time.
+pub(crate) fn format_timestamp(secs: i64) -> String {
+    let days = secs.div_euclid(86400);
+    let time = secs.rem_euclid(86400);
+
+    // Civil date from days since the epoch, after Howard Hinnant's days_from_civil inverse.
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
+
 pub(crate) fn addr_from_bytes(bytes: &[u8]) -> Result<SocketAddrV4, ()> {
     if bytes.len() < 6 {
         return Err(())
@@ -19,4 +47,16 @@ pub(crate) fn addr_from_bytes(bytes: &[u8]) -> Result<SocketAddrV4, ()> {
     let ip = ip_raw.read_u32::<BigEndian>().unwrap();
     let port = port_raw.read_u16::<BigEndian>().unwrap();
     Ok(SocketAddrV4::new(Ipv4Addr::from_bits(ip), port))
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn formats_timestamps_as_utc() {
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00:00 UTC");
+        assert_eq!(format_timestamp(951_782_400), "2000-02-29 00:00:00 UTC");
+        assert_eq!(format_timestamp(1_700_000_000), "2023-11-14 22:13:20 UTC");
+    }
+}
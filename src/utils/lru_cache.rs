@@ -0,0 +1,79 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    hash::Hash,
+};
+
+// Holds up to `capacity` entries, dropping the least recently used one to make room.
+pub(crate) struct LruCache<K, V> {
+    entries: HashMap<K, (V, u64)>,
+    // Entries by the tick of their last use, oldest first.
+    order: BTreeMap<u64, K>,
+    tick: u64,
+    capacity: usize,
+}
+
+impl<K: Hash + Eq + Clone, V> LruCache<K, V> {
+    pub(crate) fn new(capacity: usize) -> Self {
+        LruCache {
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+            capacity,
+        }
+    }
+
+    pub(crate) fn get(&mut self, key: &K) -> Option<&V> {
+        let (_, used) = self.entries.get_mut(key)?;
+        self.order.remove(used);
+        self.tick += 1;
+        *used = self.tick;
+        self.order.insert(self.tick, key.clone());
+        self.entries.get(key).map(|(v, _)| v)
+    }
+
+    pub(crate) fn insert(&mut self, key: K, val: V) {
+        self.remove(&key);
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() == self.capacity {
+            if let Some((_, oldest)) = self.order.pop_first() {
+                self.entries.remove(&oldest);
+            }
+        }
+
+        self.tick += 1;
+        self.order.insert(self.tick, key.clone());
+        self.entries.insert(key, (val, self.tick));
+    }
+
+    pub(crate) fn remove(&mut self, key: &K) -> Option<V> {
+        let (val, used) = self.entries.remove(key)?;
+        self.order.remove(&used);
+        Some(val)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn least_recently_used_entry_is_evicted() {
+        let mut cache = LruCache::new(2);
+        cache.insert(1, "a");
+        cache.insert(2, "b");
+        assert_eq!(cache.get(&1), Some(&"a"));
+
+        cache.insert(3, "c");
+        assert_eq!(cache.get(&2), None);
+        assert_eq!(cache.get(&1), Some(&"a"));
+        assert_eq!(cache.get(&3), Some(&"c"));
+
+        // Replacing an entry doesn't evict anything else.
+        cache.insert(3, "d");
+        assert_eq!(cache.get(&1), Some(&"a"));
+        assert_eq!(cache.remove(&3), Some("d"));
+        assert_eq!(cache.get(&3), None);
+    }
+}